@@ -0,0 +1,99 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use axum::body::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use key_value_store::{AppState, MemoryStorage, SharedState};
+
+const THREADS: usize = 8;
+const OPS_PER_THREAD: usize = 2_000;
+const KEYS: usize = 10_000;
+
+/// How writers get at the store.
+#[derive(Clone, Copy)]
+enum Design {
+    /// A single shard, every write takes the store's write lock. This is
+    /// how the store worked before it was sharded.
+    SingleLock,
+    /// Writes take the store's read lock and the lock of their shard.
+    Sharded,
+}
+
+impl Design {
+    fn name(self) -> &'static str {
+        match self {
+            Design::SingleLock => "single-lock",
+            Design::Sharded => "sharded",
+        }
+    }
+
+    fn state(self) -> SharedState {
+        let db = match self {
+            Design::SingleLock => AppState::new(MemoryStorage::default()),
+            Design::Sharded => AppState::default(),
+        };
+        for i in 0..KEYS {
+            db.set(key(i), Bytes::from_static(b"Hello World")).unwrap();
+        }
+        SharedState::new(db.into())
+    }
+
+    fn set(self, state: &SharedState, key: String) {
+        let value = Bytes::from_static(b"Hello Again");
+        match self {
+            Design::SingleLock => state.blocking_write().set(key, value),
+            Design::Sharded => state.blocking_read().set(key, value),
+        }
+        .unwrap();
+    }
+}
+
+fn key(i: usize) -> String {
+    format!("user/{:05}", i)
+}
+
+/// Runs `THREADS` clients at once, each doing `OPS_PER_THREAD` requests of
+/// which `write_percent` are writes, and returns how long that took.
+fn run(design: Design, state: &SharedState, write_percent: usize) -> Duration {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for t in 0..THREADS {
+            scope.spawn(move || {
+                for i in 0..OPS_PER_THREAD {
+                    let key = key((t * 7919 + i * 31) % KEYS);
+                    if i % 100 < write_percent {
+                        design.set(state, key);
+                    } else {
+                        state.blocking_read().get(&key);
+                    }
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+pub fn concurrency_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mixed");
+    group.throughput(Throughput::Elements((THREADS * OPS_PER_THREAD) as u64));
+    for write_percent in [10, 50, 90] {
+        for design in [Design::SingleLock, Design::Sharded] {
+            let state = design.state();
+            group.bench_with_input(
+                BenchmarkId::new(design.name(), format!("{write_percent}% writes")),
+                &write_percent,
+                |b, &write_percent| {
+                    b.iter_custom(|iters| {
+                        (0..iters).map(|_| run(design, &state, write_percent)).sum()
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrency_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,93 @@
+use std::{collections::HashMap, ops::Bound};
+
+use axum::body::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use key_value_store::{MemoryStorage, Storage};
+
+/// The `HashMap` the store used before it kept its keys ordered. Scans and
+/// ranges fall back to the default implementations.
+#[derive(Debug, Default)]
+struct HashMapStorage(HashMap<String, Bytes>);
+
+impl Storage for HashMapStorage {
+    fn get(&self, key: &str) -> Option<Bytes> {
+        self.0.get(key).cloned()
+    }
+
+    fn set(&mut self, key: String, value: Bytes) {
+        self.0.insert(key, value);
+    }
+
+    fn delete(&mut self, key: &str) -> Option<Bytes> {
+        self.0.remove(key)
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.0.keys().cloned().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+const KEYS: usize = 10_000;
+
+fn key(i: usize) -> String {
+    format!("user/{:05}", i)
+}
+
+fn filled<S: Storage + Default>() -> S {
+    let mut storage = S::default();
+    for i in 0..KEYS {
+        storage.set(key(i), Bytes::from_static(b"Hello World"));
+    }
+    storage
+}
+
+fn bench_storage<S: Storage + Default>(c: &mut Criterion, name: &str) {
+    let storage: S = filled();
+
+    c.bench_with_input(BenchmarkId::new("get", name), &storage, |b, storage| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 7919) % KEYS;
+            storage.get(black_box(&key(i)))
+        })
+    });
+
+    c.bench_function(&format!("set/{name}"), |b| {
+        let mut storage: S = filled();
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 7919) % KEYS;
+            storage.set(black_box(key(i)), Bytes::from_static(b"Hello Again"))
+        })
+    });
+
+    c.bench_with_input(BenchmarkId::new("scan", name), &storage, |b, storage| {
+        b.iter(|| storage.scan(black_box("user/05"), None, 100))
+    });
+
+    c.bench_with_input(BenchmarkId::new("range", name), &storage, |b, storage| {
+        b.iter(|| {
+            storage.range(
+                Bound::Included(black_box("user/02000")),
+                Bound::Excluded(black_box("user/02100")),
+                usize::MAX,
+            )
+        })
+    });
+}
+
+pub fn storage_benchmark(c: &mut Criterion) {
+    bench_storage::<HashMapStorage>(c, "hashmap");
+    bench_storage::<MemoryStorage>(c, "btreemap");
+}
+
+criterion_group!(benches, storage_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,225 @@
+use std::{fs, io, path::Path, str::FromStr, sync::Arc};
+
+use axum::{http::StatusCode, Extension};
+use serde::Deserialize;
+
+use crate::Principal;
+
+/// What a principal may do with a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+}
+
+/// Grants principals permissions on keys by prefix, loaded from a TOML
+/// file like
+///
+/// ```toml
+/// [[rules]]
+/// principal = "billing"
+/// prefix = "billing/"
+/// allow = ["read", "write", "delete"]
+///
+/// [[rules]]
+/// principal = "*"
+/// prefix = "public/"
+/// allow = ["read"]
+/// ```
+///
+/// Rules apply to the default namespace unless they name another one with
+/// `namespace = "team"`, or every namespace with `namespace = "*"`.
+///
+/// Anything that isn't allowed by a rule is denied. `*` stands for every
+/// authenticated principal, and deleting all keys at once needs `delete`
+/// on the empty prefix of every namespace.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    principal: String,
+    #[serde(default)]
+    namespace: String,
+    #[serde(default)]
+    prefix: String,
+    allow: Vec<Permission>,
+}
+
+impl Policy {
+    /// Reads the policy from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let context = |kind, err: &dyn std::fmt::Display| {
+            io::Error::new(kind, format!("{}: {err}", path.display()))
+        };
+        fs::read_to_string(path)
+            .map_err(|err| context(err.kind(), &err))?
+            .parse()
+            .map_err(|err| context(io::ErrorKind::InvalidData, &err))
+    }
+
+    pub fn allows(
+        &self,
+        principal: &Principal,
+        namespace: &str,
+        key: &str,
+        permission: Permission,
+    ) -> bool {
+        self.rules.iter().any(|rule| {
+            (rule.principal == "*" || rule.principal == principal.name)
+                && (rule.namespace == "*" || rule.namespace == namespace)
+                && key.starts_with(&rule.prefix)
+                && rule.allow.contains(&permission)
+        })
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|err| err.to_string())
+    }
+}
+
+/// The policy together with whoever sent the request, put into the request
+/// extensions once the sender is authenticated.
+#[derive(Debug, Clone)]
+pub(crate) struct Access {
+    policy: Arc<Policy>,
+    principal: Principal,
+}
+
+impl Access {
+    pub(crate) fn new(policy: Arc<Policy>, principal: Principal) -> Self {
+        Self { policy, principal }
+    }
+
+    pub(crate) fn allows(&self, namespace: &str, key: &str, permission: Permission) -> bool {
+        self.policy
+            .allows(&self.principal, namespace, key, permission)
+    }
+}
+
+/// Fails with `403 Forbidden` unless the request may use `key` of
+/// `namespace`. Without a policy, every request may.
+pub(crate) fn check(
+    access: &Option<Extension<Access>>,
+    namespace: &str,
+    key: &str,
+    permission: Permission,
+) -> Result<(), StatusCode> {
+    if allows(access, namespace, key, permission) {
+        Ok(())
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+pub(crate) fn allows(
+    access: &Option<Extension<Access>>,
+    namespace: &str,
+    key: &str,
+    permission: Permission,
+) -> bool {
+    access
+        .as_ref()
+        .is_none_or(|Extension(access)| access.allows(namespace, key, permission))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Permission, Policy};
+    use crate::Principal;
+
+    fn principal(name: &str) -> Principal {
+        Principal {
+            name: name.to_string(),
+            admin: false,
+        }
+    }
+
+    #[test]
+    fn deny_by_default() {
+        let policy = Policy::default();
+        assert!(!policy.allows(&principal("billing"), "", "a", Permission::Read));
+
+        let policy: Policy = r#"
+            [[rules]]
+            principal = "billing"
+            prefix = "billing/"
+            allow = ["read", "write"]
+        "#
+        .parse()
+        .unwrap();
+        let billing = principal("billing");
+        assert!(policy.allows(&billing, "", "billing/a", Permission::Read));
+        assert!(policy.allows(&billing, "", "billing/a", Permission::Write));
+        assert!(!policy.allows(&billing, "", "billing/a", Permission::Delete));
+        assert!(!policy.allows(&billing, "", "billing", Permission::Read));
+        assert!(!policy.allows(&billing, "", "", Permission::Read));
+        assert!(!policy.allows(&principal("search"), "", "billing/a", Permission::Read));
+    }
+
+    #[test]
+    fn wildcards() {
+        let policy: Policy = r#"
+            [[rules]]
+            principal = "*"
+            prefix = "public/"
+            allow = ["read"]
+
+            [[rules]]
+            principal = "ops"
+            allow = ["read", "write", "delete"]
+        "#
+        .parse()
+        .unwrap();
+        assert!(policy.allows(&principal("search"), "", "public/a", Permission::Read));
+        assert!(!policy.allows(&principal("search"), "", "public/a", Permission::Write));
+        assert!(policy.allows(&principal("ops"), "", "anything", Permission::Delete));
+        assert!(policy.allows(&principal("ops"), "", "", Permission::Delete));
+    }
+
+    #[test]
+    fn namespaces() {
+        let policy: Policy = r#"
+            [[rules]]
+            principal = "billing"
+            namespace = "billing"
+            allow = ["read", "write"]
+
+            [[rules]]
+            principal = "ops"
+            namespace = "*"
+            allow = ["read"]
+        "#
+        .parse()
+        .unwrap();
+        let billing = principal("billing");
+        assert!(policy.allows(&billing, "billing", "a", Permission::Write));
+        assert!(!policy.allows(&billing, "", "a", Permission::Read));
+        assert!(!policy.allows(&billing, "search", "a", Permission::Read));
+        assert!(policy.allows(&principal("ops"), "", "a", Permission::Read));
+        assert!(policy.allows(&principal("ops"), "billing", "a", Permission::Read));
+        assert!(!policy.allows(&principal("ops"), "billing", "a", Permission::Write));
+    }
+
+    #[test]
+    fn invalid_policy() {
+        assert!("[[rules]]\nprincipal = \"a\"\nallow = [\"execute\"]"
+            .parse::<Policy>()
+            .is_err());
+        assert!("[[rules]]\nprincipal = \"a\"\nallow = []\nprefx = \"a\""
+            .parse::<Policy>()
+            .is_err());
+    }
+}
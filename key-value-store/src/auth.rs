@@ -0,0 +1,270 @@
+use std::{collections::HashSet, fmt, fs, io, path::Path, str::FromStr};
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use hyper::Request;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// Whoever sent a request, as identified by their API key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub name: String,
+    /// Whether the principal may use the `/admin` routes.
+    pub admin: bool,
+}
+
+/// The API keys clients authenticate with, loaded from a TOML file like
+///
+/// ```toml
+/// [[keys]]
+/// name = "ops"
+/// key = "an unguessable secret"
+/// admin = true
+/// ```
+///
+/// Keys are sent as `Authorization: Bearer <key>` or in `X-Api-Key`.
+#[derive(Clone, Default, Deserialize)]
+#[serde(try_from = "RawApiKeys")]
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+}
+
+#[derive(Clone)]
+struct ApiKey {
+    name: String,
+    admin: bool,
+    /// Digest of the key, which tokens are compared against. It has the
+    /// same size for every key.
+    digest: [u8; 32],
+}
+
+/// `ApiKeys` as they are written, before they are checked.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawApiKeys {
+    #[serde(default)]
+    keys: Vec<RawApiKey>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawApiKey {
+    name: String,
+    key: String,
+    #[serde(default)]
+    admin: bool,
+}
+
+impl TryFrom<RawApiKeys> for ApiKeys {
+    type Error = String;
+
+    fn try_from(raw: RawApiKeys) -> Result<Self, Self::Error> {
+        let mut seen = HashSet::new();
+        let mut keys = Vec::with_capacity(raw.keys.len());
+        for RawApiKey { name, key, admin } in raw.keys {
+            if key.is_empty() {
+                return Err(format!("the key of {name} is empty"));
+            }
+            let digest = Sha256::digest(&key).into();
+            if !seen.insert(key) {
+                return Err(format!("the key of {name} is used twice"));
+            }
+            keys.push(ApiKey {
+                name,
+                admin,
+                digest,
+            });
+        }
+        Ok(Self { keys })
+    }
+}
+
+impl ApiKeys {
+    /// Reads the keys from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let context = |kind, err: &dyn std::fmt::Display| {
+            io::Error::new(kind, format!("{}: {err}", path.display()))
+        };
+        fs::read_to_string(path)
+            .map_err(|err| context(err.kind(), &err))?
+            .parse()
+            .map_err(|err| context(io::ErrorKind::InvalidData, &err))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Looks up the principal a request was sent by, `None` if it carries
+    /// no key or one we don't know.
+    pub fn authenticate(&self, headers: &HeaderMap) -> Option<Principal> {
+        self.principal(bearer_token(headers)?)
+    }
+
+    /// Looks up the principal a key belongs to.
+    pub(crate) fn principal(&self, token: &str) -> Option<Principal> {
+        // Compare digests against every key, so the time taken doesn't tell
+        // how much of a key was right, how long it is or which one matched.
+        let token: [u8; 32] = Sha256::digest(token).into();
+        let mut found = None;
+        for key in &self.keys {
+            if bool::from(key.digest.ct_eq(&token)) {
+                found = Some(key);
+            }
+        }
+        found.map(|key| Principal {
+            name: key.name.clone(),
+            admin: key.admin,
+        })
+    }
+
+    /// Stores the `Principal` of the request in its extensions, failing
+    /// with `401 Unauthorized` without a valid key.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn authorize<B>(&self, request: &mut Request<B>) -> Result<Principal, Response> {
+        let Some(principal) = self.authenticate(request.headers()) else {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))],
+            )
+                .into_response());
+        };
+        request.extensions_mut().insert(principal.clone());
+        Ok(principal)
+    }
+
+    /// Like `authorize`, but only lets admins through. Anyone else with a
+    /// valid key gets `403 Forbidden`.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn authorize_admin<B>(
+        &self,
+        request: &mut Request<B>,
+    ) -> Result<Principal, Response> {
+        let principal = self.authorize(request)?;
+        if !principal.admin {
+            return Err(StatusCode::FORBIDDEN.into_response());
+        }
+        Ok(principal)
+    }
+}
+
+impl FromStr for ApiKeys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|err| err.to_string())
+    }
+}
+
+/// Lists the principals only, the keys themselves stay out of the logs.
+impl fmt::Debug for ApiKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.keys.iter().map(|key| &key.name))
+            .finish()
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    if let Some(value) = headers.get(header::AUTHORIZATION) {
+        let value = value.to_str().ok()?;
+        let (scheme, token) = value.split_once(' ')?;
+        return scheme.eq_ignore_ascii_case("bearer").then(|| token.trim());
+    }
+    headers.get("x-api-key")?.to_str().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{ApiKeys, Principal};
+
+    const KEYS: &str = r#"
+        [[keys]]
+        name = "ops"
+        key = "secret"
+        admin = true
+
+        [[keys]]
+        name = "app"
+        key = "other secret"
+    "#;
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn authenticate() {
+        let keys: ApiKeys = KEYS.parse().unwrap();
+        let ops = Some(Principal {
+            name: "ops".to_string(),
+            admin: true,
+        });
+        assert_eq!(
+            keys.authenticate(&headers("authorization", "Bearer secret")),
+            ops
+        );
+        assert_eq!(
+            keys.authenticate(&headers("authorization", "bearer secret")),
+            ops
+        );
+        assert_eq!(keys.authenticate(&headers("x-api-key", "secret")), ops);
+        assert_eq!(
+            keys.authenticate(&headers("x-api-key", "other secret")),
+            Some(Principal {
+                name: "app".to_string(),
+                admin: false,
+            })
+        );
+        assert_eq!(
+            keys.authenticate(&headers("authorization", "Basic secret")),
+            None
+        );
+        assert_eq!(keys.authenticate(&headers("x-api-key", "secre")), None);
+        assert_eq!(keys.authenticate(&headers("x-api-key", "secrets")), None);
+        assert_eq!(keys.authenticate(&HeaderMap::new()), None);
+        assert_eq!(format!("{keys:?}"), r#"["ops", "app"]"#);
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!("[[keys]]\nname = \"a\"\nkey = \"\""
+            .parse::<ApiKeys>()
+            .is_err());
+        assert!(
+            "[[keys]]\nname = \"a\"\nkey = \"k\"\n[[keys]]\nname = \"b\"\nkey = \"k\""
+                .parse::<ApiKeys>()
+                .is_err()
+        );
+        assert!("[[keys]]\nname = \"a\"\nkey = \"k\"\nadmn = true"
+            .parse::<ApiKeys>()
+            .is_err());
+        assert!("".parse::<ApiKeys>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn keys_embedded_in_other_config() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            auth: ApiKeys,
+        }
+        let config: Config =
+            toml::from_str("[[auth.keys]]\nname = \"ops\"\nkey = \"secret\"").unwrap();
+        let principal = config.auth.authenticate(&headers("x-api-key", "secret"));
+        assert_eq!(principal.unwrap().name, "ops");
+        assert!(toml::from_str::<Config>("[[auth.keys]]\nname = \"ops\"\nkey = \"\"").is_err());
+    }
+}
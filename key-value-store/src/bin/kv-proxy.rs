@@ -0,0 +1,132 @@
+use clap::{value_parser, Arg, ArgMatches, Command};
+use key_value_store::{init_tracing, proxy_router, ApiKeys, LogFormat, Proxy, ProxyOptions};
+use std::{future, net::SocketAddr, path::PathBuf, process, time::Duration};
+use tokio::signal;
+use tracing::{error, warn, Level};
+
+type BoxError = Box<dyn std::error::Error>;
+
+#[tokio::main]
+async fn main() {
+    if let Err(err) = run().await {
+        eprintln!("error: {err}");
+        process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), BoxError> {
+    let matches = cli().get_matches();
+    let level: Level = matches.get_one::<String>("log-level").unwrap().parse()?;
+    init_tracing(level, LogFormat::Text)?;
+    let options = options(&matches)?;
+    if options.api_keys.is_empty() {
+        warn!("no API keys are configured, the admin routes are disabled");
+    }
+
+    let app = proxy_router(&Proxy::new(options));
+    let bind = matches.get_one::<SocketAddr>("bind").unwrap();
+    axum::Server::try_bind(bind)
+        .map_err(|err| format!("{bind}: {err}"))?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(async {
+            if let Err(err) = signal::ctrl_c().await {
+                error!("can't listen for Ctrl+C: {err}");
+                future::pending::<()>().await;
+            }
+        })
+        .await?;
+    Ok(())
+}
+
+fn cli() -> Command<'static> {
+    let arg = |name: &'static str, env: &'static str, help: &'static str| {
+        Arg::new(name)
+            .long(name)
+            .env(env)
+            .takes_value(true)
+            .help(help)
+    };
+    Command::new("kv-proxy")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Spreads keys over several key-value stores by consistent hashing")
+        .arg(
+            arg("bind", "KV_PROXY_BIND", "Address to listen on")
+                .default_value("127.0.0.1:3100")
+                .value_parser(value_parser!(SocketAddr)),
+        )
+        .arg(
+            arg(
+                "backend",
+                "KV_PROXY_BACKENDS",
+                "Store to send keys to, like a=http://10.0.0.1:3000",
+            )
+            .multiple_occurrences(true)
+            .use_value_delimiter(true)
+            .required(true),
+        )
+        .arg(
+            arg(
+                "vnodes",
+                "KV_PROXY_VNODES",
+                "Points of each store on the ring",
+            )
+            .default_value("128")
+            .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg(
+                "api-keys",
+                "KV_PROXY_API_KEYS",
+                "TOML file with the API keys",
+            )
+            .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(arg(
+            "backend-api-key",
+            "KV_PROXY_BACKEND_API_KEY",
+            "Admin API key of the stores, to move keys between them",
+        ))
+        .arg(
+            arg(
+                "timeout-ms",
+                "KV_PROXY_TIMEOUT_MS",
+                "How long a store may take",
+            )
+            .default_value("4000")
+            .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg(
+                "log-level",
+                "KV_LOG_LEVEL",
+                "Most verbose level that gets logged",
+            )
+            .default_value("info")
+            .value_parser(["trace", "debug", "info", "warn", "error"]),
+        )
+}
+
+fn options(matches: &ArgMatches) -> Result<ProxyOptions, BoxError> {
+    let mut options = ProxyOptions::default();
+    for backend in matches.get_many::<String>("backend").into_iter().flatten() {
+        let Some((name, url)) = backend.split_once('=') else {
+            return Err(
+                format!("{backend}: expected a name and a URL, like a=http://host:3000").into(),
+            );
+        };
+        options.backends.insert(name.to_string(), url.to_string());
+    }
+    options.vnodes = *matches.get_one("vnodes").unwrap();
+    if options.vnodes == 0 {
+        return Err("vnodes must be at least 1".into());
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("api-keys") {
+        options.api_keys =
+            ApiKeys::load(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    }
+    if let Some(api_key) = matches.get_one::<String>("backend-api-key") {
+        options.backend_api_key = api_key.clone();
+    }
+    options.timeout = Duration::from_millis(*matches.get_one("timeout-ms").unwrap());
+    Ok(options)
+}
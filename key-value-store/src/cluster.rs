@@ -0,0 +1,672 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{self, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use axum::{
+    extract::{DefaultBodyLimit, OriginalUri, Path, State},
+    http::{header, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    BoxError, Extension, Json, Router,
+};
+use hyper::{client::HttpConnector, Body, Client, Request};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{sync::oneshot, sync::Notify, time::Instant};
+use tracing::{event, Level};
+
+use crate::{
+    etag::Preconditions,
+    raft::{
+        AppendRequest, AppendResponse, Command, Entry, Members, NodeId, Raft, Role,
+        SnapshotRequest, SnapshotResponse, VoteRequest, VoteResponse,
+    },
+    storage_error,
+    wal::{self, Record},
+    SharedState,
+};
+
+/// Entries sent to a follower at once.
+const MAX_BATCH: usize = 256;
+
+/// Settings of a node of a cluster.
+#[derive(Debug, Clone)]
+pub struct ClusterOptions {
+    pub id: NodeId,
+    /// The members the cluster starts out with, including this node. Empty
+    /// for a node that waits to be added to a running cluster.
+    pub members: Members,
+    /// Admin API key the nodes use with each other.
+    pub api_key: String,
+    /// How often the leader reaches out to the followers.
+    pub heartbeat: Duration,
+    /// How long followers wait for the leader before they try to replace
+    /// it, with up to as much again at random.
+    pub election_timeout: Duration,
+    /// Applied entries kept in the log, for followers that fell behind.
+    /// Those that fall further behind get a snapshot of the store instead.
+    pub max_log_entries: usize,
+    /// Where the node notes down that it ran on the store, `None` for a
+    /// store that is only kept in memory.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for ClusterOptions {
+    fn default() -> Self {
+        Self {
+            id: 1,
+            members: Members::new(),
+            api_key: String::new(),
+            heartbeat: Duration::from_millis(100),
+            election_timeout: Duration::from_millis(1000),
+            max_log_entries: 10_000,
+            path: None,
+        }
+    }
+}
+
+/// A node of a Raft cluster. Writes go through the log of the cluster and
+/// are applied to the store of each node once a majority has them.
+///
+/// Reads are answered by each node from its own store, followers may be a
+/// little behind. Stores with limits can't be part of a cluster: each node
+/// would reject and evict entries on its own, and the stores would drift
+/// apart.
+#[derive(Clone)]
+pub struct Cluster {
+    node: Arc<Node>,
+}
+
+struct Node {
+    raft: Mutex<Raft>,
+    state: SharedState,
+    options: ClusterOptions,
+    client: Client<HttpConnector>,
+    /// Writes waiting to be applied, by index.
+    pending: Mutex<HashMap<u64, Pending>>,
+    /// Held while entries are applied or a snapshot is taken or restored.
+    applying: tokio::sync::Mutex<()>,
+    /// Wakes the applier once entries were committed.
+    committed: Arc<Notify>,
+    /// Followers with a request on the way.
+    sending: Mutex<HashSet<NodeId>>,
+}
+
+/// The term a write was proposed in, and where to send the result of
+/// applying it.
+type Pending = (u64, oneshot::Sender<Result<(), StatusCode>>);
+
+impl fmt::Debug for Cluster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cluster")
+            .field("id", &self.node.options.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Why a write through the cluster didn't happen.
+#[derive(Debug)]
+pub(crate) enum WriteError {
+    Status(StatusCode),
+    /// Only the leader takes writes, it is at this URL.
+    NotLeader(String),
+}
+
+impl From<StatusCode> for WriteError {
+    fn from(status: StatusCode) -> Self {
+        Self::Status(status)
+    }
+}
+
+impl IntoResponse for WriteError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Status(status) => status.into_response(),
+            Self::NotLeader(location) => (
+                StatusCode::TEMPORARY_REDIRECT,
+                [(header::LOCATION, location)],
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// What the node knows about the cluster, for `GET /admin/cluster`.
+#[derive(Debug, Serialize)]
+pub struct ClusterStatus {
+    pub id: NodeId,
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<NodeId>,
+    pub commit: u64,
+    pub applied: u64,
+    pub members: Members,
+}
+
+impl Cluster {
+    /// Makes `state` the store of a node of the cluster, and keeps it in
+    /// the cluster until the last handle is dropped.
+    ///
+    /// The votes and log of a node are only kept in memory. Restarted on
+    /// the same store it could vote twice in a term, or apply entries on
+    /// top of those it applied before, so this fails unless the store is
+    /// empty and no node ran on it before. It fails for a store with
+    /// limits as well.
+    pub async fn start(state: &SharedState, options: ClusterOptions) -> io::Result<Self> {
+        {
+            let db = state.read().await;
+            if db.has_limits() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a store with limits can't be a node of a cluster",
+                ));
+            }
+            if !db.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "a new node of a cluster starts out with an empty store",
+                ));
+            }
+        }
+        if let Some(path) = &options.path {
+            claim(path, options.id)?;
+        }
+        let raft = Raft::new(
+            options.id,
+            options.members.clone(),
+            options.election_timeout,
+            Instant::now(),
+        );
+        let node = Arc::new(Node {
+            raft: Mutex::new(raft),
+            state: Arc::clone(state),
+            options,
+            client: Client::new(),
+            pending: Mutex::new(HashMap::new()),
+            applying: tokio::sync::Mutex::new(()),
+            committed: Arc::new(Notify::new()),
+            sending: Mutex::new(HashSet::new()),
+        });
+        tokio::spawn(tick(Arc::downgrade(&node)));
+        tokio::spawn(apply(Arc::downgrade(&node), Arc::clone(&node.committed)));
+        Ok(Self { node })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.node.options.id
+    }
+
+    pub fn status(&self) -> ClusterStatus {
+        let raft = self.node.raft.lock().unwrap();
+        ClusterStatus {
+            id: raft.id,
+            role: raft.role,
+            term: raft.term,
+            leader: raft.leader,
+            commit: raft.commit,
+            applied: raft.applied,
+            members: raft.members.clone(),
+        }
+    }
+
+    /// Commits a write of a single key, once it was applied to the store of
+    /// this node.
+    pub(crate) async fn write(
+        &self,
+        record: Record,
+        preconditions: Preconditions,
+        uri: &Uri,
+    ) -> Result<(), WriteError> {
+        self.node
+            .propose(uri, |_| {
+                Ok((
+                    Command::Write {
+                        record,
+                        preconditions,
+                    },
+                    (),
+                ))
+            })
+            .await
+    }
+}
+
+impl Node {
+    /// Appends the command made by `command` to the log and waits until it
+    /// is applied. Fails without a leader, or with the status `command`
+    /// fails with.
+    async fn propose<T>(
+        self: &Arc<Self>,
+        uri: &Uri,
+        command: impl FnOnce(&Raft) -> Result<(Command, T), StatusCode>,
+    ) -> Result<T, WriteError> {
+        let (applied, value) = {
+            let mut raft = self.raft.lock().unwrap();
+            if raft.role != Role::Leader {
+                return Err(redirect(&raft, uri));
+            }
+            let (command, value) = command(&raft)?;
+            let (index, term) = raft.propose(command).expect("this node leads");
+            let (tx, rx) = oneshot::channel();
+            self.pending.lock().unwrap().insert(index, (term, tx));
+            (rx, value)
+        };
+        self.committed.notify_one();
+        self.replicate();
+
+        // Long enough for a new leader to take over and decide about it.
+        let timeout = self.options.election_timeout * 10;
+        match tokio::time::timeout(timeout, applied).await {
+            Ok(Ok(result)) => result.map(|()| value).map_err(WriteError::Status),
+            // Replaced by an entry of another leader.
+            Ok(Err(_)) | Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE.into()),
+        }
+    }
+
+    /// Sends each follower what it is missing, unless something is on its
+    /// way to it already. Empty entries serve as heartbeats.
+    fn replicate(self: &Arc<Self>) {
+        let peers = {
+            let raft = self.raft.lock().unwrap();
+            if raft.role != Role::Leader {
+                return;
+            }
+            raft.peers()
+        };
+        for (peer, url) in peers {
+            if self.sending.lock().unwrap().insert(peer) {
+                let node = Arc::clone(self);
+                tokio::spawn(async move {
+                    node.send(peer, &url).await;
+                    node.sending.lock().unwrap().remove(&peer);
+                });
+            }
+        }
+    }
+
+    async fn send(self: &Arc<Self>, peer: NodeId, url: &str) {
+        loop {
+            let req = self.raft.lock().unwrap().append_request(peer, MAX_BATCH);
+            let sent = match req {
+                Some(req) => self.send_entries(peer, url, req).await,
+                None => self.send_snapshot(peer, url).await,
+            };
+            if let Err(err) = sent {
+                event!(Level::DEBUG, peer, "replicating to {url} failed: {err}");
+                return;
+            }
+            if !self.raft.lock().unwrap().behind(peer) {
+                return;
+            }
+        }
+    }
+
+    async fn send_entries(
+        self: &Arc<Self>,
+        peer: NodeId,
+        url: &str,
+        req: AppendRequest,
+    ) -> Result<(), BoxError> {
+        let resp: AppendResponse = self.call(url, "/raft/append", &req).await?;
+        let commit = {
+            let mut raft = self.raft.lock().unwrap();
+            let commit = raft.commit;
+            raft.handle_append_response(peer, &resp);
+            raft.commit > commit
+        };
+        if commit {
+            self.committed.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Sends the whole store as of the last applied entry.
+    async fn send_snapshot(self: &Arc<Self>, peer: NodeId, url: &str) -> Result<(), BoxError> {
+        let req = {
+            let _applying = self.applying.lock().await;
+            let (term, leader, (index, index_term, members)) = {
+                let raft = self.raft.lock().unwrap();
+                if raft.role != Role::Leader {
+                    return Ok(());
+                }
+                (raft.term, raft.id, raft.snapshot_point())
+            };
+            SnapshotRequest {
+                term,
+                leader,
+                index,
+                index_term,
+                members,
+                records: self.state.read().await.snapshot_records(),
+            }
+        };
+        event!(Level::INFO, peer, index = req.index, "sending a snapshot");
+        let resp: SnapshotResponse = self.call(url, "/raft/snapshot", &req).await?;
+        self.raft
+            .lock()
+            .unwrap()
+            .handle_snapshot_response(peer, req.index, &resp);
+        self.committed.notify_one();
+        Ok(())
+    }
+
+    fn start_election(self: &Arc<Self>) {
+        let (req, peers, won) = {
+            let mut raft = self.raft.lock().unwrap();
+            let req = raft.start_election(Instant::now());
+            event!(Level::INFO, term = req.term, "starting an election");
+            (req, raft.peers(), raft.role == Role::Leader)
+        };
+        if won {
+            self.replicate();
+            self.committed.notify_one();
+        }
+        for (peer, url) in peers {
+            let node = Arc::clone(self);
+            let req = req.clone();
+            tokio::spawn(async move {
+                let resp: VoteResponse = match node.call(&url, "/raft/vote", &req).await {
+                    Ok(resp) => resp,
+                    Err(err) => {
+                        event!(Level::DEBUG, peer, "asking {url} for a vote failed: {err}");
+                        return;
+                    }
+                };
+                let won = {
+                    let mut raft = node.raft.lock().unwrap();
+                    let candidate = raft.role == Role::Candidate;
+                    raft.handle_vote_response(peer, &resp);
+                    candidate && raft.role == Role::Leader
+                };
+                if won {
+                    event!(Level::INFO, term = req.term, "elected as the leader");
+                    node.replicate();
+                }
+            });
+        }
+    }
+
+    /// Posts `body` as JSON to another node.
+    async fn call<T: Serialize, R: DeserializeOwned>(
+        &self,
+        url: &str,
+        path: &str,
+        body: &T,
+    ) -> Result<R, BoxError> {
+        let request = Request::post(format!("{}{path}", url.trim_end_matches('/')))
+            .header(
+                header::AUTHORIZATION,
+                format!("Bearer {}", self.options.api_key),
+            )
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(body)?))?;
+        let call = async {
+            let response = self.client.request(request).await?;
+            if response.status() != StatusCode::OK {
+                return Err(format!("responded with {}", response.status()).into());
+            }
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            Ok(serde_json::from_slice(&body)?)
+        };
+        tokio::time::timeout(self.options.election_timeout, call).await?
+    }
+
+    /// Applies the entries that were committed, in order.
+    async fn apply_committed(&self) {
+        let _applying = self.applying.lock().await;
+        let entries: Vec<(u64, Entry)> = {
+            let raft = self.raft.lock().unwrap();
+            (raft.applied + 1..=raft.commit)
+                .filter_map(|index| Some((index, raft.entry(index)?.clone())))
+                .collect()
+        };
+        for (index, entry) in entries {
+            let result = match entry.command {
+                Command::Write {
+                    record,
+                    preconditions,
+                } => self
+                    .state
+                    .read()
+                    .await
+                    .commit_if(record, |current| preconditions.check(current))
+                    .map_err(storage_error)
+                    .and_then(|checked| checked),
+                Command::Noop | Command::Members(_) => Ok(()),
+            };
+            self.raft.lock().unwrap().applied = index;
+            if let Some((term, tx)) = self.pending.lock().unwrap().remove(&index) {
+                if term == entry.term {
+                    let _ = tx.send(result);
+                }
+            }
+        }
+        self.raft
+            .lock()
+            .unwrap()
+            .compact(self.options.max_log_entries);
+    }
+}
+
+/// Notes down at `path` that node `id` runs on the store, failing if a node
+/// did so before.
+fn claim(path: &path::Path, id: NodeId) -> io::Result<()> {
+    let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            return Err(io::Error::new(
+                err.kind(),
+                format!(
+                    "{}: a node ran on this store before and its votes and log are lost, \
+                     add it to the cluster again under a new id with an empty store",
+                    path.display()
+                ),
+            ));
+        }
+        Err(err) => return Err(err),
+    };
+    writeln!(file, "{id}")?;
+    file.sync_all()?;
+    wal::sync_dir(path)
+}
+
+/// Where to send a write that this node can't take.
+fn redirect(raft: &Raft, uri: &Uri) -> WriteError {
+    match raft.leader_url() {
+        Some(leader) => {
+            let path = uri.path_and_query().map_or("/", |path| path.as_str());
+            WriteError::NotLeader(format!("{}{path}", leader.trim_end_matches('/')))
+        }
+        None => WriteError::Status(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+/// Sends heartbeats while leading, and starts elections when the leader
+/// went quiet.
+async fn tick(node: Weak<Node>) {
+    let heartbeat = match node.upgrade() {
+        Some(node) => node.options.heartbeat,
+        None => return,
+    };
+    let mut interval = tokio::time::interval(heartbeat);
+    loop {
+        interval.tick().await;
+        let Some(node) = node.upgrade() else {
+            return;
+        };
+        let election_due = node.raft.lock().unwrap().election_due(Instant::now());
+        if election_due {
+            node.start_election();
+        } else {
+            node.replicate();
+        }
+    }
+}
+
+async fn apply(node: Weak<Node>, committed: Arc<Notify>) {
+    loop {
+        // Checks every now and then whether the node is still around.
+        let _ = tokio::time::timeout(Duration::from_secs(1), committed.notified()).await;
+        let Some(node) = node.upgrade() else {
+            return;
+        };
+        node.apply_committed().await;
+    }
+}
+
+/// Fails with `501 Not Implemented` in a cluster, for the writes that don't
+/// go through its log.
+pub(crate) fn local_only(cluster: &Option<Extension<Cluster>>) -> Result<(), StatusCode> {
+    match cluster {
+        Some(_) => Err(StatusCode::NOT_IMPLEMENTED),
+        None => Ok(()),
+    }
+}
+
+/// The routes the nodes use with each other.
+pub(crate) fn raft_routes(cluster: &Cluster) -> Router {
+    async fn vote(
+        State(cluster): State<Cluster>,
+        Json(req): Json<VoteRequest>,
+    ) -> Json<VoteResponse> {
+        let mut raft = cluster.node.raft.lock().unwrap();
+        Json(raft.handle_vote_request(&req, Instant::now()))
+    }
+
+    async fn append(
+        State(cluster): State<Cluster>,
+        Json(req): Json<AppendRequest>,
+    ) -> Json<AppendResponse> {
+        let resp = cluster
+            .node
+            .raft
+            .lock()
+            .unwrap()
+            .handle_append_request(req, Instant::now());
+        cluster.node.committed.notify_one();
+        Json(resp)
+    }
+
+    async fn snapshot(
+        State(cluster): State<Cluster>,
+        Json(req): Json<SnapshotRequest>,
+    ) -> Result<Json<SnapshotResponse>, StatusCode> {
+        let node = &cluster.node;
+        let restore = node
+            .raft
+            .lock()
+            .unwrap()
+            .handle_snapshot_request(&req, Instant::now());
+        if restore {
+            let _applying = node.applying.lock().await;
+            // Entries may have been applied while waiting.
+            if node.raft.lock().unwrap().applied < req.index {
+                node.state
+                    .write()
+                    .await
+                    .restore(req.records)
+                    .map_err(storage_error)?;
+                node.raft
+                    .lock()
+                    .unwrap()
+                    .install_snapshot(req.index, req.index_term, req.members);
+                event!(Level::INFO, index = req.index, "restored a snapshot");
+            }
+        }
+        let term = node.raft.lock().unwrap().term;
+        Ok(Json(SnapshotResponse { term }))
+    }
+
+    Router::new()
+        .route("/raft/vote", post(vote))
+        .route("/raft/append", post(append))
+        .route("/raft/snapshot", post(snapshot))
+        // Snapshots hold the whole store, and only admins get here.
+        .layer(DefaultBodyLimit::disable())
+        .with_state(cluster.clone())
+}
+
+/// The members to change, failing with `409 Conflict` while the last
+/// change is not committed yet.
+fn current_members(raft: &Raft) -> Result<Members, StatusCode> {
+    if raft.changing_members() {
+        return Err(StatusCode::CONFLICT);
+    }
+    Ok(raft.members.clone())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Member {
+    url: String,
+}
+
+/// `/admin/cluster`, to see how the cluster is doing and change its
+/// members one at a time.
+pub(crate) fn admin_routes(cluster: &Cluster) -> Router {
+    async fn status(State(cluster): State<Cluster>) -> Json<ClusterStatus> {
+        Json(cluster.status())
+    }
+
+    /// Responds with `201 Created` for a new member and `200 OK` if only its
+    /// URL changed.
+    async fn put_member(
+        Path(id): Path<NodeId>,
+        State(cluster): State<Cluster>,
+        OriginalUri(uri): OriginalUri,
+        Json(member): Json<Member>,
+    ) -> Result<StatusCode, WriteError> {
+        if member.url.parse::<Uri>().is_err() {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        let created = cluster
+            .node
+            .propose(&uri, |raft| {
+                let mut members = current_members(raft)?;
+                let created = members.insert(id, member.url).is_none();
+                Ok((Command::Members(members), created))
+            })
+            .await?;
+        event!(Level::INFO, member = id, "changed a member");
+        Ok(if created {
+            StatusCode::CREATED
+        } else {
+            StatusCode::OK
+        })
+    }
+
+    async fn delete_member(
+        Path(id): Path<NodeId>,
+        State(cluster): State<Cluster>,
+        OriginalUri(uri): OriginalUri,
+    ) -> Result<(), WriteError> {
+        cluster
+            .node
+            .propose(&uri, |raft| {
+                let mut members = current_members(raft)?;
+                if members.remove(&id).is_none() {
+                    return Err(StatusCode::NOT_FOUND);
+                }
+                if members.is_empty() {
+                    return Err(StatusCode::CONFLICT);
+                }
+                Ok((Command::Members(members), ()))
+            })
+            .await?;
+        event!(Level::INFO, member = id, "removed a member");
+        Ok(())
+    }
+
+    Router::new()
+        .route("/cluster", get(status))
+        .route(
+            "/cluster/members/:id",
+            put(put_member).delete(delete_member),
+        )
+        .with_state(cluster.clone())
+}
@@ -0,0 +1,321 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{sync::OwnedRwLockWriteGuard, task::JoinHandle};
+use tracing::{event, Level};
+
+use crate::{
+    expiry,
+    wal::{self, Record},
+    AppState, SharedState,
+};
+
+/// Where the snapshot for the log at `log` lives.
+fn snapshot_path(log: &Path) -> PathBuf {
+    log.with_extension("snapshot")
+}
+
+/// Where the log is moved to while a snapshot is written.
+fn compacting_path(log: &Path) -> PathBuf {
+    log.with_extension("compacting")
+}
+
+impl AppState {
+    /// Loads the latest snapshot for the log at `log`, plus the records of a
+    /// compaction that did not finish. Returns whether there was one.
+    pub(crate) fn restore_snapshot(&mut self, log: &Path) -> io::Result<bool> {
+        match wal::read(&snapshot_path(log)) {
+            Ok(records) => records.into_iter().for_each(|record| self.apply(record)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let compacting = compacting_path(log);
+        if !compacting.exists() {
+            return Ok(false);
+        }
+        // The rotated log may overlap with the snapshot if we crashed right
+        // after writing it. Replaying sets, deletes and clears on top of their
+        // own result doesn't change it, so that's fine. A snapshot that goes
+        // beyond the rotated log is only written by `finish_compaction`,
+        // which leaves the log alone until the rotated one is gone, so the
+        // newer records are replayed after it.
+        for record in wal::read(&compacting)? {
+            self.apply(record);
+        }
+        Ok(true)
+    }
+
+    /// Completes a compaction that was interrupted or failed, once the log
+    /// is open.
+    pub(crate) fn finish_compaction(&mut self) -> io::Result<()> {
+        self.replace_rotated_log()?;
+        match self.wal.get_mut().unwrap() {
+            Some(wal) => wal.truncate(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes a snapshot of everything, which covers the log too, and drops
+    /// the rotated log. Were the rotated log still around once the log is
+    /// truncated, it would be replayed on top and bring back older values.
+    fn replace_rotated_log(&mut self) -> io::Result<()> {
+        let records = self.snapshot_records();
+        let Some(wal) = self.wal.get_mut().unwrap() else {
+            return Ok(());
+        };
+        let log = wal.path().to_path_buf();
+        wal::write(&snapshot_path(&log), records)?;
+        fs::remove_file(compacting_path(&log))?;
+        wal::sync_dir(&log)
+    }
+
+    /// Whether an earlier compaction failed to write its snapshot, and left
+    /// the rotated log behind.
+    fn compaction_failed(&mut self) -> bool {
+        match self.wal.get_mut().unwrap() {
+            Some(wal) => !self.compacting && compacting_path(wal.path()).exists(),
+            None => false,
+        }
+    }
+
+    /// Moves the current log aside and returns the log path together with
+    /// the records for the snapshot that replaces it.
+    fn begin_compaction(&mut self) -> io::Result<Option<(PathBuf, Vec<Record>)>> {
+        let Some(wal) = self.wal.get_mut().unwrap() else {
+            return Ok(None);
+        };
+        let compacting = compacting_path(wal.path());
+        if self.compacting || compacting.exists() {
+            // Another compaction is still writing its snapshot.
+            return Ok(None);
+        }
+        wal.rotate(&compacting)?;
+        let log = wal.path().to_path_buf();
+        self.compacting = true;
+        Ok(Some((log, self.snapshot_records())))
+    }
+
+    /// Namespaces go first, so that their keys have somewhere to go.
+    pub(crate) fn snapshot_records(&self) -> Vec<Record> {
+        let mut entries = Vec::new();
+        for shard in self.read_shards() {
+            entries.extend(
+                shard
+                    .db
+                    .list()
+                    .into_iter()
+                    .filter(|key| !shard.is_expired(key))
+                    .filter_map(|key| {
+                        let value = shard.db.get(&key)?;
+                        let record = match shard.expiries.get(&key) {
+                            Some(at) => Record::SetEx {
+                                key,
+                                value,
+                                expires_at: expiry::to_unix_millis(*at),
+                            },
+                            None => Record::Set { key, value },
+                        };
+                        Some(record)
+                    }),
+            );
+        }
+        self.namespace_records().chain(entries).collect()
+    }
+}
+
+/// Writes a point-in-time snapshot of the store and truncates the log
+/// behind it.
+///
+/// Writers are only blocked while the log is rotated, the snapshot itself
+/// is written on a blocking thread.
+pub async fn compact(state: &SharedState) -> io::Result<()> {
+    let mut db = Arc::clone(state).write_owned().await;
+    if db.compaction_failed() {
+        return finish_compaction(db).await;
+    }
+    let Some((log, records)) = db.begin_compaction()? else {
+        return Ok(());
+    };
+    drop(db);
+
+    let written = tokio::task::spawn_blocking(move || {
+        wal::write(&snapshot_path(&log), records)?;
+        fs::remove_file(compacting_path(&log))
+    })
+    .await
+    .map_err(io::Error::other)
+    .and_then(|written| written);
+
+    let mut db = Arc::clone(state).write_owned().await;
+    db.compacting = false;
+    if let Err(err) = written {
+        // The rotated log holds records the old snapshot doesn't, so it has
+        // to be folded into a new one before the next compaction.
+        event!(Level::ERROR, "writing the snapshot failed: {err}");
+        return finish_compaction(db).await;
+    }
+    Ok(())
+}
+
+/// Writes a snapshot of everything on a blocking thread. Writers wait, as
+/// the log is truncated behind it.
+async fn finish_compaction(mut db: OwnedRwLockWriteGuard<AppState>) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || db.finish_compaction())
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Compacts the store whenever its log grows beyond `max_log_bytes`. The
+/// task ends once the state is dropped.
+pub fn spawn_compactor(
+    state: &SharedState,
+    max_log_bytes: u64,
+    check_every: Duration,
+) -> JoinHandle<()> {
+    let state = Arc::downgrade(state);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(check_every);
+        loop {
+            interval.tick().await;
+            let Some(state) = state.upgrade() else {
+                break;
+            };
+            if state.read().await.log_size() <= max_log_bytes {
+                continue;
+            }
+            if let Err(err) = compact(&state).await {
+                event!(Level::ERROR, "compacting the log failed: {err}");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+
+    use super::{compact, compacting_path, snapshot_path};
+    use crate::{wal, AppState, FsyncPolicy, SharedState};
+
+    #[tokio::test]
+    async fn compaction_keeps_data_and_truncates_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+
+        let state = SharedState::new(AppState::open(&path, FsyncPolicy::Always).unwrap().into());
+        {
+            let db = state.write().await;
+            for i in 0..10 {
+                db.set("a".to_string(), Bytes::from(i.to_string())).unwrap();
+            }
+            db.set("b".to_string(), Bytes::from_static(b"b")).unwrap();
+            db.set("c".to_string(), Bytes::from_static(b"c")).unwrap();
+            db.remove("c").unwrap();
+        }
+
+        compact(&state).await.unwrap();
+        assert_eq!(state.read().await.log_size(), 0);
+        assert!(snapshot_path(&path).exists());
+        assert!(!compacting_path(&path).exists());
+
+        // Writes after the snapshot end up in the log tail.
+        state
+            .write()
+            .await
+            .set("d".to_string(), Bytes::from_static(b"d"))
+            .unwrap();
+        drop(state);
+
+        let db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        let mut keys = db.keys();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b", "d"]);
+        assert_eq!(&db.get("a").unwrap()[..], b"9");
+    }
+
+    #[tokio::test]
+    async fn interrupted_compaction_is_finished_on_startup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+
+        let db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        db.set("a".to_string(), Bytes::from_static(b"1")).unwrap();
+        compact(&SharedState::new(db.into())).await.unwrap();
+
+        let mut db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        db.set("a".to_string(), Bytes::from_static(b"2")).unwrap();
+        db.set("b".to_string(), Bytes::from_static(b"3")).unwrap();
+        // Crash after the log was rotated, before the snapshot was written.
+        db.begin_compaction().unwrap().unwrap();
+        db.set("c".to_string(), Bytes::from_static(b"4")).unwrap();
+        drop(db);
+
+        let db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(&db.get("a").unwrap()[..], b"2");
+        assert_eq!(&db.get("b").unwrap()[..], b"3");
+        assert_eq!(&db.get("c").unwrap()[..], b"4");
+        assert_eq!(db.log_size(), 0);
+        assert!(!compacting_path(&path).exists());
+    }
+
+    #[test]
+    fn crash_while_finishing_a_compaction_keeps_newer_values() {
+        for rotated_log_replaced in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("kv.log");
+
+            let mut db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+            db.set("a".to_string(), Bytes::from_static(b"1")).unwrap();
+            db.begin_compaction().unwrap().unwrap();
+            db.set("a".to_string(), Bytes::from_static(b"2")).unwrap();
+            // Crash in `finish_compaction` right after the snapshot of
+            // everything was written, or before the log was truncated.
+            if rotated_log_replaced {
+                db.replace_rotated_log().unwrap();
+            } else {
+                wal::write(&snapshot_path(&path), db.snapshot_records()).unwrap();
+            }
+            drop(db);
+
+            let db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+            assert_eq!(&db.get("a").unwrap()[..], b"2");
+            assert!(!compacting_path(&path).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_compaction_is_finished_later() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+        let state = SharedState::new(AppState::open(&path, FsyncPolicy::Always).unwrap().into());
+        let set = |key: &str, value: &'static [u8]| {
+            let state = state.clone();
+            let key = key.to_string();
+            async move { state.read().await.set(key, Bytes::from_static(value)) }
+        };
+        set("a", b"1").await.unwrap();
+
+        // The snapshot is written next to it first, which can't be done now.
+        let blocker = snapshot_path(&path).with_extension("tmp");
+        std::fs::create_dir(&blocker).unwrap();
+        assert!(compact(&state).await.is_err());
+        assert!(compacting_path(&path).exists());
+        set("b", b"2").await.unwrap();
+
+        std::fs::remove_dir(&blocker).unwrap();
+        compact(&state).await.unwrap();
+        assert!(!compacting_path(&path).exists());
+        assert_eq!(state.read().await.log_size(), 0);
+        compact(&state).await.unwrap();
+        drop(state);
+
+        let db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(&db.get("a").unwrap()[..], b"1");
+        assert_eq!(&db.get("b").unwrap()[..], b"2");
+    }
+}
@@ -0,0 +1,601 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use axum::http::Uri;
+use serde::Deserialize;
+use tracing::Level;
+
+use crate::{
+    shard, ApiKeys, ClusterOptions, EvictionPolicy, Fault, FsyncPolicy, Limits, LogFormat, NodeId,
+    Policy, RateLimit, RouterOptions,
+};
+
+/// Settings of the `key-value-store` binary, loaded from a TOML file like
+///
+/// ```toml
+/// bind = "0.0.0.0:3000"
+/// resp_bind = "0.0.0.0:6379"
+/// log_level = "info"
+/// log_format = "json"
+///
+/// [storage]
+/// path = "/var/lib/kv/kv.log"
+/// fsync = "interval"
+/// max_bytes = 1073741824
+///
+/// [http]
+/// timeout_ms = 2000
+/// kv_rate_limit = { burst = 200, per_second = 100 }
+///
+/// [auth]
+/// api_keys = "/etc/kv/keys.toml"
+///
+/// [replication]
+/// leader = "http://10.0.0.1:3000"
+/// api_key = "follower-secret"
+/// ```
+///
+/// or, for a node of a cluster,
+///
+/// ```toml
+/// [cluster]
+/// id = 1
+/// api_key = "cluster-secret"
+/// members = [
+///     { id = 1, url = "http://10.0.0.1:3000" },
+///     { id = 2, url = "http://10.0.0.2:3000" },
+///     { id = 3, url = "http://10.0.0.3:3000" },
+/// ]
+/// ```
+///
+/// Everything left out keeps its default.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    /// Address to serve Redis clients on, not served without one.
+    pub resp_bind: Option<SocketAddr>,
+    /// One of `trace`, `debug`, `info`, `warn` or `error`.
+    pub log_level: String,
+    /// One of `text`, `json` or `logfmt`.
+    pub log_format: LogFormat,
+    pub storage: StorageConfig,
+    pub http: HttpConfig,
+    pub auth: AuthConfig,
+    pub replication: ReplicationConfig,
+    pub cluster: ClusterConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// The write-ahead log.
+    pub path: PathBuf,
+    pub fsync: Fsync,
+    /// How often the log is flushed with `fsync = "interval"`.
+    pub fsync_interval_ms: u64,
+    /// The log is compacted once it is larger than this.
+    pub compact_threshold_bytes: u64,
+    pub compact_interval_ms: u64,
+    /// How often expired entries are evicted.
+    pub reap_interval_ms: u64,
+    pub max_keys: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub eviction: EvictionPolicy,
+    /// Keys are spread over this many shards, so that writes of different
+    /// keys don't wait for each other.
+    pub shards: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fsync {
+    #[default]
+    Always,
+    Interval,
+    Never,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Largest value that can be written.
+    pub body_limit_bytes: usize,
+    /// How long a write may take before it fails with `408`.
+    pub timeout_ms: u64,
+    /// How long requests in flight get to finish on shutdown.
+    pub shutdown_timeout_ms: u64,
+    /// Unlimited by default. Clients are told apart by their API key, and
+    /// by their address without one, so clients behind a proxy share one.
+    pub kv_rate_limit: Option<RateLimit>,
+    /// Unlimited by default, a proxy moving keys between stores uses these
+    /// routes for every key.
+    pub admin_rate_limit: Option<RateLimit>,
+    /// See `Fault` for the format.
+    pub faults: Vec<Fault>,
+    /// Lets anyone scrape `/metrics`, not just admins.
+    pub public_metrics: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// See `ApiKeys` for the format.
+    pub api_keys: Option<PathBuf>,
+    /// See `Policy` for the format.
+    pub acl: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// Base URL of the leader, like `http://10.0.0.1:3000`. With one, this
+    /// instance is a read-only follower of it.
+    pub leader: Option<String>,
+    /// Admin API key of the leader.
+    pub api_key: Option<String>,
+    /// How long to wait before reconnecting to the leader.
+    pub retry_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Id of this node. With one, the store is a node of a Raft cluster.
+    /// Nodes keep their log in memory, one that restarts has to be added
+    /// again under a new id with an empty store. The node notes down that
+    /// it ran next to the write-ahead log, in a file ending in `.node`.
+    pub id: Option<NodeId>,
+    /// The members the cluster starts out with, the same on each of them.
+    /// Empty for a node that waits to be added to a running cluster.
+    pub members: Vec<MemberConfig>,
+    /// Admin API key the nodes use with each other.
+    pub api_key: Option<String>,
+    pub heartbeat_ms: u64,
+    pub election_timeout_ms: u64,
+    /// Applied entries kept in the log, see `ClusterOptions`.
+    pub max_log_entries: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemberConfig {
+    pub id: NodeId,
+    /// Base URL of the member, like `http://10.0.0.1:3000`.
+    pub url: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            resp_bind: None,
+            log_level: "debug".to_string(),
+            log_format: LogFormat::Text,
+            storage: StorageConfig::default(),
+            http: HttpConfig::default(),
+            auth: AuthConfig::default(),
+            replication: ReplicationConfig::default(),
+            cluster: ClusterConfig::default(),
+        }
+    }
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        let options = ClusterOptions::default();
+        Self {
+            id: None,
+            members: Vec::new(),
+            api_key: None,
+            heartbeat_ms: options.heartbeat.as_millis() as u64,
+            election_timeout_ms: options.election_timeout.as_millis() as u64,
+            max_log_entries: options.max_log_entries,
+        }
+    }
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            leader: None,
+            api_key: None,
+            retry_ms: 1000,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("kv.log"),
+            fsync: Fsync::Always,
+            fsync_interval_ms: 1000,
+            compact_threshold_bytes: 64 * 1024 * 1024,
+            compact_interval_ms: 1000,
+            reap_interval_ms: 1000,
+            max_keys: None,
+            max_bytes: None,
+            eviction: EvictionPolicy::Lru,
+            shards: shard::DEFAULT_SHARDS,
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        let options = RouterOptions::default();
+        Self {
+            body_limit_bytes: options.body_limit,
+            timeout_ms: options.timeout.as_millis() as u64,
+            shutdown_timeout_ms: 10_000,
+            kv_rate_limit: options.kv_rate_limit,
+            admin_rate_limit: options.admin_rate_limit,
+            faults: Vec::new(),
+            public_metrics: options.public_metrics,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let context = |kind, err: &dyn std::fmt::Display| {
+            io::Error::new(kind, format!("{}: {err}", path.display()))
+        };
+        fs::read_to_string(path)
+            .map_err(|err| context(err.kind(), &err))?
+            .parse()
+            .map_err(|err| context(io::ErrorKind::InvalidData, &err))
+    }
+
+    /// Checks the settings that have a valid type but make no sense,
+    /// naming the first one that is wrong.
+    pub fn validate(&self) -> Result<(), String> {
+        if Level::from_str(&self.log_level).is_err() {
+            return Err(format!(
+                "log_level: {:?} is not one of trace, debug, info, warn or error",
+                self.log_level
+            ));
+        }
+        let positive = [
+            ("storage.fsync_interval_ms", self.storage.fsync_interval_ms),
+            (
+                "storage.compact_interval_ms",
+                self.storage.compact_interval_ms,
+            ),
+            ("storage.reap_interval_ms", self.storage.reap_interval_ms),
+            ("storage.shards", self.storage.shards as u64),
+            ("http.body_limit_bytes", self.http.body_limit_bytes as u64),
+            ("http.timeout_ms", self.http.timeout_ms),
+            ("http.shutdown_timeout_ms", self.http.shutdown_timeout_ms),
+            ("replication.retry_ms", self.replication.retry_ms),
+            ("cluster.heartbeat_ms", self.cluster.heartbeat_ms),
+        ];
+        for (name, value) in positive {
+            if value == 0 {
+                return Err(format!("{name} must be greater than 0"));
+            }
+        }
+        let rate_limits = [
+            ("http.kv_rate_limit", self.http.kv_rate_limit),
+            ("http.admin_rate_limit", self.http.admin_rate_limit),
+        ];
+        for (name, limit) in rate_limits {
+            if limit.is_some_and(|limit| limit.burst == 0 || limit.per_second == 0) {
+                return Err(format!(
+                    "{name}: burst and per_second must be greater than 0"
+                ));
+            }
+        }
+        for (i, fault) in self.http.faults.iter().enumerate() {
+            fault
+                .validate()
+                .map_err(|err| format!("http.faults[{i}].{err}"))?;
+        }
+        if let Some(leader) = &self.replication.leader {
+            self.leader()
+                .map_err(|err| format!("replication.leader: {leader:?} {err}"))?;
+            if self.replication.api_key.is_none() {
+                return Err("replication.api_key is needed to follow a leader".to_string());
+            }
+        }
+        if self.cluster.id.is_some() {
+            self.validate_cluster()?;
+        }
+        Ok(())
+    }
+
+    fn validate_cluster(&self) -> Result<(), String> {
+        if self.replication.leader.is_some() {
+            return Err(
+                "replication.leader: a node of a cluster can't follow a leader".to_string(),
+            );
+        }
+        if self.cluster.api_key.is_none() {
+            return Err("cluster.api_key is needed for a cluster".to_string());
+        }
+        if self.storage.max_keys.is_some() || self.storage.max_bytes.is_some() {
+            return Err(
+                "storage.max_keys and max_bytes can't be used by a node of a cluster".to_string(),
+            );
+        }
+        if self.cluster.election_timeout_ms < 2 * self.cluster.heartbeat_ms {
+            return Err(
+                "cluster.election_timeout_ms must be at least twice heartbeat_ms".to_string(),
+            );
+        }
+        let mut ids = HashSet::new();
+        for (i, member) in self.cluster.members.iter().enumerate() {
+            if !ids.insert(member.id) {
+                return Err(format!(
+                    "cluster.members[{i}].id: {} is used twice",
+                    member.id
+                ));
+            }
+            http_url(&member.url)
+                .map_err(|err| format!("cluster.members[{i}].url: {:?} {err}", member.url))?;
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Level {
+        self.log_level.parse().unwrap_or(Level::DEBUG)
+    }
+
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        match self.storage.fsync {
+            Fsync::Always => FsyncPolicy::Always,
+            Fsync::Interval => {
+                FsyncPolicy::Interval(Duration::from_millis(self.storage.fsync_interval_ms))
+            }
+            Fsync::Never => FsyncPolicy::Never,
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_bytes: self.storage.max_bytes,
+            max_keys: self.storage.max_keys,
+            policy: self.storage.eviction,
+        }
+    }
+
+    /// The leader to follow, if this instance is a follower.
+    pub fn leader(&self) -> Result<Option<Uri>, String> {
+        let Some(leader) = &self.replication.leader else {
+            return Ok(None);
+        };
+        http_url(leader).map(Some)
+    }
+
+    /// The settings of the node, if the store is a node of a cluster.
+    pub fn cluster_options(&self) -> Option<ClusterOptions> {
+        Some(ClusterOptions {
+            id: self.cluster.id?,
+            members: self
+                .cluster
+                .members
+                .iter()
+                .map(|member| (member.id, member.url.clone()))
+                .collect(),
+            api_key: self.cluster.api_key.clone().unwrap_or_default(),
+            heartbeat: Duration::from_millis(self.cluster.heartbeat_ms),
+            election_timeout: Duration::from_millis(self.cluster.election_timeout_ms),
+            max_log_entries: self.cluster.max_log_entries,
+            path: Some(self.storage.path.with_extension("node")),
+        })
+    }
+
+    /// Reads the API keys and ACL the config points to.
+    pub fn router_options(&self) -> io::Result<RouterOptions> {
+        Ok(RouterOptions {
+            api_keys: match &self.auth.api_keys {
+                Some(path) => ApiKeys::load(path)?,
+                None => ApiKeys::default(),
+            },
+            acl: self.auth.acl.as_ref().map(Policy::load).transpose()?,
+            kv_rate_limit: self.http.kv_rate_limit,
+            admin_rate_limit: self.http.admin_rate_limit,
+            body_limit: self.http.body_limit_bytes,
+            timeout: Duration::from_millis(self.http.timeout_ms),
+            faults: self.http.faults.clone(),
+            read_only: self.replication.leader.is_some(),
+            public_metrics: self.http.public_metrics,
+            cluster: None,
+        })
+    }
+}
+
+fn http_url(url: &str) -> Result<Uri, String> {
+    let uri: Uri = url.parse().map_err(|err| format!("{err}"))?;
+    if uri.scheme_str() != Some("http") || uri.authority().is_none() {
+        return Err("is not an http:// URL".to_string());
+    }
+    Ok(uri)
+}
+
+impl FromStr for Config {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Config, Fsync};
+    use crate::{EvictionPolicy, FsyncPolicy, LogFormat, RateLimit};
+
+    #[test]
+    fn defaults() {
+        let config: Config = "".parse().unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.bind.to_string(), "127.0.0.1:3000");
+        assert_eq!(config.fsync_policy(), FsyncPolicy::Always);
+
+        let options = config.router_options().unwrap();
+        assert!(options.api_keys.is_empty());
+        assert!(options.acl.is_none());
+        assert_eq!(options.timeout, Duration::from_secs(4));
+    }
+
+    #[test]
+    fn partial_config() {
+        let config: Config = r#"
+            bind = "0.0.0.0:8080"
+            resp_bind = "0.0.0.0:6380"
+            log_level = "info"
+            log_format = "logfmt"
+
+            [storage]
+            fsync = "interval"
+            fsync_interval_ms = 50
+            eviction = "lfu"
+
+            [http]
+            kv_rate_limit = { burst = 5, per_second = 1 }
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.bind.port(), 8080);
+        assert_eq!(config.resp_bind.map(|addr| addr.port()), Some(6380));
+        assert_eq!(config.log_level(), tracing::Level::INFO);
+        assert_eq!(config.log_format, LogFormat::Logfmt);
+        assert_eq!(config.storage.fsync, Fsync::Interval);
+        assert_eq!(
+            config.fsync_policy(),
+            FsyncPolicy::Interval(Duration::from_millis(50))
+        );
+        assert_eq!(config.limits().policy, EvictionPolicy::Lfu);
+        assert_eq!(
+            config.http.kv_rate_limit,
+            Some(RateLimit {
+                burst: 5,
+                per_second: 1
+            })
+        );
+        assert_eq!(config.http.timeout_ms, 4000);
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert!("bind = \"localhost\"".parse::<Config>().is_err());
+        assert!("[storage]\nfsync = \"sometimes\""
+            .parse::<Config>()
+            .is_err());
+        assert!("[http]\ntimeout = 5".parse::<Config>().is_err());
+
+        let config: Config = "log_level = \"loud\"".parse().unwrap();
+        assert!(config.validate().unwrap_err().starts_with("log_level"));
+        let config: Config = "[http]\ntimeout_ms = 0".parse().unwrap();
+        assert_eq!(
+            config.validate(),
+            Err("http.timeout_ms must be greater than 0".to_string())
+        );
+        let config: Config = "[http]\nadmin_rate_limit = { burst = 0, per_second = 1 }"
+            .parse()
+            .unwrap();
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .starts_with("http.admin_rate_limit"));
+        let config: Config = "[[http.faults]]\nroute = \"/kv/:key\"\nerror_rate = 2.0"
+            .parse()
+            .unwrap();
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .starts_with("http.faults[0].error_rate"));
+        let config: Config = "[replication]\nleader = \"10.0.0.1:3000\"\napi_key = \"secret\""
+            .parse()
+            .unwrap();
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .starts_with("replication.leader"));
+        let config: Config = "[replication]\nleader = \"http://10.0.0.1:3000\""
+            .parse()
+            .unwrap();
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .starts_with("replication.api_key"));
+    }
+
+    #[test]
+    fn follower_config() {
+        let config: Config = r#"
+            [replication]
+            leader = "http://10.0.0.1:3000"
+            api_key = "secret"
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(
+            config.leader().unwrap().unwrap().to_string(),
+            "http://10.0.0.1:3000/"
+        );
+        assert!(config.router_options().unwrap().read_only);
+        assert!(!Config::default().router_options().unwrap().read_only);
+    }
+
+    #[test]
+    fn cluster_config() {
+        let config: Config = r#"
+            [cluster]
+            id = 2
+            api_key = "secret"
+            members = [
+                { id = 1, url = "http://10.0.0.1:3000" },
+                { id = 2, url = "http://10.0.0.2:3000" },
+            ]
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        let options = config.cluster_options().unwrap();
+        assert_eq!(options.id, 2);
+        assert_eq!(options.members[&1], "http://10.0.0.1:3000");
+        assert!(Config::default().cluster_options().is_none());
+
+        let mut invalid = config.clone();
+        invalid.cluster.members[1].id = 1;
+        assert!(invalid
+            .validate()
+            .unwrap_err()
+            .starts_with("cluster.members[1].id"));
+        let mut invalid = config.clone();
+        invalid.cluster.election_timeout_ms = invalid.cluster.heartbeat_ms;
+        assert!(invalid
+            .validate()
+            .unwrap_err()
+            .starts_with("cluster.election_timeout_ms"));
+        let mut invalid = config.clone();
+        invalid.storage.max_keys = Some(100);
+        assert!(invalid
+            .validate()
+            .unwrap_err()
+            .starts_with("storage.max_keys"));
+        let mut invalid = config;
+        invalid.replication.leader = Some("http://10.0.0.3:3000".to_string());
+        invalid.replication.api_key = Some("secret".to_string());
+        assert!(invalid
+            .validate()
+            .unwrap_err()
+            .starts_with("replication.leader"));
+    }
+}
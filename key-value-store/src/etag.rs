@@ -0,0 +1,151 @@
+use axum::http::{header, HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+
+/// Strong validator of a value, derived from its content so that it stays
+/// the same across restarts and compactions.
+pub(crate) fn hash(value: &[u8]) -> u64 {
+    xxhash_rust::xxh3::xxh3_64(value)
+}
+
+/// The quoted form used in the `ETag` header.
+pub(crate) fn format(hash: u64) -> String {
+    format!("\"{hash:016x}\"")
+}
+
+/// Evaluates `If-Match` and `If-None-Match` of a write against the current
+/// ETag of the entry, `None` if there is no entry.
+pub(crate) fn check_write(headers: &HeaderMap, current: Option<&str>) -> Result<(), StatusCode> {
+    check(
+        header_value(headers, header::IF_MATCH),
+        header_value(headers, header::IF_NONE_MATCH),
+        current,
+    )
+}
+
+/// The `If-Match` and `If-None-Match` of a write, kept to evaluate them
+/// later, like once a cluster applies the write.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Preconditions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) if_match: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) if_none_match: Option<String>,
+}
+
+impl Preconditions {
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        Self {
+            if_match: header_value(headers, header::IF_MATCH).map(str::to_string),
+            if_none_match: header_value(headers, header::IF_NONE_MATCH).map(str::to_string),
+        }
+    }
+
+    /// Like `check_write`.
+    pub(crate) fn check(&self, current: Option<&str>) -> Result<(), StatusCode> {
+        check(
+            self.if_match.as_deref(),
+            self.if_none_match.as_deref(),
+            current,
+        )
+    }
+}
+
+fn check(
+    if_match: Option<&str>,
+    if_none_match: Option<&str>,
+    current: Option<&str>,
+) -> Result<(), StatusCode> {
+    if let Some(if_match) = if_match {
+        if !matches(if_match, current, true) {
+            return Err(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+    if let Some(if_none_match) = if_none_match {
+        if matches(if_none_match, current, false) {
+            return Err(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+    Ok(())
+}
+
+/// Whether a read can be answered with `304 Not Modified`.
+pub(crate) fn not_modified(headers: &HeaderMap, current: &str) -> bool {
+    header_value(headers, header::IF_NONE_MATCH)
+        .is_some_and(|if_none_match| matches(if_none_match, Some(current), false))
+}
+
+fn header_value(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    // A header we can't read can't match anything.
+    headers.get(name).map(|value| value.to_str().unwrap_or(""))
+}
+
+/// Checks a list of entity tags, or `*`, against the current ETag. Weak
+/// tags never match under strong comparison.
+fn matches(list: &str, current: Option<&str>, strong: bool) -> bool {
+    let Some(current) = current else {
+        return false;
+    };
+    list.split(',').map(str::trim).any(|tag| {
+        if tag == "*" {
+            return true;
+        }
+        match tag.strip_prefix("W/") {
+            Some(weak) => !strong && weak == current,
+            None => tag == current,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue, StatusCode};
+
+    use super::check_write;
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn if_match() {
+        let current = Some("\"a\"");
+        assert_eq!(check_write(&headers("if-match", "\"a\""), current), Ok(()));
+        assert_eq!(
+            check_write(&headers("if-match", "\"b\", \"a\""), current),
+            Ok(())
+        );
+        assert_eq!(check_write(&headers("if-match", "*"), current), Ok(()));
+        assert_eq!(
+            check_write(&headers("if-match", "\"b\""), current),
+            Err(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(
+            check_write(&headers("if-match", "W/\"a\""), current),
+            Err(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(
+            check_write(&headers("if-match", "*"), None),
+            Err(StatusCode::PRECONDITION_FAILED)
+        );
+    }
+
+    #[test]
+    fn if_none_match() {
+        let current = Some("\"a\"");
+        assert_eq!(
+            check_write(&headers("if-none-match", "\"b\""), current),
+            Ok(())
+        );
+        assert_eq!(check_write(&headers("if-none-match", "*"), None), Ok(()));
+        assert_eq!(
+            check_write(&headers("if-none-match", "W/\"a\""), current),
+            Err(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(
+            check_write(&headers("if-none-match", "*"), current),
+            Err(StatusCode::PRECONDITION_FAILED)
+        );
+    }
+}
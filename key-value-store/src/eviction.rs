@@ -0,0 +1,433 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{durable, wal::Record, AppState};
+
+/// Which entry goes first once the store is over its limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionPolicy {
+    /// Least recently used.
+    #[default]
+    Lru,
+    /// Least frequently used, ties go to the least recently used.
+    Lfu,
+}
+
+/// Upper bounds for the store, unbounded by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// Total size of all keys and values.
+    pub max_bytes: Option<usize>,
+    pub max_keys: Option<usize>,
+    pub policy: EvictionPolicy,
+}
+
+/// Where an entry stands in line for eviction, lowest first.
+type Rank = (u64, u64);
+
+/// Sizes, content hashes and access statistics for every entry of a shard.
+#[derive(Debug)]
+pub(crate) struct Usage {
+    entries: HashMap<String, Entry>,
+    /// The entries in the order they get evicted in, kept only while the
+    /// store has limits.
+    order: Mutex<BTreeMap<Rank, String>>,
+    policy: Option<EvictionPolicy>,
+    bytes: usize,
+    /// Shared by all shards, so that their accesses can be compared.
+    clock: Arc<AtomicU64>,
+    pub(crate) evictions: u64,
+    pub(crate) expirations: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    size: usize,
+    etag: u64,
+    last_access: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl Usage {
+    pub(crate) fn new(clock: Arc<AtomicU64>) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: Mutex::new(BTreeMap::new()),
+            policy: None,
+            bytes: 0,
+            clock,
+            evictions: 0,
+            expirations: 0,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &str, size: usize, etag: u64) {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        let entry = Entry {
+            size,
+            etag,
+            last_access: AtomicU64::new(now),
+            hits: AtomicU64::new(0),
+        };
+        if let Some(rank) = self.rank(&entry) {
+            self.order.get_mut().unwrap().insert(rank, key.to_string());
+        }
+        if let Some(old) = self.entries.insert(key.to_string(), entry) {
+            self.unrank(&old);
+            self.bytes -= old.size;
+        }
+        self.bytes += size;
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some(old) = self.entries.remove(key) {
+            self.unrank(&old);
+            self.bytes -= old.size;
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.get_mut().unwrap().clear();
+        self.bytes = 0;
+    }
+
+    /// Records a read. Only needs shared access, so reads don't have to
+    /// take the write lock.
+    pub(crate) fn touch(&self, key: &str) {
+        let Some(entry) = self.entries.get(key) else {
+            return;
+        };
+        let touch = || {
+            let now = self.clock.fetch_add(1, Ordering::Relaxed);
+            entry.last_access.store(now, Ordering::Relaxed);
+            entry.hits.fetch_add(1, Ordering::Relaxed);
+        };
+        if self.policy.is_none() {
+            return touch();
+        }
+        // Concurrent reads of the entry move it one at a time.
+        let mut order = self.order.lock().unwrap();
+        order.remove(&self.rank(entry).unwrap());
+        touch();
+        order.insert(self.rank(entry).unwrap(), key.to_string());
+    }
+
+    /// Keeps the entries in order for `policy`, or stops keeping them in
+    /// order with `None`.
+    pub(crate) fn set_policy(&mut self, policy: Option<EvictionPolicy>) {
+        self.policy = policy;
+        let order = self.order.get_mut().unwrap();
+        order.clear();
+        if policy.is_some() {
+            for (key, entry) in &self.entries {
+                let rank = Self::rank_for(policy, entry).unwrap();
+                order.insert(rank, key.clone());
+            }
+        }
+    }
+
+    fn rank(&self, entry: &Entry) -> Option<Rank> {
+        Self::rank_for(self.policy, entry)
+    }
+
+    fn rank_for(policy: Option<EvictionPolicy>, entry: &Entry) -> Option<Rank> {
+        let last_access = entry.last_access.load(Ordering::Relaxed);
+        let hits = entry.hits.load(Ordering::Relaxed);
+        match policy? {
+            EvictionPolicy::Lru => Some((last_access, 0)),
+            EvictionPolicy::Lfu => Some((hits, last_access)),
+        }
+    }
+
+    fn unrank(&mut self, entry: &Entry) {
+        if let Some(rank) = self.rank(entry) {
+            self.order.get_mut().unwrap().remove(&rank);
+        }
+    }
+
+    pub(crate) fn size(&self, key: &str) -> Option<usize> {
+        self.entries.get(key).map(|entry| entry.size)
+    }
+
+    pub(crate) fn etag(&self, key: &str) -> Option<u64> {
+        self.entries.get(key).map(|entry| entry.etag)
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Picks the entry to evict next, never one of `keep`, together with
+    /// its rank. The entry with the lowest rank of all shards goes first.
+    fn victim(&self, keep: &[String]) -> Option<(Rank, String)> {
+        self.order
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, key)| !keep.contains(key))
+            .map(|(rank, key)| (*rank, key.clone()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+    pub keys: usize,
+    pub bytes: usize,
+    pub max_keys: Option<usize>,
+    pub max_bytes: Option<usize>,
+    /// Entries removed to stay within the limits.
+    pub evictions: u64,
+    /// Entries removed because their TTL ran out.
+    pub expirations: u64,
+}
+
+/// How much an entry counts towards `Limits::max_bytes`.
+pub(crate) fn entry_size(key: &str, value: &[u8]) -> usize {
+    key.len() + value.len()
+}
+
+impl AppState {
+    /// Sets the limits. Call this before `with_log`, which evicts what the
+    /// replayed log holds beyond them.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        let policy = self.has_limits().then_some(limits.policy);
+        for shard in self.shards.iter_mut() {
+            shard.get_mut().unwrap().usage.set_policy(policy);
+        }
+        self
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            keys: 0,
+            bytes: 0,
+            max_keys: self.limits.max_keys,
+            max_bytes: self.limits.max_bytes,
+            evictions: 0,
+            expirations: 0,
+        };
+        for shard in self.read_shards() {
+            stats.keys += shard.db.len();
+            stats.bytes += shard.usage.bytes();
+            stats.evictions += shard.usage.evictions;
+            stats.expirations += shard.usage.expirations;
+        }
+        stats
+    }
+
+    /// Whether the store has a limit on its keys or bytes.
+    pub(crate) fn has_limits(&self) -> bool {
+        self.limits.max_keys.is_some() || self.limits.max_bytes.is_some()
+    }
+
+    /// Whether an entry could be stored at all, even after evicting
+    /// everything else.
+    pub(crate) fn fits(&self, key: &str, value: &[u8]) -> bool {
+        self.limits
+            .max_bytes
+            .is_none_or(|max| entry_size(key, value) <= max)
+            && self.limits.max_keys != Some(0)
+    }
+
+    /// Evicts entries until the store is within its limits again. Expired
+    /// entries go first. Every eviction is logged, as it depends on reads
+    /// that a replay of the log doesn't know about. Keys in `keep` are never
+    /// evicted.
+    pub(crate) fn enforce_limits(&self, keep: &[String]) -> io::Result<()> {
+        if !self.over_limits() {
+            return Ok(());
+        }
+        let _evicting = self.evicting.lock().unwrap();
+        self.evict_expired();
+        while self.over_limits() {
+            let Some(key) = self.victim(keep) else {
+                break;
+            };
+            let mut shard = self.write_shard(&key);
+            // Someone else may have removed it in the meantime.
+            if shard.usage.size(&key).is_none() {
+                continue;
+            }
+            let pending = self.append(&Record::Delete { key: key.clone() })?;
+            self.remove_entry(&mut shard, &key);
+            shard.usage.evictions += 1;
+            drop(shard);
+            durable(pending)?;
+        }
+        Ok(())
+    }
+
+    fn victim(&self, keep: &[String]) -> Option<String> {
+        self.read_shards()
+            .filter_map(|shard| shard.usage.victim(keep))
+            .min()
+            .map(|(_, key)| key)
+    }
+
+    fn over_limits(&self) -> bool {
+        let Limits {
+            max_bytes,
+            max_keys,
+            ..
+        } = self.limits;
+        let bytes = || {
+            self.read_shards()
+                .map(|shard| shard.usage.bytes())
+                .sum::<usize>()
+        };
+        max_bytes.is_some_and(|max| bytes() > max) || max_keys.is_some_and(|max| self.len() > max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use axum::body::Bytes;
+
+    use super::{EvictionPolicy, Limits};
+    use crate::{AppState, FsyncPolicy};
+
+    fn set(db: &mut AppState, key: &str, value: &'static [u8]) {
+        db.set(key.to_string(), Bytes::from_static(value)).unwrap();
+    }
+
+    fn keys(db: &AppState) -> Vec<String> {
+        let mut keys = db.keys();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut db = AppState::default().with_limits(Limits {
+            max_keys: Some(3),
+            ..Default::default()
+        });
+        set(&mut db, "a", b"1");
+        set(&mut db, "b", b"2");
+        set(&mut db, "c", b"3");
+        db.get("a");
+
+        set(&mut db, "d", b"4");
+        assert_eq!(keys(&db), vec!["a", "c", "d"]);
+        assert_eq!(db.stats().evictions, 1);
+    }
+
+    #[test]
+    fn lfu_evicts_least_frequently_used() {
+        let mut db = AppState::default().with_limits(Limits {
+            max_keys: Some(3),
+            policy: EvictionPolicy::Lfu,
+            ..Default::default()
+        });
+        set(&mut db, "a", b"1");
+        set(&mut db, "b", b"2");
+        set(&mut db, "c", b"3");
+        db.get("a");
+        db.get("a");
+        db.get("b");
+        db.get("c");
+        db.get("c");
+
+        set(&mut db, "d", b"4");
+        assert_eq!(keys(&db), vec!["a", "c", "d"]);
+    }
+
+    #[test]
+    fn limits_cover_earlier_entries() {
+        let mut db = AppState::default();
+        set(&mut db, "a", b"1");
+        set(&mut db, "b", b"2");
+        set(&mut db, "c", b"3");
+        db.get("a");
+        let mut db = db.with_limits(Limits {
+            max_keys: Some(2),
+            ..Default::default()
+        });
+
+        set(&mut db, "d", b"4");
+        assert_eq!(keys(&db), vec!["a", "d"]);
+    }
+
+    #[test]
+    fn max_bytes_evicts_until_the_value_fits() {
+        let mut db = AppState::default().with_limits(Limits {
+            max_bytes: Some(10),
+            ..Default::default()
+        });
+        set(&mut db, "a", b"1234");
+        set(&mut db, "b", b"1234");
+        assert_eq!(db.stats().bytes, 10);
+
+        // Overwriting a value frees up its old size.
+        set(&mut db, "b", b"1");
+        set(&mut db, "c", b"12");
+        assert_eq!(keys(&db), vec!["a", "b", "c"]);
+
+        set(&mut db, "d", b"12345678");
+        assert_eq!(keys(&db), vec!["d"]);
+        assert_eq!(db.stats().evictions, 3);
+
+        let err = db
+            .set("e".to_string(), Bytes::from_static(b"1234567890"))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(keys(&db), vec!["d"]);
+    }
+
+    #[test]
+    fn evictions_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+        let limits = Limits {
+            max_keys: Some(2),
+            ..Default::default()
+        };
+
+        let mut db = AppState::default()
+            .with_limits(limits)
+            .with_log(&path, FsyncPolicy::Always)
+            .unwrap();
+        set(&mut db, "a", b"1");
+        set(&mut db, "b", b"2");
+        db.get("a");
+        set(&mut db, "c", b"3");
+        drop(db);
+
+        let db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(keys(&db), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn replayed_log_is_held_to_the_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+        let mut db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        set(&mut db, "a", b"1");
+        set(&mut db, "b", b"2");
+        set(&mut db, "c", b"3");
+        drop(db);
+
+        let db = AppState::default()
+            .with_limits(Limits {
+                max_keys: Some(2),
+                ..Default::default()
+            })
+            .with_log(&path, FsyncPolicy::Always)
+            .unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.stats().evictions, 1);
+    }
+}
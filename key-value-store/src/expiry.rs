@@ -0,0 +1,209 @@
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::body::Bytes;
+use tokio::{task::JoinHandle, time::Instant};
+
+use crate::{shard::Shard, wal::Record, AppState, SharedState};
+
+/// Longest TTL an entry can have, about a hundred years. Longer ones would
+/// take the expiry past what an `Instant` can hold.
+pub(crate) const MAX_TTL: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+impl AppState {
+    /// Stores `value` under `key` for `ttl`, after which it is gone. Fails
+    /// with `InvalidInput` for a TTL longer than about a hundred years.
+    pub fn set_with_ttl(&self, key: String, value: Bytes, ttl: Duration) -> io::Result<()> {
+        self.commit_entry(set_record(key, value, Some(ttl))?)
+    }
+
+    /// Time left until `key` expires, `None` if it never does.
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        let shard = self.read_shard(key);
+        let at = shard.expiries.get(key)?;
+        Some(at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Removes every expired entry from the storage and returns how many
+    /// there were. Shards are cleaned up one at a time.
+    ///
+    /// Nothing is written to the log: the expiry is part of the record that
+    /// set the value, so a replay drops the entry all the same.
+    pub fn evict_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut evicted = 0;
+        for mut shard in self.write_shards() {
+            let expired: Vec<String> = shard
+                .expiries
+                .iter()
+                .filter(|(_, at)| **at <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+
+            for key in &expired {
+                self.remove_entry(&mut shard, key);
+            }
+            shard.usage.expirations += expired.len() as u64;
+            evicted += expired.len();
+        }
+        evicted
+    }
+
+    fn has_expired_entries(&self) -> bool {
+        let now = Instant::now();
+        self.read_shards()
+            .any(|shard| shard.expiries.values().any(|at| *at <= now))
+    }
+}
+
+impl Shard {
+    pub(crate) fn is_expired(&self, key: &str) -> bool {
+        self.expiries
+            .get(key)
+            .is_some_and(|at| *at <= Instant::now())
+    }
+}
+
+/// The record for a write that expires after `ttl`, if there is one.
+/// Fails with `InvalidInput` if `ttl` is longer than `MAX_TTL`.
+pub(crate) fn set_record(key: String, value: Bytes, ttl: Option<Duration>) -> io::Result<Record> {
+    match ttl {
+        Some(ttl) if ttl > MAX_TTL => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("a TTL of {}s is too long", ttl.as_secs()),
+        )),
+        Some(ttl) => Ok(Record::SetEx {
+            key,
+            value,
+            expires_at: to_unix_millis(Instant::now() + ttl),
+        }),
+        None => Ok(Record::Set { key, value }),
+    }
+}
+
+pub(crate) fn to_unix_millis(at: Instant) -> u64 {
+    let at = SystemTime::now() + at.saturating_duration_since(Instant::now());
+    at.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Converts an expiry from the log back to an instant, which may lie in
+/// the past.
+pub(crate) fn from_unix_millis(millis: u64) -> Instant {
+    let at = UNIX_EPOCH + Duration::from_millis(millis);
+    match at.duration_since(SystemTime::now()) {
+        Ok(left) => Instant::now() + left.min(MAX_TTL),
+        Err(_) => Instant::now(),
+    }
+}
+
+/// Evicts expired entries every `every`. Reads already skip them, this only
+/// frees up the memory. The task ends once the state is dropped.
+pub fn spawn_reaper(state: &SharedState, every: Duration) -> JoinHandle<()> {
+    let state = Arc::downgrade(state);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            let Some(state) = state.upgrade() else {
+                break;
+            };
+            let db = state.read().await;
+            if db.has_expired_entries() {
+                db.evict_expired();
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::Bytes;
+
+    use tokio::time::Instant;
+
+    use super::{from_unix_millis, spawn_reaper};
+    use crate::{AppState, FsyncPolicy, SharedState};
+
+    #[tokio::test(start_paused = true)]
+    async fn reaper_evicts_expired_entries() {
+        let state = SharedState::default();
+        {
+            let db = state.write().await;
+            db.set_with_ttl(
+                "a".to_string(),
+                Bytes::from_static(b"1"),
+                Duration::from_secs(5),
+            )
+            .unwrap();
+            db.set("b".to_string(), Bytes::from_static(b"2")).unwrap();
+        }
+        spawn_reaper(&state, Duration::from_secs(1));
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(state.read().await.len(), 2);
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let db = state.read().await;
+        assert_eq!(db.len(), 1);
+        assert!(db.get("b").is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn plain_set_clears_ttl() {
+        let db = AppState::default();
+        db.set_with_ttl(
+            "a".to_string(),
+            Bytes::from_static(b"1"),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert!(db.ttl("a").unwrap() <= Duration::from_secs(5));
+        db.set("a".to_string(), Bytes::from_static(b"2")).unwrap();
+        assert_eq!(db.ttl("a"), None);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(&db.get("a").unwrap()[..], b"2");
+    }
+
+    #[tokio::test]
+    async fn expiry_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+
+        let db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        db.set_with_ttl(
+            "a".to_string(),
+            Bytes::from_static(b"1"),
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+        db.set_with_ttl("b".to_string(), Bytes::from_static(b"2"), Duration::ZERO)
+            .unwrap();
+        drop(db);
+
+        let db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        assert!(db.ttl("a").unwrap() > Duration::from_secs(3500));
+        assert!(db.get("b").is_none());
+        assert_eq!(db.len(), 1);
+    }
+
+    #[test]
+    fn ttl_is_bounded() {
+        let db = AppState::default();
+        let err = db
+            .set_with_ttl("a".to_string(), Bytes::from_static(b"1"), Duration::MAX)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(db.is_empty());
+
+        // Expiries from the log can't overflow either.
+        assert!(from_unix_millis(u64::MAX) > Instant::now());
+    }
+}
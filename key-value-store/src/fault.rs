@@ -0,0 +1,178 @@
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    extract::MatchedPath,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use hyper::Request;
+use rand::Rng;
+use serde::Deserialize;
+use tower::{Layer, Service};
+use tracing::{event, Level};
+
+/// Latency and errors injected into the requests of a route, to see how
+/// clients cope with a slow or flaky store. Meant for staging, there are
+/// none unless configured, like
+///
+/// ```toml
+/// [[http.faults]]
+/// route = "/kv/:key"
+/// method = "GET"
+/// latency_ms = 500
+/// jitter_ms = 250
+/// error_rate = 0.01
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fault {
+    /// The route as it is declared, like `/kv/:key`, or `*` for all of
+    /// them.
+    pub route: String,
+    /// Only requests with this method, all of them without one.
+    pub method: Option<String>,
+    pub latency_ms: u64,
+    /// Up to this much more latency, picked at random for each request.
+    pub jitter_ms: u64,
+    /// Share of requests, from 0 to 1, that fail with `status` after the
+    /// latency instead of being handled.
+    pub error_rate: f64,
+    pub status: u16,
+}
+
+impl Default for Fault {
+    fn default() -> Self {
+        Self {
+            route: "*".to_string(),
+            method: None,
+            latency_ms: 0,
+            jitter_ms: 0,
+            error_rate: 0.0,
+            status: 503,
+        }
+    }
+}
+
+impl Fault {
+    /// Checks that the fault can be injected, saying what is wrong if not.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.error_rate) {
+            return Err(format!(
+                "error_rate must be between 0 and 1, not {}",
+                self.error_rate
+            ));
+        }
+        if !(400..600).contains(&self.status) {
+            return Err(format!(
+                "status must be an error status, not {}",
+                self.status
+            ));
+        }
+        Ok(())
+    }
+
+    fn matches<B>(&self, req: &Request<B>) -> bool {
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(MatchedPath::as_str);
+        (self.route == "*" || route == Some(self.route.as_str()))
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|method| req.method().as_str().eq_ignore_ascii_case(method))
+    }
+
+    fn latency(&self) -> Duration {
+        let jitter = match self.jitter_ms {
+            0 => 0,
+            jitter => rand::thread_rng().gen_range(0..=jitter),
+        };
+        Duration::from_millis(self.latency_ms + jitter)
+    }
+
+    fn fails(&self) -> bool {
+        self.error_rate > 0.0 && rand::thread_rng().gen_bool(self.error_rate)
+    }
+}
+
+#[derive(Clone)]
+pub struct FaultService<S> {
+    inner: S,
+    faults: Arc<[Fault]>,
+}
+
+impl<S, B> Service<Request<B>> for FaultService<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let fault = self
+            .faults
+            .iter()
+            .find(|fault| fault.matches(&req))
+            .cloned();
+        let mut this = self.inner.clone();
+        Box::pin(async move {
+            if let Some(fault) = fault {
+                let latency = fault.latency();
+                if !latency.is_zero() {
+                    tokio::time::sleep(latency).await;
+                }
+                if fault.fails() {
+                    event!(Level::DEBUG, status = fault.status, "injected an error");
+                    let status = StatusCode::from_u16(fault.status)
+                        .unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+                    return Ok(status.into_response());
+                }
+            }
+            this.call(req).await
+        })
+    }
+}
+
+/// Injects the first of `faults` that matches into each request.
+#[derive(Clone)]
+pub(crate) struct FaultLayer {
+    faults: Arc<[Fault]>,
+}
+
+impl FaultLayer {
+    /// Panics if one of the faults is invalid.
+    pub(crate) fn new(faults: Vec<Fault>) -> Self {
+        for fault in &faults {
+            if let Err(err) = fault.validate() {
+                panic!("invalid fault for {}: {err}", fault.route);
+            }
+        }
+        Self {
+            faults: faults.into(),
+        }
+    }
+}
+
+impl<S> Layer<S> for FaultLayer {
+    type Service = FaultService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        FaultService {
+            inner,
+            faults: Arc::clone(&self.faults),
+        }
+    }
+}
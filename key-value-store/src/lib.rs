@@ -1,18 +1,35 @@
-use std::{collections::HashMap, convert::Infallible, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io,
+    ops::Bound,
+    path::Path as FsPath,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use axum::{
-    body::Bytes,
+    body::{Bytes, StreamBody},
     error_handling::HandleErrorLayer,
-    extract::{DefaultBodyLimit, Path, State},
+    extract::{DefaultBodyLimit, OriginalUri, Path, Query, State},
     handler::Handler,
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{delete, get},
-    BoxError, Router,
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    BoxError, Extension, Json, Router,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::{stream, Stream};
 use hyper::{Body, Request};
-use log::LogLayer;
-use tokio::sync::RwLock;
+use log::{LogLayer, RateLimitLayer, X_REQUEST_ID};
+use metrics::{Metrics, MetricsLayer};
+use namespace::NamespaceName;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast, RwLock},
+    time::Instant,
+};
 use tower::{timeout::TimeoutLayer, ServiceBuilder};
 use tower_http::{
     auth::RequireAuthorizationLayer, limit::RequestBodyLimitLayer, trace::TraceLayer,
@@ -23,56 +40,721 @@ pub type SharedState = Arc<RwLock<AppState>>;
 
 use tracing::{event, instrument, Level};
 
-use tracing_subscriber::FmtSubscriber;
+use acl::Access;
+use cluster::WriteError;
+use etag::Preconditions;
+use fault::FaultLayer;
+use shard::Shard;
+use wal::{PendingSync, Record, Wal};
+
+pub use acl::{Permission, Policy};
+pub use auth::{ApiKeys, Principal};
+pub use cluster::{Cluster, ClusterOptions, ClusterStatus};
+pub use compaction::{compact, spawn_compactor};
+pub use config::{
+    AuthConfig, ClusterConfig, Config, Fsync, HttpConfig, MemberConfig, ReplicationConfig,
+    StorageConfig,
+};
+pub use eviction::{EvictionPolicy, Limits, Stats};
+pub use expiry::spawn_reaper;
+pub use fault::Fault;
+pub use log::{init_tracing, LogFormat, RateLimit};
+pub use namespace::{NamespaceInfo, Quota};
+pub use proxy::{proxy_router, Proxy, ProxyOptions, Rebalanced};
+pub use raft::{Members, NodeId, Role};
+pub use replication::spawn_follower;
+pub use resp::spawn_resp;
+pub use ring::Ring;
+pub use server::serve;
+pub use shard::Entry;
+pub use storage::{MemoryStorage, Storage};
+pub use txn::{Op, OpResult, Transaction};
+pub use wal::FsyncPolicy;
+pub use watch::Event;
 
+mod acl;
+mod auth;
+mod cluster;
+mod compaction;
+mod config;
+mod etag;
+mod eviction;
+mod expiry;
+mod fault;
 mod log;
+mod metrics;
+mod namespace;
+mod proxy;
+mod raft;
+mod replication;
+mod resp;
+mod ring;
+mod server;
+mod shard;
+mod storage;
+mod txn;
+mod wal;
+mod watch;
+
+#[derive(Debug)]
+pub struct AppState {
+    shards: Box<[std::sync::RwLock<Shard>]>,
+    limits: Limits,
+    wal: Mutex<Option<Wal>>,
+    events: broadcast::Sender<Event>,
+    /// Every record that is logged, for the followers.
+    replication: broadcast::Sender<Record>,
+    namespaces: HashMap<String, namespace::Namespace>,
+    /// Held while evicting, so that writers that are over the limits at
+    /// the same time don't evict more than needed.
+    evicting: Mutex<()>,
+    /// Set while a compaction writes its snapshot.
+    compacting: bool,
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self::sharded(shard::DEFAULT_SHARDS, MemoryStorage::default)
+    }
+}
+
+impl AppState {
+    /// A store with a single shard kept in `storage`.
+    pub fn new(storage: impl Storage + 'static) -> Self {
+        Self::from_storages([Box::new(storage) as Box<dyn Storage>])
+    }
+
+    /// Opens an in-memory store backed by the write-ahead log at `path`.
+    pub fn open(path: impl AsRef<FsPath>, policy: FsyncPolicy) -> io::Result<Self> {
+        Self::default().with_log(path, policy)
+    }
+
+    /// Attaches the write-ahead log at `path`, loading the latest snapshot
+    /// and replaying everything that was written to the log after it.
+    pub fn with_log(mut self, path: impl AsRef<FsPath>, policy: FsyncPolicy) -> io::Result<Self> {
+        let interrupted = self.restore_snapshot(path.as_ref())?;
+        let (wal, records) = Wal::open(path, policy)?;
+        for record in records {
+            self.apply(record);
+        }
+        *self.wal.get_mut().unwrap() = Some(wal);
+        if interrupted {
+            self.finish_compaction()?;
+        }
+        self.enforce_limits(&[])?;
+        Ok(self)
+    }
+
+    /// Looks up `key`, treating entries that expired but were not evicted
+    /// yet as gone.
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let shard = self.read_shard(key);
+        if shard.is_expired(key) {
+            return None;
+        }
+        shard.usage.touch(key);
+        shard.db.get(key)
+    }
+
+    /// Like `get`, together with the `ETag` and TTL of the value. All three
+    /// are read under the same lock, a write can't fall in between.
+    pub fn entry(&self, key: &str) -> Option<Entry> {
+        self.read_shard(key).entry(key)
+    }
+
+    /// All keys of the default namespace in lexicographic order.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = Vec::new();
+        for shard in self.read_shards() {
+            keys.extend(shard.db.list().into_iter().filter(|key| {
+                !shard.is_expired(key) && namespace::split(key).0 == namespace::DEFAULT
+            }));
+        }
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Up to `limit` keys of the default namespace starting with `prefix` in
+    /// lexicographic order, beginning after the key `after`.
+    pub fn scan(&self, prefix: &str, after: Option<&str>, limit: usize) -> Vec<String> {
+        self.scan_in(namespace::DEFAULT, prefix, after, limit)
+    }
+
+    /// Up to `limit` entries of the default namespace with keys between
+    /// `start` and `end` in lexicographic order.
+    pub fn range(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: usize,
+    ) -> Vec<(String, Bytes)> {
+        self.range_in(namespace::DEFAULT, start, end, limit)
+    }
+
+    /// The `ETag` of the value stored under `key`, a hash of its content.
+    pub fn etag(&self, key: &str) -> Option<String> {
+        self.read_shard(key).etag(key)
+    }
+
+    /// Size of the value stored under `key`, without counting as a read.
+    pub fn value_len(&self, key: &str) -> Option<usize> {
+        let shard = self.read_shard(key);
+        if shard.is_expired(key) {
+            return None;
+        }
+        shard.db.get(key).map(|value| value.len())
+    }
+
+    /// Number of entries in the storage, including expired ones that were
+    /// not evicted yet.
+    pub fn len(&self) -> usize {
+        self.read_shards().map(|shard| shard.db.len()).sum()
+    }
 
-#[derive(Default, Debug)]
-pub struct AppState(HashMap<String, Bytes>);
+    pub fn is_empty(&self) -> bool {
+        self.read_shards().all(|shard| shard.db.is_empty())
+    }
+
+    /// Only locks the shard of `key`, writes of other keys can happen at
+    /// the same time.
+    pub fn set(&self, key: String, value: Bytes) -> io::Result<()> {
+        self.commit_entry(Record::Set { key, value })
+    }
+
+    pub fn remove(&self, key: &str) -> io::Result<()> {
+        self.commit_entry(Record::Delete {
+            key: key.to_string(),
+        })
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.commit(Record::Clear)
+    }
+
+    /// Size of the write-ahead log in bytes, zero without one.
+    pub fn log_size(&self) -> u64 {
+        self.wal.lock().unwrap().as_ref().map_or(0, Wal::size)
+    }
+
+    /// Flushes the write-ahead log, if there is one.
+    pub fn sync(&self) -> io::Result<()> {
+        match &*self.wal.lock().unwrap() {
+            Some(wal) => wal.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the record to the log before it is applied, so that everything
+    /// that was acknowledged can be replayed after a restart.
+    fn commit(&mut self, record: Record) -> io::Result<()> {
+        let mut written = Vec::new();
+        self.check_fits(&record, &mut written)?;
+        self.check_quotas(&record, |key| self.read_shard(key).usage.size(key))?;
 
+        let pending = self.append(&record)?;
+        self.apply(record);
+        durable(pending)?;
+        self.enforce_limits(&written)
+    }
+
+    fn commit_entry(&self, record: Record) -> io::Result<()> {
+        self.commit_if(record, |_| Ok::<_, Infallible>(()))?
+            .unwrap_or_else(|never| match never {});
+        Ok(())
+    }
+
+    /// Like `commit` for a record that writes a single key, if `check`
+    /// passes for the current `ETag` of the key. Only the shard of the key
+    /// is locked, and the quota of its namespace.
+    pub(crate) fn commit_if<E>(
+        &self,
+        record: Record,
+        check: impl FnOnce(Option<&str>) -> Result<(), E>,
+    ) -> io::Result<Result<(), E>> {
+        let key = record
+            .key()
+            .expect("only records of a single key can be committed without the whole store")
+            .to_string();
+        let mut written = Vec::new();
+        self.check_fits(&record, &mut written)?;
+
+        let mut shard = self.write_shard(&key);
+        let quota = self.lock_quota(&key);
+        if let Err(err) = check(shard.etag(&key).as_deref()) {
+            return Ok(Err(err));
+        }
+        self.check_quotas(&record, |key| shard.usage.size(key))?;
+        let pending = self.append(&record)?;
+        self.apply_entry(&mut shard, record);
+        drop(quota);
+        drop(shard);
+
+        // Readers and writers of the shard don't wait for the disk, the
+        // writer does before the record is acknowledged.
+        durable(pending)?;
+        self.enforce_limits(&written)?;
+        Ok(Ok(()))
+    }
+
+    /// Logs `record`, returning the sync it still has to wait for with
+    /// `durable`. The log is only locked while the record is written, and
+    /// writers share the syncs.
+    ///
+    /// A sync that fails leaves the record applied but maybe not on disk,
+    /// the log refuses any more writes then.
+    fn append(&self, record: &Record) -> io::Result<Option<PendingSync>> {
+        let pending = match &mut *self.wal.lock().unwrap() {
+            Some(wal) => wal.write(record)?,
+            None => None,
+        };
+        // Records of a key are logged under the lock of its shard, so
+        // followers get them in order.
+        if self.replication.receiver_count() > 0 {
+            let _ = self.replication.send(record.clone());
+        }
+        Ok(pending)
+    }
+
+    /// Collects the keys written by `record`, failing if any of the values
+    /// can never fit into the store.
+    fn check_fits(&self, record: &Record, written: &mut Vec<String>) -> io::Result<()> {
+        match record {
+            Record::Set { key, value } | Record::SetEx { key, value, .. } => {
+                if !self.fits(key, value) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::StorageFull,
+                        format!("{key} exceeds the limits of the store"),
+                    ));
+                }
+                written.push(key.clone());
+            }
+            Record::Batch(records) => {
+                for record in records {
+                    self.check_fits(record, written)?;
+                }
+            }
+            Record::Delete { .. }
+            | Record::Clear
+            | Record::Namespace { .. }
+            | Record::DropNamespace { .. } => {}
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Set { ref key, .. }
+            | Record::SetEx { ref key, .. }
+            | Record::Delete { ref key } => {
+                let mut shard = self.shard_of(key).write().unwrap();
+                self.apply_entry(&mut shard, record);
+            }
+            Record::Clear => {
+                self.write_shards().for_each(|mut shard| shard.clear());
+                self.clear_namespace_usage();
+                self.notify(Event::Clear);
+            }
+            Record::Batch(records) => {
+                for record in records {
+                    self.apply(record);
+                }
+            }
+            Record::Namespace { .. } | Record::DropNamespace { .. } => self.apply_namespace(record),
+        }
+    }
+
+    /// Applies a record of a single key to `shard`, the one the key is in.
+    fn apply_entry(&self, shard: &mut Shard, record: Record) {
+        match record {
+            Record::Set { key, value } => {
+                shard.expiries.remove(&key);
+                self.insert_entry(shard, key, value);
+            }
+            Record::SetEx {
+                key,
+                value,
+                expires_at,
+            } => {
+                let at = expiry::from_unix_millis(expires_at);
+                if at <= Instant::now() {
+                    self.remove_entry(shard, &key);
+                } else {
+                    shard.expiries.insert(key.clone(), at);
+                    self.insert_entry(shard, key, value);
+                }
+            }
+            Record::Delete { key } => self.remove_entry(shard, &key),
+            _ => unreachable!("not a record of a single key: {record:?}"),
+        }
+    }
+
+    fn insert_entry(&self, shard: &mut Shard, key: String, value: Bytes) {
+        let size = eviction::entry_size(&key, &value);
+        let before = shard.usage.size(&key);
+        shard.usage.insert(&key, size, etag::hash(&value));
+        self.account(&key, before, Some(size));
+        self.notify(Event::set(&key, &value));
+        shard.db.set(key, value);
+    }
+
+    fn remove_entry(&self, shard: &mut Shard, key: &str) {
+        shard.expiries.remove(key);
+        let before = shard.usage.size(key);
+        shard.usage.remove(key);
+        self.account(key, before, None);
+        if shard.db.delete(key).is_some() {
+            self.notify(Event::Delete {
+                key: key.to_string(),
+            });
+        }
+    }
+}
+
+/// Settings of the router that aren't part of the store itself.
+#[derive(Debug, Clone)]
+pub struct RouterOptions {
+    /// Keys accepted by the `/admin` routes, and by all others once there
+    /// is an `acl`. Without any, nobody gets in.
+    pub api_keys: ApiKeys,
+    /// Who may do what with which keys. Without one, every request may use
+    /// every key outside of `/admin`.
+    pub acl: Option<Policy>,
+    /// How often each client may use the routes outside of `/admin`,
+    /// unlimited without one.
+    pub kv_rate_limit: Option<RateLimit>,
+    /// How often each client may use the `/admin` routes, unlimited without
+    /// one.
+    pub admin_rate_limit: Option<RateLimit>,
+    /// Largest value that can be written, in bytes.
+    pub body_limit: usize,
+    /// How long a write may take before it fails with `408`.
+    pub timeout: Duration,
+    /// Latency and errors to inject, for testing clients. None by default.
+    pub faults: Vec<Fault>,
+    /// Whether only `GET` and `HEAD` requests are allowed, for followers.
+    pub read_only: bool,
+    /// Whether `/metrics` can be scraped without an admin key.
+    pub public_metrics: bool,
+    /// The cluster the store is a node of, its writes go through the log
+    /// of the cluster.
+    pub cluster: Option<Cluster>,
+}
+
+impl Default for RouterOptions {
+    fn default() -> Self {
+        Self {
+            api_keys: ApiKeys::default(),
+            acl: None,
+            kv_rate_limit: None,
+            admin_rate_limit: None,
+            body_limit: 1024 * 8000,
+            timeout: Duration::from_secs(4),
+            faults: Vec::new(),
+            read_only: false,
+            public_metrics: false,
+            cluster: None,
+        }
+    }
+}
+
+impl RouterOptions {
+    pub fn builder() -> RouterOptionsBuilder {
+        RouterOptionsBuilder::default()
+    }
+}
+
+/// Builds `RouterOptions`, starting from the defaults.
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct RouterOptionsBuilder {
+    options: RouterOptions,
+}
+
+impl RouterOptionsBuilder {
+    pub fn api_keys(mut self, api_keys: ApiKeys) -> Self {
+        self.options.api_keys = api_keys;
+        self
+    }
+
+    pub fn acl(mut self, acl: Policy) -> Self {
+        self.options.acl = Some(acl);
+        self
+    }
+
+    pub fn kv_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.options.kv_rate_limit = Some(limit);
+        self
+    }
+
+    pub fn admin_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.options.admin_rate_limit = Some(limit);
+        self
+    }
+
+    pub fn body_limit(mut self, bytes: usize) -> Self {
+        self.options.body_limit = bytes;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = timeout;
+        self
+    }
+
+    pub fn fault(mut self, fault: Fault) -> Self {
+        self.options.faults.push(fault);
+        self
+    }
+
+    pub fn read_only(mut self) -> Self {
+        self.options.read_only = true;
+        self
+    }
+
+    pub fn public_metrics(mut self) -> Self {
+        self.options.public_metrics = true;
+        self
+    }
+
+    pub fn cluster(mut self, cluster: Cluster) -> Self {
+        self.options.cluster = Some(cluster);
+        self
+    }
+
+    pub fn build(self) -> RouterOptions {
+        self.options
+    }
+}
+
+/// Waits until a logged record is on disk, if it has to be.
+fn durable(pending: Option<PendingSync>) -> io::Result<()> {
+    pending.map_or(Ok(()), PendingSync::wait_in_place)
+}
+
+/// The routes of the store with the default options. Building a router has
+/// no side effects, logging is set up with `init_tracing`.
 pub fn router(state: &SharedState) -> Router {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::DEBUG)
-        .finish();
+    router_with_options(state, RouterOptions::default())
+}
+
+#[allow(clippy::result_large_err)]
+pub fn router_with_options(state: &SharedState, options: RouterOptions) -> Router {
+    let kv_routes = kv_routes(state, &options);
+    let acl = options.acl.map(Arc::new);
+    let kv_routes = Router::new()
+        .merge(kv_routes.clone())
+        .nest("/ns/:namespace", kv_routes);
+    let kv_routes = match &acl {
+        Some(policy) => {
+            let api_keys = options.api_keys.clone();
+            let policy = Arc::clone(policy);
+            kv_routes.layer(RequireAuthorizationLayer::custom(
+                move |req: &mut Request<Body>| {
+                    let principal = api_keys.authorize(req)?;
+                    req.extensions_mut()
+                        .insert(Access::new(Arc::clone(&policy), principal));
+                    Ok(())
+                },
+            ))
+        }
+        None => kv_routes,
+    };
+    let kv_routes = match options.kv_rate_limit {
+        Some(limit) => kv_routes.layer(RateLimitLayer::new(limit, options.api_keys.clone())),
+        None => kv_routes,
+    };
 
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("Setting a default Subscriber failed");
+    let replicate = get(replication::replicate)
+        .with_state(Arc::clone(state))
+        .layer(RequireAuthorizationLayer::custom({
+            let api_keys = options.api_keys.clone();
+            move |req: &mut Request<Body>| api_keys.authorize_admin(req).map(drop)
+        }));
+    let admin_routes = admin_routes(
+        state,
+        options.api_keys.clone(),
+        acl,
+        options.cluster.as_ref(),
+    );
+    let admin_routes = match options.admin_rate_limit {
+        Some(limit) => admin_routes.layer(RateLimitLayer::new(limit, options.api_keys.clone())),
+        None => admin_routes,
+    };
+
+    let metrics = Metrics::new();
+    let scrape = get(metrics::metrics).with_state((Arc::clone(state), metrics.clone()));
+    let scrape = if options.public_metrics {
+        scrape
+    } else {
+        let api_keys = options.api_keys.clone();
+        scrape.layer(RequireAuthorizationLayer::custom(
+            move |req: &mut Request<Body>| api_keys.authorize_admin(req).map(drop),
+        ))
+    };
+    let app = kv_routes
+        .nest("/admin", admin_routes)
+        .route("/replicate", replicate)
+        .route("/metrics", scrape);
+    let app = match options.cluster {
+        Some(cluster) => {
+            let api_keys = options.api_keys.clone();
+            app.merge(
+                cluster::raft_routes(&cluster).layer(RequireAuthorizationLayer::custom(
+                    move |req: &mut Request<Body>| api_keys.authorize_admin(req).map(drop),
+                )),
+            )
+            .layer(Extension(cluster))
+        }
+        None => app,
+    };
+    let app = if options.read_only {
+        app.layer(middleware::from_fn(replication::read_only))
+    } else {
+        app
+    };
+    // Injected faults show up in the metrics and logs like real ones.
+    let app = if options.faults.is_empty() {
+        app
+    } else {
+        app.layer(FaultLayer::new(options.faults))
+    };
+    app.layer(MetricsLayer::new(metrics))
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|req: &Request<Body>| {
+                let request_id = req.headers().get(X_REQUEST_ID);
+                tracing::debug_span!(
+                    "request",
+                    method = %req.method(),
+                    uri = %req.uri(),
+                    request_id = request_id.and_then(|id| id.to_str().ok()),
+                )
+            }),
+        )
+        .layer(LogLayer::new())
+}
 
+/// The routes of a single namespace, which one depends on where they are
+/// nested.
+fn kv_routes(state: &SharedState, options: &RouterOptions) -> Router {
     let kv_set_service = ServiceBuilder::new()
         .layer(HandleErrorLayer::new(handle_error))
         .layer(DefaultBodyLimit::disable())
-        .layer(RequestBodyLimitLayer::new(1024 * 8000))
-        .layer(TimeoutLayer::new(Duration::from_secs(4)))
+        .layer(RequestBodyLimitLayer::new(options.body_limit))
+        .layer(TimeoutLayer::new(options.timeout))
         .service(kv_store_set.with_state(Arc::clone(state)));
 
     Router::new()
+        .route("/kv", get(kv_store_list).with_state(Arc::clone(state)))
         .route(
             "/kv/:key",
             get(kv_store_get)
                 .post_service(kv_set_service)
                 .with_state(Arc::clone(state)),
         )
-        .nest("/admin", admin_routes(state))
-        .layer(TraceLayer::new_for_http())
-        .layer(LogLayer::new())
+        .route("/txn", post(kv_store_txn).with_state(Arc::clone(state)))
+        .route(
+            "/watch",
+            get(kv_store_watch_prefix).with_state(Arc::clone(state)),
+        )
+        .route(
+            "/watch/:key",
+            get(kv_store_watch).with_state(Arc::clone(state)),
+        )
 }
 
-fn admin_routes(state: &SharedState) -> Router {
+#[allow(clippy::result_large_err)]
+fn admin_routes(
+    state: &SharedState,
+    api_keys: ApiKeys,
+    acl: Option<Arc<Policy>>,
+    cluster: Option<&Cluster>,
+) -> Router {
     async fn remove_key(
-        Path(key): Path<String>,
+        Path(path): Path<KeyPath>,
         State(state): State<SharedState>,
-    ) -> Result<(), Infallible> {
-        state.write().await.0.remove(&key);
+        access: Option<Extension<Access>>,
+        cluster: Option<Extension<Cluster>>,
+        OriginalUri(uri): OriginalUri,
+        headers: HeaderMap,
+    ) -> Result<(), WriteError> {
+        acl::check(&access, &path.namespace, &path.key, Permission::Delete)?;
+        let db = metrics::read(&state).await;
+        let key = path.stored(&db)?;
+        if let Some(Extension(cluster)) = cluster {
+            drop(db);
+            let preconditions = Preconditions::from_headers(&headers);
+            return cluster
+                .write(Record::Delete { key }, preconditions, &uri)
+                .await;
+        }
+        db.commit_if(Record::Delete { key }, |current| {
+            etag::check_write(&headers, current)
+        })
+        .map_err(storage_error)??;
         Ok(())
     }
 
     async fn delete_all_keys(
         State(state): State<SharedState>,
-        request: Request<Body>,
-    ) -> Result<(), Infallible> {
-        println!("{:?}", request.extensions());
-        state.write().await.0.clear();
-        Ok(())
+        Extension(principal): Extension<Principal>,
+        access: Option<Extension<Access>>,
+        cluster: Option<Extension<Cluster>>,
+    ) -> Result<(), StatusCode> {
+        acl::check(&access, "*", "", Permission::Delete)?;
+        cluster::local_only(&cluster)?;
+        event!(Level::INFO, principal = %principal.name, "deleting all keys");
+        metrics::write(&state).await.clear().map_err(storage_error)
+    }
+
+    async fn list_namespaces(State(state): State<SharedState>) -> Json<Vec<NamespaceInfo>> {
+        Json(metrics::read(&state).await.namespaces())
+    }
+
+    /// Responds with `201 Created` for a new namespace and `200 OK` if only
+    /// the quota changed.
+    async fn put_namespace(
+        Path(name): Path<String>,
+        State(state): State<SharedState>,
+        access: Option<Extension<Access>>,
+        cluster: Option<Extension<Cluster>>,
+        Json(quota): Json<Quota>,
+    ) -> Result<StatusCode, StatusCode> {
+        acl::check(&access, &name, "", Permission::Write)?;
+        cluster::local_only(&cluster)?;
+        let created = metrics::write(&state)
+            .await
+            .create_namespace(&name, quota)
+            .map_err(storage_error)?;
+        Ok(if created {
+            StatusCode::CREATED
+        } else {
+            StatusCode::OK
+        })
+    }
+
+    async fn drop_namespace(
+        Path(name): Path<String>,
+        State(state): State<SharedState>,
+        Extension(principal): Extension<Principal>,
+        access: Option<Extension<Access>>,
+        cluster: Option<Extension<Cluster>>,
+    ) -> Result<(), StatusCode> {
+        acl::check(&access, &name, "", Permission::Delete)?;
+        cluster::local_only(&cluster)?;
+        event!(Level::INFO, principal = %principal.name, namespace = %name, "dropping namespace");
+        let dropped = metrics::write(&state)
+            .await
+            .drop_namespace(&name)
+            .map_err(storage_error)?;
+        dropped.then_some(()).ok_or(StatusCode::NOT_FOUND)
+    }
+
+    async fn compact_log(State(state): State<SharedState>) -> Result<(), StatusCode> {
+        compact(&state).await.map_err(storage_error)
+    }
+
+    async fn stats(State(state): State<SharedState>) -> Json<Stats> {
+        Json(metrics::read(&state).await.stats())
     }
 
     Router::new()
@@ -84,40 +766,377 @@ fn admin_routes(state: &SharedState) -> Router {
             "/keys/:key",
             delete(remove_key).with_state(Arc::clone(state)),
         )
+        .route(
+            "/namespaces",
+            get(list_namespaces).with_state(Arc::clone(state)),
+        )
+        .route(
+            "/namespaces/:namespace",
+            put(put_namespace)
+                .delete(drop_namespace)
+                .with_state(Arc::clone(state)),
+        )
+        .route(
+            "/namespaces/:namespace/keys/:key",
+            delete(remove_key).with_state(Arc::clone(state)),
+        )
+        .route("/compact", post(compact_log).with_state(Arc::clone(state)))
+        .route("/stats", get(stats).with_state(Arc::clone(state)))
+        .merge(cluster.map(cluster::admin_routes).unwrap_or_default())
         .layer(RequireAuthorizationLayer::custom(
-            |req: &mut Request<Body>| {
-                req.extensions_mut().insert("admin");
-                println!("{:?}", req.headers());
+            move |req: &mut Request<Body>| {
+                let principal = api_keys.authorize_admin(req)?;
+                if let Some(policy) = &acl {
+                    req.extensions_mut()
+                        .insert(Access::new(Arc::clone(policy), principal));
+                }
                 Ok(())
             },
         ))
 }
 
+/// The path of a single key, in the default namespace unless it is nested
+/// under `/ns/:namespace`.
+#[derive(Debug, Deserialize)]
+struct KeyPath {
+    #[serde(default)]
+    namespace: String,
+    key: String,
+}
+
+impl KeyPath {
+    /// The key as it is stored, failing with `404 Not Found` if the
+    /// namespace doesn't exist.
+    fn stored(&self, db: &AppState) -> Result<String, StatusCode> {
+        if !db.has_namespace(&self.namespace) {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        namespace::key(&self.namespace, &self.key).ok_or(StatusCode::BAD_REQUEST)
+    }
+}
+
+/// Fails with `404 Not Found` if the namespace doesn't exist.
+fn check_namespace(db: &AppState, namespace: &str) -> Result<(), StatusCode> {
+    if db.has_namespace(namespace) {
+        Ok(())
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+/// Responds with the value and its `ETag`, and with the seconds it has left
+/// in `X-Expire-After` if it expires.
 #[instrument(level = "debug")]
 async fn kv_store_get(
-    Path(key): Path<String>,
+    Path(path): Path<KeyPath>,
     State(state): State<SharedState>,
-) -> Result<Bytes, StatusCode> {
-    let db = state.read().await;
-
-    tokio::time::sleep(Duration::from_secs(3)).await;
+    access: Option<Extension<Access>>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    acl::check(&access, &path.namespace, &path.key, Permission::Read)?;
+    let db = metrics::read(&state).await;
+    let key = path.stored(&db)?;
 
-    if let Some(val) = db.0.get(&key) {
+    if let Some(Entry { value, etag, ttl }) = db.entry(&key) {
         event!(Level::DEBUG, "Found");
-        Ok(val.to_owned())
+        if etag::not_modified(&headers, &etag) {
+            return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+        }
+        let mut response = ([(header::ETAG, etag)], value).into_response();
+        if let Some(ttl) = ttl {
+            // Whole seconds, rounded up so the key is never reported expired
+            // while it can still be read.
+            let secs = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+            response.headers_mut().insert("x-expire-after", secs.into());
+        }
+        Ok(response)
     } else {
         event!(Level::DEBUG, "Not Found");
         Err(StatusCode::NOT_FOUND)
     }
 }
 
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+/// Entries read per lock acquisition while streaming a range.
+const RANGE_BATCH: usize = 100;
+
+#[derive(Debug, Deserialize)]
+struct ListParams {
+    #[serde(default)]
+    prefix: String,
+    limit: Option<usize>,
+    /// The `next_cursor` of the previous page.
+    cursor: Option<String>,
+    /// First key of a range, inclusive.
+    start: Option<String>,
+    /// Last key of a range, exclusive.
+    end: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct KeyList {
+    keys: Vec<KeyInfo>,
+    /// Set if there may be more keys to fetch.
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct KeyInfo {
+    key: String,
+    size: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct RangeEntry {
+    key: String,
+    /// Base64, as values are arbitrary bytes.
+    value: String,
+}
+
+/// Keys the sender may not read are left out.
+async fn kv_store_list(
+    NamespaceName(namespace): NamespaceName,
+    Query(params): Query<ListParams>,
+    State(state): State<SharedState>,
+    access: Option<Extension<Access>>,
+) -> Result<Response, StatusCode> {
+    check_namespace(&*metrics::read(&state).await, &namespace)?;
+    if params.start.is_some() || params.end.is_some() {
+        return Ok(kv_store_range(state, namespace, params, access).into_response());
+    }
+
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+
+    let db = metrics::read(&state).await;
+    let keys = db.scan_in(&namespace, &params.prefix, params.cursor.as_deref(), limit);
+    let next_cursor = if keys.len() == limit {
+        keys.last().cloned()
+    } else {
+        None
+    };
+    let keys = keys
+        .into_iter()
+        .filter(|key| acl::allows(&access, &namespace, key, Permission::Read))
+        .filter_map(|key| {
+            let size = db.value_len(&namespace::key(&namespace, &key)?)?;
+            Some(KeyInfo { key, size })
+        })
+        .collect();
+
+    Ok(Json(KeyList { keys, next_cursor }).into_response())
+}
+
+/// Streams all entries between `start` and `end` as newline-delimited JSON.
+fn kv_store_range(
+    state: SharedState,
+    namespace: String,
+    params: ListParams,
+    access: Option<Extension<Access>>,
+) -> impl IntoResponse {
+    let stream = range_stream(
+        state,
+        namespace,
+        access,
+        params.start.map_or(Bound::Unbounded, Bound::Included),
+        params.end.map_or(Bound::Unbounded, Bound::Excluded),
+        params.limit.unwrap_or(usize::MAX),
+    );
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(stream),
+    )
+}
+
+/// Reads the range in batches, so that the lock isn't held while the
+/// response is sent and writers can get in between.
+fn range_stream(
+    state: SharedState,
+    namespace: String,
+    access: Option<Extension<Access>>,
+    start: Bound<String>,
+    end: Bound<String>,
+    limit: usize,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    stream::unfold(Some((start, limit)), move |cursor| {
+        let state = Arc::clone(&state);
+        let namespace = namespace.clone();
+        let access = access.clone();
+        let end = end.clone();
+        async move {
+            let (start, remaining) = cursor?;
+            let wanted = remaining.min(RANGE_BATCH);
+            let batch = metrics::read(&state).await.range_in(
+                &namespace,
+                start.as_ref().map(String::as_str),
+                end.as_ref().map(String::as_str),
+                wanted,
+            );
+            let (last, _) = batch.last()?;
+
+            let next = (batch.len() == wanted && remaining > wanted)
+                .then(|| (Bound::Excluded(last.clone()), remaining - wanted));
+
+            let mut lines = Vec::new();
+            for (key, value) in batch {
+                if !acl::allows(&access, &namespace, &key, Permission::Read) {
+                    continue;
+                }
+                let entry = RangeEntry {
+                    key,
+                    value: BASE64.encode(value),
+                };
+                serde_json::to_writer(&mut lines, &entry).expect("entries serialize to JSON");
+                lines.push(b'\n');
+            }
+            Some((Ok(Bytes::from(lines)), next))
+        }
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct SetParams {
+    /// Seconds until the value expires.
+    ttl: Option<u64>,
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn kv_store_set(
-    Path(key): Path<String>,
+    Path(path): Path<KeyPath>,
+    Query(params): Query<SetParams>,
     State(state): State<SharedState>,
+    access: Option<Extension<Access>>,
+    cluster: Option<Extension<Cluster>>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
     bytes: Bytes,
-) -> Result<(), StatusCode> {
-    state.write().await.0.insert(key, bytes);
-    Ok(())
+) -> Result<impl IntoResponse, WriteError> {
+    acl::check(&access, &path.namespace, &path.key, Permission::Write)?;
+    let ttl = match params.ttl {
+        Some(secs) => Some(secs),
+        None => expire_after(&headers)?,
+    };
+
+    // Writes only need the shard of their key, the read lock keeps out
+    // changes to the whole store.
+    let db = metrics::read(&state).await;
+    let key = path.stored(&db)?;
+    let etag = etag::format(etag::hash(&bytes));
+    let record =
+        expiry::set_record(key, bytes, ttl.map(Duration::from_secs)).map_err(storage_error)?;
+    if let Some(Extension(cluster)) = cluster {
+        drop(db);
+        let preconditions = Preconditions::from_headers(&headers);
+        cluster.write(record, preconditions, &uri).await?;
+        return Ok([(header::ETAG, etag)]);
+    }
+    db.commit_if(record, |current| etag::check_write(&headers, current))
+        .map_err(storage_error)??;
+    Ok([(header::ETAG, etag)])
+}
+
+#[derive(Debug, Deserialize)]
+struct TxnRequest {
+    ops: Vec<Op>,
+}
+
+/// Applies a batch of operations under a single write lock, responding with
+/// `412 Precondition Failed` if any check didn't pass.
+async fn kv_store_txn(
+    NamespaceName(namespace): NamespaceName,
+    State(state): State<SharedState>,
+    access: Option<Extension<Access>>,
+    cluster: Option<Extension<Cluster>>,
+    Json(txn): Json<TxnRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    cluster::local_only(&cluster)?;
+    for op in &txn.ops {
+        acl::check(&access, &namespace, op.key(), op.permission())?;
+    }
+    let txn = metrics::write(&state)
+        .await
+        .transaction_in(&namespace, txn.ops)
+        .map_err(storage_error)?;
+    let status = if txn.committed {
+        StatusCode::OK
+    } else {
+        StatusCode::PRECONDITION_FAILED
+    };
+    Ok((status, Json(txn)))
+}
+
+/// Streams changes to a single key.
+async fn kv_store_watch(
+    Path(path): Path<KeyPath>,
+    State(state): State<SharedState>,
+    access: Option<Extension<Access>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    acl::check(&access, &path.namespace, &path.key, Permission::Read)?;
+    let db = metrics::read(&state).await;
+    check_namespace(&db, &path.namespace)?;
+    let events = db.subscribe();
+    Ok(watch::sse(
+        events,
+        path.namespace,
+        watch::Filter::Key(path.key),
+        access,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchParams {
+    #[serde(default)]
+    prefix: String,
+}
+
+/// Streams changes to all keys starting with `prefix`, or every key, that
+/// the sender may read.
+async fn kv_store_watch_prefix(
+    NamespaceName(namespace): NamespaceName,
+    Query(params): Query<WatchParams>,
+    State(state): State<SharedState>,
+    access: Option<Extension<Access>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let db = metrics::read(&state).await;
+    check_namespace(&db, &namespace)?;
+    let events = db.subscribe();
+    Ok(watch::sse(
+        events,
+        namespace,
+        watch::Filter::Prefix(params.prefix),
+        access,
+    ))
+}
+
+/// Reads the TTL in seconds from the `X-Expire-After` header.
+fn expire_after(headers: &HeaderMap) -> Result<Option<u64>, StatusCode> {
+    let Some(value) = headers.get("x-expire-after") else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|secs| secs.trim().parse().ok())
+        .map(Some)
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+fn storage_error(err: io::Error) -> StatusCode {
+    let status = match err.kind() {
+        io::ErrorKind::StorageFull => StatusCode::INSUFFICIENT_STORAGE,
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    if status != StatusCode::INTERNAL_SERVER_ERROR {
+        event!(Level::DEBUG, "{err}");
+        return status;
+    }
+    event!(Level::ERROR, "writing to the log failed: {err}");
+    StatusCode::INTERNAL_SERVER_ERROR
 }
 
 async fn handle_error(error: BoxError) -> impl IntoResponse {
@@ -130,20 +1149,51 @@ async fn handle_error(error: BoxError) -> impl IntoResponse {
 
     (
         StatusCode::INTERNAL_SERVER_ERROR,
-        String::from(format!("Unhandled internal error: {}", error)),
+        format!("Unhandled internal error: {}", error),
     )
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
     use axum::{
         body::Bytes,
         http::{Request, StatusCode},
     };
-    use hyper::Body;
-    use tower::Service;
+    use hyper::{body::HttpBody, Body};
+    use tower::{Service, ServiceExt};
 
-    use crate::{router, SharedState};
+    use crate::{
+        router, router_with_options, AppState, Event, Fault, FsyncPolicy, Limits, MemoryStorage,
+        RateLimit, RouterOptions, SharedState, Storage,
+    };
+
+    const API_KEYS: &str = r#"
+        [[keys]]
+        name = "ops"
+        key = "secret"
+        admin = true
+
+        [[keys]]
+        name = "app"
+        key = "app secret"
+    "#;
+    const ADMIN_AUTHORIZATION: &str = "Bearer secret";
+
+    /// A router that lets `ADMIN_AUTHORIZATION` use the admin routes.
+    fn admin_router(state: &SharedState) -> axum::Router {
+        let options = RouterOptions::builder()
+            .api_keys(API_KEYS.parse().unwrap())
+            .build();
+        router_with_options(state, options)
+    }
 
     #[tokio::test]
     async fn basic_kv_store_post_test() {
@@ -161,7 +1211,7 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
 
         let db = state.read().await;
-        let result = db.0.get("test").unwrap();
+        let result = db.get("test").unwrap();
         assert_eq!(&result[..], b"Hello World");
     }
 
@@ -186,8 +1236,8 @@ mod tests {
         state
             .write()
             .await
-            .0
-            .insert("test".to_string(), Bytes::from_static(b"Hello World"));
+            .set("test".to_string(), Bytes::from_static(b"Hello World"))
+            .unwrap();
         let mut app = router(&state);
 
         let request = Request::builder()
@@ -201,4 +1251,986 @@ mod tests {
         let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
         assert_eq!(&body[..], "Hello World".as_bytes());
     }
+
+    #[tokio::test]
+    async fn kv_store_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+
+        let state = SharedState::new(AppState::open(&path, FsyncPolicy::Always).unwrap().into());
+        let mut app = admin_router(&state);
+
+        for (method, uri, body) in [
+            ("POST", "/kv/a", "1"),
+            ("POST", "/kv/b", "2"),
+            ("POST", "/kv/c", "3"),
+            ("DELETE", "/admin/keys/b", ""),
+        ] {
+            let request = Request::builder()
+                .uri(uri)
+                .method(method)
+                .header("authorization", ADMIN_AUTHORIZATION)
+                .body(body.into())
+                .unwrap();
+            let response = app.call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        drop(app);
+        drop(state);
+
+        let db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(&db.get("a").unwrap()[..], b"1");
+        assert!(db.get("b").is_none());
+        assert_eq!(&db.get("c").unwrap()[..], b"3");
+    }
+
+    /// Counts writes and forwards everything to a `MemoryStorage`.
+    #[derive(Debug, Default)]
+    struct CountingStorage {
+        inner: MemoryStorage,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl Storage for CountingStorage {
+        fn get(&self, key: &str) -> Option<Bytes> {
+            self.inner.get(key)
+        }
+
+        fn set(&mut self, key: String, value: Bytes) {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.set(key, value)
+        }
+
+        fn delete(&mut self, key: &str) -> Option<Bytes> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.delete(key)
+        }
+
+        fn clear(&mut self) {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.clear()
+        }
+
+        fn list(&self) -> Vec<String> {
+            self.inner.list()
+        }
+
+        fn len(&self) -> usize {
+            self.inner.len()
+        }
+    }
+
+    #[tokio::test]
+    async fn kv_store_with_custom_storage() {
+        let storage = CountingStorage::default();
+        let writes = Arc::clone(&storage.writes);
+        let state = SharedState::new(AppState::new(storage).into());
+        let mut app = admin_router(&state);
+
+        for (method, uri, body) in [
+            ("POST", "/kv/a", "1"),
+            ("POST", "/kv/b", "2"),
+            ("DELETE", "/admin/keys/a", ""),
+        ] {
+            let request = Request::builder()
+                .uri(uri)
+                .method(method)
+                .header("authorization", ADMIN_AUTHORIZATION)
+                .body(body.into())
+                .unwrap();
+            let response = app.call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let db = state.read().await;
+        assert_eq!(db.keys(), vec!["b".to_string()]);
+        assert_eq!(writes.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn kv_store_ttl() {
+        let state = SharedState::default();
+        let mut app = router(&state);
+
+        for request in [
+            Request::builder()
+                .uri("/kv/query?ttl=5")
+                .method("POST")
+                .body("Hello".into())
+                .unwrap(),
+            Request::builder()
+                .uri("/kv/header")
+                .method("POST")
+                .header("X-Expire-After", "15")
+                .body("World".into())
+                .unwrap(),
+        ] {
+            let response = app.call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let get = |key: &str| {
+            Request::builder()
+                .uri(format!("/kv/{key}"))
+                .method("GET")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.call(get("query")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-expire-after"], "5");
+
+        tokio::time::advance(Duration::from_millis(2500)).await;
+        let response = app.call(get("query")).await.unwrap();
+        assert_eq!(response.headers()["x-expire-after"], "3");
+
+        tokio::time::advance(Duration::from_millis(3500)).await;
+        let response = app.call(get("query")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app.call(get("header")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let response = app.call(get("header")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn kv_store_invalid_ttl() {
+        let state = SharedState::default();
+        let mut app = router(&state);
+
+        let request = Request::builder()
+            .uri("/kv/test")
+            .method("POST")
+            .header("X-Expire-After", "soon")
+            .body("Hello".into())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(state.read().await.is_empty());
+
+        let request = Request::builder()
+            .uri(format!("/kv/test?ttl={}", u64::MAX))
+            .method("POST")
+            .body("Hello".into())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(state.read().await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn kv_store_conditional_requests() {
+        let state = SharedState::default();
+        let mut app = admin_router(&state);
+
+        let mut call = |method: &str, uri: &str, condition: Option<(&str, &str)>, body: &str| {
+            let mut request = Request::builder()
+                .uri(uri)
+                .method(method)
+                .header("authorization", ADMIN_AUTHORIZATION);
+            if let Some((name, value)) = condition {
+                request = request.header(name, value);
+            }
+            app.call(request.body(Body::from(body.to_string())).unwrap())
+        };
+
+        // Only create the key if it doesn't exist yet.
+        let response = call("POST", "/kv/test", Some(("If-None-Match", "*")), "one")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+        let response = call("POST", "/kv/test", Some(("If-None-Match", "*")), "two")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        let response = call("GET", "/kv/test", None, "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["etag"], etag.as_str());
+        let response = call("GET", "/kv/test", Some(("If-None-Match", &etag)), "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // Compare-and-swap.
+        let response = call("POST", "/kv/test", Some(("If-Match", &etag)), "two")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call("POST", "/kv/test", Some(("If-Match", &etag)), "three")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        let response = call("DELETE", "/admin/keys/test", Some(("If-Match", &etag)), "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(&state.read().await.get("test").unwrap()[..], b"two");
+
+        let etag = state.read().await.etag("test").unwrap();
+        let response = call("DELETE", "/admin/keys/test", Some(("If-Match", &etag)), "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(state.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn kv_store_transaction() {
+        let state = SharedState::default();
+        state
+            .write()
+            .await
+            .set("a".to_string(), Bytes::from_static(b"1"))
+            .unwrap();
+        let etag = state.read().await.etag("a").unwrap();
+        let mut app = router(&state);
+
+        let mut txn = |body: serde_json::Value| {
+            let request = Request::builder()
+                .uri("/txn")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let response = app.call(request);
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+                )
+            }
+        };
+
+        let (status, body) = txn(serde_json::json!({ "ops": [
+            { "op": "check", "key": "a", "etag": "\"stale\"" },
+            { "op": "set", "key": "b", "value": "Mg==" },
+            { "op": "delete", "key": "a" },
+        ]}))
+        .await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(body["committed"], false);
+        assert_eq!(body["results"][0]["ok"], false);
+        assert_eq!(body["results"][0]["etag"], etag.as_str());
+        assert_eq!(state.read().await.keys(), vec!["a"]);
+
+        let (status, body) = txn(serde_json::json!({ "ops": [
+            { "op": "check", "key": "a", "etag": etag },
+            { "op": "set", "key": "b", "value": "Mg==" },
+            { "op": "delete", "key": "a" },
+        ]}))
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["committed"], true);
+        assert_eq!(state.read().await.keys(), vec!["b"]);
+        assert_eq!(&state.read().await.get("b").unwrap()[..], b"2");
+    }
+
+    #[tokio::test]
+    async fn kv_store_watch() {
+        let state = SharedState::default();
+        let mut app = router(&state);
+
+        let request = Request::builder()
+            .uri("/watch?prefix=user/")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut body = response.into_body();
+
+        {
+            let db = state.write().await;
+            db.set("other".to_string(), Bytes::from_static(b"1"))
+                .unwrap();
+            db.set("user/a".to_string(), Bytes::from_static(b"2"))
+                .unwrap();
+            db.remove("user/a").unwrap();
+        }
+
+        let mut received = String::new();
+        while !received.contains("event:delete") {
+            let chunk = body.data().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        let set = serde_json::to_string(&Event::set("user/a", b"2")).unwrap();
+        assert_eq!(
+            received,
+            format!(
+                "event:set\ndata:{set}\n\nevent:delete\ndata:{}\n\n",
+                r#"{"type":"delete","key":"user/a"}"#
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn kv_store_list_keys() {
+        let state = SharedState::default();
+        {
+            let db = state.write().await;
+            for key in ["user/b", "user/a", "team/a", "user/c", "users"] {
+                db.set(key.to_string(), Bytes::from(key)).unwrap();
+            }
+        }
+        let mut app = router(&state);
+
+        let mut list = |uri: &'static str| {
+            let request = Request::builder()
+                .uri(uri)
+                .method("GET")
+                .body(Body::empty())
+                .unwrap();
+            let response = app.call(request);
+            async move {
+                let response = response.await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        let page = list("/kv").await;
+        assert_eq!(page["keys"].as_array().unwrap().len(), 5);
+        assert_eq!(page["next_cursor"], serde_json::Value::Null);
+
+        let page = list("/kv?prefix=user/&limit=2").await;
+        assert_eq!(
+            page["keys"],
+            serde_json::json!([
+                { "key": "user/a", "size": 6 },
+                { "key": "user/b", "size": 6 },
+            ])
+        );
+        assert_eq!(page["next_cursor"], "user/b");
+
+        let page = list("/kv?prefix=user/&limit=2&cursor=user/b").await;
+        assert_eq!(
+            page["keys"],
+            serde_json::json!([{ "key": "user/c", "size": 6 }])
+        );
+        assert_eq!(page["next_cursor"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn kv_store_range() {
+        let state = SharedState::default();
+        {
+            let db = state.write().await;
+            for i in 0..250 {
+                let key = format!("key{i:03}");
+                db.set(key.clone(), Bytes::from(key)).unwrap();
+            }
+            db.set("other".to_string(), Bytes::from_static(b"\xff"))
+                .unwrap();
+        }
+        let mut app = router(&state);
+
+        let mut range = |uri: &'static str| {
+            let request = Request::builder()
+                .uri(uri)
+                .method("GET")
+                .body(Body::empty())
+                .unwrap();
+            let response = app.call(request);
+            async move {
+                let response = response.await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                body.split(|b| *b == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(|line| serde_json::from_slice::<serde_json::Value>(line).unwrap())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let entries = range("/kv?start=key010&end=key020").await;
+        assert_eq!(entries.len(), 10);
+        assert_eq!(entries[0]["key"], "key010");
+        assert_eq!(entries[0]["value"], "a2V5MDEw");
+        assert_eq!(entries[9]["key"], "key019");
+
+        // Spans several batches.
+        let entries = range("/kv?start=key").await;
+        assert_eq!(entries.len(), 251);
+        assert_eq!(entries[250]["key"], "other");
+        assert_eq!(entries[250]["value"], "/w==");
+
+        let entries = range("/kv?end=key005&limit=3").await;
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2]["key"], "key002");
+
+        let entries = range("/kv?start=z&end=a").await;
+        assert!(entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn admin_requires_api_key() {
+        let state = SharedState::default();
+
+        for (app, authorization, status) in [
+            (admin_router(&state), None, StatusCode::UNAUTHORIZED),
+            (
+                admin_router(&state),
+                Some("Bearer wrong"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                admin_router(&state),
+                Some("Bearer app secret"),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                admin_router(&state),
+                Some(ADMIN_AUTHORIZATION),
+                StatusCode::OK,
+            ),
+            // Without any keys, nobody gets in.
+            (
+                router(&state),
+                Some(ADMIN_AUTHORIZATION),
+                StatusCode::UNAUTHORIZED,
+            ),
+        ] {
+            let mut request = Request::builder().uri("/admin/keys").method("DELETE");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            let response = app
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{authorization:?}");
+            if status == StatusCode::UNAUTHORIZED {
+                assert_eq!(response.headers()["www-authenticate"], "Bearer");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn kv_store_acl() {
+        let state = SharedState::default();
+        {
+            let db = state.write().await;
+            for key in ["app-a", "ops-a"] {
+                db.set(key.to_string(), Bytes::from_static(b"1")).unwrap();
+            }
+        }
+        let acl = r#"
+            [[rules]]
+            principal = "app"
+            prefix = "app-"
+            allow = ["read", "write"]
+        "#;
+        let options = RouterOptions::builder()
+            .api_keys(API_KEYS.parse().unwrap())
+            .acl(acl.parse().unwrap())
+            .build();
+        let app = router_with_options(&state, options);
+
+        for (method, uri, authorization, status) in [
+            ("POST", "/kv/app-b", None, StatusCode::UNAUTHORIZED),
+            (
+                "POST",
+                "/kv/app-b",
+                Some("Bearer app secret"),
+                StatusCode::OK,
+            ),
+            (
+                "POST",
+                "/kv/ops-b",
+                Some("Bearer app secret"),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "DELETE",
+                "/admin/keys/app-b",
+                Some("Bearer app secret"),
+                StatusCode::FORBIDDEN,
+            ),
+            // Admins are bound by the policy like everyone else.
+            (
+                "POST",
+                "/kv/app-c",
+                Some(ADMIN_AUTHORIZATION),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "DELETE",
+                "/admin/keys/app-b",
+                Some(ADMIN_AUTHORIZATION),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "DELETE",
+                "/admin/keys",
+                Some(ADMIN_AUTHORIZATION),
+                StatusCode::FORBIDDEN,
+            ),
+        ] {
+            let mut request = Request::builder().uri(uri).method(method);
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            let response = app
+                .clone()
+                .oneshot(request.body(Body::from("2")).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                status,
+                "{method} {uri} {authorization:?}"
+            );
+        }
+        assert_eq!(state.read().await.len(), 3);
+
+        let request = Request::builder()
+            .uri("/kv")
+            .header("authorization", "Bearer app secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let list: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let keys: Vec<&str> = list["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key["key"].as_str().unwrap())
+            .collect();
+        assert_eq!(keys, vec!["app-a", "app-b"]);
+    }
+
+    #[tokio::test]
+    async fn kv_store_rate_limit() {
+        let state = SharedState::default();
+        let limit = RateLimit {
+            burst: 2,
+            per_second: 1,
+        };
+        let options = RouterOptions::builder()
+            .api_keys(API_KEYS.parse().unwrap())
+            .kv_rate_limit(limit)
+            .admin_rate_limit(limit)
+            .build();
+        let mut app = router_with_options(&state, options);
+
+        let get = |authorization: Option<&str>, uri: &str| {
+            let mut request = Request::builder().uri(uri);
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        for expected in [
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            let response = app.call(get(None, "/kv")).await.unwrap();
+            assert_eq!(response.status(), expected);
+        }
+        let response = app.call(get(None, "/kv")).await.unwrap();
+        assert_eq!(response.headers()["retry-after"], "1");
+
+        // Clients with an API key have their own budget, and so do the
+        // admin routes.
+        let response = app
+            .call(get(Some("Bearer app secret"), "/kv"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .call(get(Some(ADMIN_AUTHORIZATION), "/admin/stats"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn kv_store_metrics() {
+        let state = SharedState::default();
+        let mut app = admin_router(&state);
+
+        for uri in ["/kv/a", "/kv/b"] {
+            let request = Request::builder()
+                .uri(uri)
+                .method("POST")
+                .body("Hello".into())
+                .unwrap();
+            let response = app.call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let request = Request::builder()
+            .uri("/unknown")
+            .body(Body::empty())
+            .unwrap();
+        app.call(request).await.unwrap();
+
+        let get = |authorization: Option<&str>| {
+            let mut request = Request::builder().uri("/metrics");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        let response = app.call(get(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.call(get(Some("Bearer app secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let public = RouterOptions::builder().public_metrics().build();
+        let response = router_with_options(&state, public)
+            .call(get(None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.call(get(Some(ADMIN_AUTHORIZATION))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        for line in [
+            r#"kv_http_requests_total{method="POST",route="/kv/:key",status="200"} 2"#,
+            r#"kv_http_requests_total{method="GET",route="unmatched",status="404"} 1"#,
+            r#"kv_http_request_duration_seconds_count{method="POST",route="/kv/:key",status="200"} 2"#,
+            "kv_keys 2",
+            "kv_bytes 12",
+            "kv_evictions_total 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{line} missing from\n{text}"
+            );
+        }
+        assert!(text.contains(r#"kv_lock_wait_seconds_count{mode="write"}"#));
+    }
+
+    #[tokio::test]
+    async fn kv_store_request_id() {
+        let state = SharedState::default();
+        let mut app = router(&state);
+
+        let request = Request::builder()
+            .uri("/kv")
+            .header("x-request-id", "abc-123")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.headers()["x-request-id"], "abc-123");
+
+        let request = Request::builder().uri("/kv").body(Body::empty()).unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.headers()["x-request-id"].len(), 36);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn kv_store_concurrent_writes() {
+        let state = SharedState::default();
+        let app = router(&state);
+
+        let post = |uri: String, condition: Option<&'static str>| {
+            let mut request = Request::builder().uri(uri).method("POST");
+            if let Some(condition) = condition {
+                request = request.header("If-None-Match", condition);
+            }
+            let app = app.clone();
+            tokio::spawn(app.oneshot(request.body(Body::from("Hello")).unwrap()))
+        };
+
+        let writes: Vec<_> = (0..50).map(|i| post(format!("/kv/key{i}"), None)).collect();
+        // Only one of the writers gets to create the key.
+        let creates: Vec<_> = (0..50)
+            .map(|_| post("/kv/once".to_string(), Some("*")))
+            .collect();
+
+        for write in writes {
+            assert_eq!(write.await.unwrap().unwrap().status(), StatusCode::OK);
+        }
+        let mut created = 0;
+        for create in creates {
+            match create.await.unwrap().unwrap().status() {
+                StatusCode::OK => created += 1,
+                status => assert_eq!(status, StatusCode::PRECONDITION_FAILED),
+            }
+        }
+        assert_eq!(created, 1);
+        assert_eq!(state.read().await.len(), 51);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn kv_store_get_during_writes() {
+        let state = SharedState::default();
+        let app = router(&state);
+
+        let writer = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                for i in 0..2000 {
+                    let value = if i % 2 == 0 { "even" } else { "odd value" };
+                    state
+                        .read()
+                        .await
+                        .set("key".to_string(), Bytes::from(value))
+                        .unwrap();
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let app = app.clone();
+                tokio::spawn(async move {
+                    for _ in 0..300 {
+                        let request = Request::get("/kv/key").body(Body::empty()).unwrap();
+                        let response = app.clone().oneshot(request).await.unwrap();
+                        if response.status() == StatusCode::NOT_FOUND {
+                            continue;
+                        }
+                        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+                        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                        // The ETag always belongs to the body it came with.
+                        assert_eq!(etag, crate::etag::format(crate::etag::hash(&body)));
+                    }
+                })
+            })
+            .collect();
+
+        writer.await.unwrap();
+        for reader in readers {
+            reader.await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn synced_writes_share_a_shard() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+        let db = AppState::sharded(1, crate::MemoryStorage::default)
+            .with_log(&path, FsyncPolicy::Always)
+            .unwrap();
+        let state = SharedState::new(db.into());
+
+        // Writers wait for the disk without holding the shard or a worker.
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for i in 0..25 {
+                        let key = format!("{writer}-{i}");
+                        state.read().await.set(key, Bytes::from("value")).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        drop(state);
+
+        let db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(db.len(), 200);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn kv_store_faults() {
+        let state = SharedState::default();
+        let options = RouterOptions::builder()
+            .fault(Fault {
+                route: "/kv/:key".to_string(),
+                method: Some("GET".to_string()),
+                latency_ms: 2000,
+                ..Default::default()
+            })
+            .fault(Fault {
+                route: "/kv".to_string(),
+                error_rate: 1.0,
+                status: 500,
+                ..Default::default()
+            })
+            .build();
+        let mut app = router_with_options(&state, options);
+
+        let request = Request::builder()
+            .uri("/kv/test")
+            .method("POST")
+            .body("Hello".into())
+            .unwrap();
+        let start = tokio::time::Instant::now();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        let request = Request::builder()
+            .uri("/kv/test")
+            .body(Body::empty())
+            .unwrap();
+        let start = tokio::time::Instant::now();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(start.elapsed() >= Duration::from_secs(2));
+
+        let request = Request::builder().uri("/kv").body(Body::empty()).unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn admin_stats() {
+        let state = SharedState::new(
+            AppState::default()
+                .with_limits(Limits {
+                    max_keys: Some(1),
+                    ..Default::default()
+                })
+                .into(),
+        );
+        let mut app = admin_router(&state);
+
+        for key in ["a", "b"] {
+            let request = Request::builder()
+                .uri(format!("/kv/{key}"))
+                .method("POST")
+                .body("Hello".into())
+                .unwrap();
+            let response = app.call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let request = Request::builder()
+            .uri("/admin/stats")
+            .method("GET")
+            .header("authorization", ADMIN_AUTHORIZATION)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["keys"], 1);
+        assert_eq!(stats["bytes"], 6);
+        assert_eq!(stats["evictions"], 1);
+    }
+
+    #[tokio::test]
+    async fn kv_store_namespaces() {
+        let state = SharedState::default();
+        let mut app = admin_router(&state);
+
+        let request = Request::builder()
+            .uri("/ns/team/kv/a")
+            .method("POST")
+            .body("1".into())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        for expected in [StatusCode::CREATED, StatusCode::OK] {
+            let request = Request::builder()
+                .uri("/admin/namespaces/team")
+                .method("PUT")
+                .header("authorization", ADMIN_AUTHORIZATION)
+                .header("content-type", "application/json")
+                .body(r#"{"max_keys": 1, "max_bytes": null}"#.into())
+                .unwrap();
+            let response = app.call(request).await.unwrap();
+            assert_eq!(response.status(), expected);
+        }
+
+        for (uri, body, expected) in [
+            ("/ns/team/kv/a", "1", StatusCode::OK),
+            ("/kv/a", "2", StatusCode::OK),
+            ("/ns/team/kv/b", "3", StatusCode::INSUFFICIENT_STORAGE),
+        ] {
+            let request = Request::builder()
+                .uri(uri)
+                .method("POST")
+                .body(body.into())
+                .unwrap();
+            let response = app.call(request).await.unwrap();
+            assert_eq!(response.status(), expected, "{uri}");
+        }
+
+        for (uri, expected) in [("/ns/team/kv/a", "1"), ("/kv/a", "2")] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let response = app.call(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(&body[..], expected.as_bytes());
+        }
+
+        let request = Request::builder()
+            .uri("/ns/team/kv")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let list: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(list["keys"], serde_json::json!([{"key": "a", "size": 1}]));
+
+        let request = Request::builder()
+            .uri("/admin/namespaces")
+            .header("authorization", ADMIN_AUTHORIZATION)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let namespaces: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            namespaces,
+            serde_json::json!([{
+                "name": "team",
+                "keys": 1,
+                "bytes": 2,
+                "max_keys": 1,
+                "max_bytes": null,
+            }])
+        );
+
+        for expected in [StatusCode::OK, StatusCode::NOT_FOUND] {
+            let request = Request::builder()
+                .uri("/admin/namespaces/team")
+                .method("DELETE")
+                .header("authorization", ADMIN_AUTHORIZATION)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.call(request).await.unwrap();
+            assert_eq!(response.status(), expected);
+        }
+        assert_eq!(state.read().await.keys(), vec!["a"]);
+        assert_eq!(state.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn admin_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+
+        let state = SharedState::new(AppState::open(&path, FsyncPolicy::Always).unwrap().into());
+        state
+            .write()
+            .await
+            .set("test".to_string(), Bytes::from_static(b"Hello World"))
+            .unwrap();
+        assert!(state.read().await.log_size() > 0);
+        let mut app = admin_router(&state);
+
+        let request = Request::builder()
+            .uri("/admin/compact")
+            .method("POST")
+            .header("authorization", ADMIN_AUTHORIZATION)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.read().await.log_size(), 0);
+    }
 }
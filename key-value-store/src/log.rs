@@ -1,8 +1,57 @@
-use std::time::Instant;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+use axum::{
+    extract::ConnectInfo,
+    http::{self, header, header::HeaderName, Extensions, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
 use futures::future::BoxFuture;
-use hyper::Request;
+use hyper::{body::HttpBody, Request};
+use serde::Deserialize;
 use tower::{Layer, Service};
+use tracing::{
+    event,
+    field::{Field, Visit},
+    subscriber::SetGlobalDefaultError,
+    Level, Subscriber,
+};
+use tracing_subscriber::{
+    fmt::{
+        format::{self, FormatEvent, FormatFields},
+        time::{FormatTime, SystemTime},
+        FmtContext,
+    },
+    registry::LookupSpan,
+    FmtSubscriber,
+};
+use uuid::Uuid;
+
+use crate::ApiKeys;
+
+/// Header carrying the id of a request, taken from the client if it sends
+/// a usable one and returned with the response.
+pub(crate) const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The id the client sent, or a new one. Ids are limited to printable
+/// ASCII so that they can be logged as they are.
+fn request_id(headers: &HeaderMap) -> HeaderValue {
+    headers
+        .get(X_REQUEST_ID)
+        .filter(|id| {
+            (1..=128).contains(&id.len()) && id.as_bytes().iter().all(u8::is_ascii_graphic)
+        })
+        .cloned()
+        .unwrap_or_else(|| {
+            HeaderValue::from_str(&Uuid::new_v4().to_string()).expect("UUIDs are valid headers")
+        })
+}
 
 #[derive(Clone, Copy)]
 pub struct LogService<S> {
@@ -15,11 +64,12 @@ impl<S> LogService<S> {
     }
 }
 
-impl<S, B> Service<Request<B>> for LogService<S>
+impl<S, B, ResBody> Service<Request<B>> for LogService<S>
 where
-    S: Service<Request<B>> + Clone + Send + 'static,
+    S: Service<Request<B>, Response = http::Response<ResBody>> + Clone + Send + 'static,
     S::Future: Send,
-    B: Send + 'static,
+    B: HttpBody + Send + 'static,
+    ResBody: HttpBody,
 {
     type Response = S::Response;
     type Error = S::Error;
@@ -32,22 +82,31 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, req: Request<B>) -> Self::Future {
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
         let mut this = self.inner.clone();
+        let request_id = request_id(req.headers());
+        req.headers_mut().insert(X_REQUEST_ID, request_id.clone());
         let method = req.method().to_owned();
         let path = req.uri().path().to_owned();
-        let prev = Instant::now();
-        println!("processing {} {}", method, path);
+        let bytes_in = req.body().size_hint().exact();
+        let start = Instant::now();
         Box::pin(async move {
-            let res = this.call(req).await;
-            let curr = Instant::now();
-            println!(
-                "end processing {} {} {}",
-                curr.duration_since(prev).as_nanos(),
-                method,
-                path
+            let mut res = this.call(req).await?;
+            // Streamed responses have no size up front and are logged without.
+            let bytes_out = res.body().size_hint().exact();
+            event!(
+                Level::INFO,
+                method = %method,
+                path = %path,
+                status = res.status().as_u16(),
+                latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+                bytes_in,
+                bytes_out,
+                request_id = request_id.to_str().unwrap_or_default(),
+                "request finished"
             );
-            res
+            res.headers_mut().insert(X_REQUEST_ID, request_id);
+            Ok(res)
         })
     }
 }
@@ -68,3 +127,362 @@ impl<S> Layer<S> for LogLayer {
         LogService::new(inner)
     }
 }
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, with colors.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+    /// `key=value` pairs, as read by many log collectors.
+    Logfmt,
+}
+
+/// Installs the global tracing subscriber, writing events up to `level` to
+/// stdout as `format`. Fails if a subscriber is installed already.
+pub fn init_tracing(level: Level, format: LogFormat) -> Result<(), SetGlobalDefaultError> {
+    let subscriber = FmtSubscriber::builder().with_max_level(level);
+    match format {
+        LogFormat::Text => tracing::subscriber::set_global_default(subscriber.finish()),
+        LogFormat::Json => {
+            tracing::subscriber::set_global_default(subscriber.json().flatten_event(true).finish())
+        }
+        LogFormat::Logfmt => {
+            tracing::subscriber::set_global_default(subscriber.event_format(Logfmt).finish())
+        }
+    }
+}
+
+/// Formats events as logfmt, e.g.
+///
+/// ```text
+/// ts=2023-02-01T12:00:00.000000Z level=info target=key_value_store::log msg="request finished" status=200
+/// ```
+struct Logfmt;
+
+impl<S, N> FormatEvent<S, N> for Logfmt
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        write!(writer, "ts=")?;
+        SystemTime.format_time(&mut writer)?;
+        write!(
+            writer,
+            " level={} target={}",
+            metadata.level().as_str().to_ascii_lowercase(),
+            metadata.target()
+        )?;
+        let mut visitor = LogfmtVisitor {
+            writer: &mut writer,
+            result: Ok(()),
+        };
+        event.record(&mut visitor);
+        visitor.result?;
+        writeln!(writer)
+    }
+}
+
+struct LogfmtVisitor<'a, 'w> {
+    writer: &'a mut format::Writer<'w>,
+    result: fmt::Result,
+}
+
+impl Visit for LogfmtVisitor<'_, '_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if self.result.is_ok() {
+            let key = match field.name() {
+                "message" => "msg",
+                name => name,
+            };
+            self.result = write!(self.writer, " {key}={}", logfmt_value(value));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+}
+
+/// Quotes values that would otherwise be ambiguous.
+fn logfmt_value(value: &str) -> Cow<'_, str> {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| !c.is_whitespace() && !c.is_control() && c != '"' && c != '=');
+    if plain {
+        Cow::Borrowed(value)
+    } else {
+        Cow::Owned(format!("{value:?}"))
+    }
+}
+
+/// How many requests a client may send: bursts of up to `burst` requests,
+/// and `per_second` on average.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: u32,
+}
+
+/// The requests a client has left, refilled as time passes.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Takes a token, or says how long until there is one.
+    fn take(&mut self, limit: RateLimit, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second as f64).min(limit.burst as f64);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limit.per_second as f64,
+            ))
+        }
+    }
+
+    fn is_full(&self, limit: RateLimit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * limit.per_second as f64 >= limit.burst as f64
+    }
+}
+
+/// Clients are told apart by their API key if they send a valid one, by
+/// their address otherwise. Invalid keys don't count, so that making them
+/// up doesn't get anyone a fresh bucket.
+fn client(headers: &HeaderMap, extensions: &Extensions, api_keys: &ApiKeys) -> String {
+    if let Some(principal) = api_keys.authenticate(headers) {
+        return format!("key {}", principal.name);
+    }
+    match extensions.get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => format!("ip {}", addr.ip()),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Most clients that are kept track of. Beyond that, the one that was seen
+/// least recently is forgotten, and starts over with a full bucket.
+const MAX_BUCKETS: usize = 10_000;
+
+/// The bucket of every client, in the order they were last seen.
+#[derive(Debug, Default)]
+struct Buckets {
+    by_client: HashMap<String, (u64, Bucket)>,
+    by_age: BTreeMap<u64, String>,
+    seen: u64,
+}
+
+impl Buckets {
+    fn take(&mut self, client: String, limit: RateLimit, now: Instant) -> Result<(), Duration> {
+        self.seen += 1;
+        let (seen, bucket) = self.by_client.entry(client.clone()).or_insert((
+            0,
+            Bucket {
+                tokens: limit.burst as f64,
+                updated: now,
+            },
+        ));
+        self.by_age.remove(seen);
+        *seen = self.seen;
+        let taken = bucket.take(limit, now);
+        self.by_age.insert(self.seen, client);
+
+        // Clients that didn't send anything for a while have full buckets,
+        // forgetting them changes nothing.
+        while let Some(entry) = self.by_age.first_entry() {
+            let oldest = &self.by_client[entry.get()].1;
+            if self.by_client.len() <= MAX_BUCKETS && !oldest.is_full(limit, now) {
+                break;
+            }
+            self.by_client.remove(&entry.remove());
+        }
+        taken
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limit: RateLimit,
+    api_keys: ApiKeys,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl<S> RateLimitService<S> {
+    fn check(&self, client: String) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.buckets.lock().unwrap().take(client, self.limit, now)
+    }
+}
+
+impl<S, B> Service<Request<B>> for RateLimitService<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let client = client(req.headers(), req.extensions(), &self.api_keys);
+        if let Err(wait) = self.check(client) {
+            // Rounded up, so that clients don't come back too early.
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            let response = (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+            )
+                .into_response();
+            return Box::pin(async move { Ok(response) });
+        }
+        let mut this = self.inner.clone();
+        Box::pin(async move { this.call(req).await })
+    }
+}
+
+/// Answers clients that send more than `limit` allows with
+/// `429 Too Many Requests` and a `Retry-After` header. Clients share their
+/// budget across all routes the layer is applied to.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limit: RateLimit,
+    api_keys: ApiKeys,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimitLayer {
+    /// Panics if `limit` lets nothing through.
+    pub fn new(limit: RateLimit, api_keys: ApiKeys) -> Self {
+        assert!(
+            limit.burst > 0 && limit.per_second > 0,
+            "rate limit must allow requests"
+        );
+        Self {
+            limit,
+            api_keys,
+            buckets: Arc::default(),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limit: self.limit,
+            api_keys: self.api_keys.clone(),
+            buckets: Arc::clone(&self.buckets),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{logfmt_value, request_id, Bucket, Buckets, RateLimit, MAX_BUCKETS, X_REQUEST_ID};
+
+    #[test]
+    fn request_ids() {
+        let mut headers = HeaderMap::new();
+        let generated = request_id(&headers);
+        assert_eq!(generated.len(), 36);
+        assert_ne!(request_id(&headers), generated);
+
+        headers.insert(X_REQUEST_ID, HeaderValue::from_static("abc-123"));
+        assert_eq!(request_id(&headers), "abc-123");
+        headers.insert(X_REQUEST_ID, HeaderValue::from_static("two words"));
+        assert_eq!(request_id(&headers).len(), 36);
+    }
+
+    #[test]
+    fn logfmt_quoting() {
+        assert_eq!(logfmt_value("200"), "200");
+        assert_eq!(logfmt_value("/kv/a"), "/kv/a");
+        assert_eq!(logfmt_value("request finished"), r#""request finished""#);
+        assert_eq!(logfmt_value("a=b"), r#""a=b""#);
+        assert_eq!(logfmt_value(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(logfmt_value(""), r#""""#);
+    }
+
+    #[test]
+    fn token_bucket() {
+        let limit = RateLimit {
+            burst: 2,
+            per_second: 4,
+        };
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 2.0,
+            updated: start,
+        };
+        assert_eq!(bucket.take(limit, start), Ok(()));
+        assert_eq!(bucket.take(limit, start), Ok(()));
+        assert_eq!(bucket.take(limit, start), Err(Duration::from_millis(250)));
+
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.take(limit, later), Ok(()));
+        assert!(bucket.take(limit, later).is_err());
+
+        // Idle clients don't save up more than a burst.
+        let much_later = later + Duration::from_secs(60);
+        assert!(bucket.is_full(limit, much_later));
+        assert_eq!(bucket.take(limit, much_later), Ok(()));
+        assert_eq!(bucket.take(limit, much_later), Ok(()));
+        assert!(bucket.take(limit, much_later).is_err());
+    }
+
+    #[test]
+    fn buckets_are_bounded() {
+        let limit = RateLimit {
+            burst: 1,
+            per_second: 1,
+        };
+        let start = Instant::now();
+        let mut buckets = Buckets::default();
+        assert!(buckets.take("a".to_string(), limit, start).is_ok());
+        for i in 0..MAX_BUCKETS {
+            assert!(buckets.take(i.to_string(), limit, start).is_ok());
+        }
+        assert_eq!(buckets.by_client.len(), MAX_BUCKETS);
+        assert!(!buckets.by_client.contains_key("a"));
+        assert!(buckets.take("0".to_string(), limit, start).is_err());
+
+        // Full buckets are dropped as newer clients come in.
+        let later = start + Duration::from_secs(1);
+        assert!(buckets.take("b".to_string(), limit, later).is_ok());
+        assert_eq!(buckets.by_client.len(), 1);
+        assert_eq!(buckets.by_age.len(), 1);
+    }
+}
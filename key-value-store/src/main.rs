@@ -1,18 +1,268 @@
-use key_value_store::{router, SharedState};
-use std::net::SocketAddr;
+use clap::{value_parser, Arg, ArgMatches, Command};
+use key_value_store::{
+    init_tracing, router_with_options, serve, spawn_compactor, spawn_follower, spawn_reaper,
+    spawn_resp, AppState, Cluster, Config, Fsync, LogFormat, MemoryStorage, SharedState,
+};
+use std::{
+    future,
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    process,
+    time::Duration,
+};
+use tokio::signal;
+use tracing::{error, info, warn};
 
 type BoxError = Box<dyn std::error::Error>;
 
 #[tokio::main]
-async fn main() -> Result<(), BoxError> {
-    let state = SharedState::default();
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+async fn main() {
+    if let Err(err) = run().await {
+        eprintln!("error: {err}");
+        process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), BoxError> {
+    let config = config(&cli().get_matches())?;
+    init_tracing(config.log_level(), config.log_format)?;
+    let mut options = config.router_options()?;
+    if options.api_keys.is_empty() {
+        warn!("no API keys are configured, the admin routes are disabled");
+        if !options.public_metrics {
+            warn!("/metrics is disabled too, set http.public_metrics to expose it");
+        }
+    }
+    if !options.faults.is_empty() {
+        warn!("faults are injected into requests, don't do this in production");
+    }
 
-    let app = router(&state);
+    let db = AppState::sharded(config.storage.shards, MemoryStorage::default)
+        .with_limits(config.limits())
+        .with_log(&config.storage.path, config.fsync_policy())
+        .map_err(|err| format!("{}: {err}", config.storage.path.display()))?;
+    let state = SharedState::new(db.into());
+    spawn_compactor(
+        &state,
+        config.storage.compact_threshold_bytes,
+        Duration::from_millis(config.storage.compact_interval_ms),
+    );
+    spawn_reaper(
+        &state,
+        Duration::from_millis(config.storage.reap_interval_ms),
+    );
+    if let Some(leader) = config.leader()? {
+        info!("following {leader}, only reads are served");
+        spawn_follower(
+            &state,
+            leader,
+            config.replication.api_key.clone().unwrap_or_default(),
+            Duration::from_millis(config.replication.retry_ms),
+        );
+    }
+    if let Some(cluster) = config.cluster_options() {
+        info!("running as node {} of a cluster", cluster.id);
+        options.cluster = Some(Cluster::start(&state, cluster).await?);
+    }
+    if let Some(addr) = config.resp_bind {
+        let listener = TcpListener::bind(addr).map_err(|err| format!("{addr}: {err}"))?;
+        spawn_resp(listener, &state, options.clone())?;
+    }
+    let app = router_with_options(&state, options);
 
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await?;
+    let listener =
+        TcpListener::bind(config.bind).map_err(|err| format!("{}: {err}", config.bind))?;
+    serve(
+        listener,
+        app,
+        &state,
+        shutdown_signal(),
+        Duration::from_millis(config.http.shutdown_timeout_ms),
+    )
+    .await?;
 
     Ok(())
 }
+
+/// Resolves on Ctrl+C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = signal::ctrl_c().await {
+            error!("can't listen for Ctrl+C: {err}");
+            future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                error!("can't listen for SIGTERM: {err}");
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+fn cli() -> Command<'static> {
+    let arg = |name: &'static str, env: &'static str, help: &'static str| {
+        Arg::new(name)
+            .long(name)
+            .env(env)
+            .takes_value(true)
+            .help(help)
+    };
+    Command::new("key-value-store")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("An HTTP key-value store")
+        .after_help(
+            "Flags take precedence over environment variables, which take precedence \
+             over the config file.",
+        )
+        .arg(
+            arg("config", "KV_CONFIG", "TOML file to read the config from")
+                .short('c')
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(arg("bind", "KV_BIND", "Address to listen on").value_parser(value_parser!(SocketAddr)))
+        .arg(
+            arg(
+                "resp-bind",
+                "KV_RESP_BIND",
+                "Address to serve Redis clients on",
+            )
+            .value_parser(value_parser!(SocketAddr)),
+        )
+        .arg(
+            arg(
+                "log-level",
+                "KV_LOG_LEVEL",
+                "Most verbose level that gets logged",
+            )
+            .value_parser(["trace", "debug", "info", "warn", "error"]),
+        )
+        .arg(
+            arg("log-format", "KV_LOG_FORMAT", "How log lines are written")
+                .value_parser(["text", "json", "logfmt"]),
+        )
+        .arg(
+            arg("storage-path", "KV_STORAGE_PATH", "Write-ahead log file")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg("fsync", "KV_FSYNC", "When the log is flushed to disk")
+                .value_parser(["always", "interval", "never"]),
+        )
+        .arg(
+            arg("max-keys", "KV_MAX_KEYS", "Entries kept before evicting")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg("max-bytes", "KV_MAX_BYTES", "Bytes kept before evicting")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg(
+                "body-limit",
+                "KV_BODY_LIMIT",
+                "Largest value that can be written, in bytes",
+            )
+            .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg("timeout-ms", "KV_TIMEOUT_MS", "How long a write may take")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg("api-keys", "KV_API_KEYS", "TOML file with the API keys")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg("acl", "KV_ACL", "TOML file with the access control list")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(arg(
+            "leader",
+            "KV_LEADER",
+            "URL of the leader to follow, read-only",
+        ))
+        .arg(arg(
+            "leader-api-key",
+            "KV_LEADER_API_KEY",
+            "Admin API key of the leader",
+        ))
+}
+
+/// The config file, if there is one, with the flags applied on top.
+fn config(matches: &ArgMatches) -> Result<Config, BoxError> {
+    let mut config = match matches.get_one::<PathBuf>("config") {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    if let Some(bind) = matches.get_one("bind") {
+        config.bind = *bind;
+    }
+    if let Some(addr) = matches.get_one("resp-bind") {
+        config.resp_bind = Some(*addr);
+    }
+    if let Some(level) = matches.get_one::<String>("log-level") {
+        config.log_level = level.clone();
+    }
+    if let Some(format) = matches.get_one::<String>("log-format") {
+        config.log_format = match format.as_str() {
+            "json" => LogFormat::Json,
+            "logfmt" => LogFormat::Logfmt,
+            _ => LogFormat::Text,
+        };
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("storage-path") {
+        config.storage.path = path.clone();
+    }
+    if let Some(fsync) = matches.get_one::<String>("fsync") {
+        config.storage.fsync = match fsync.as_str() {
+            "always" => Fsync::Always,
+            "interval" => Fsync::Interval,
+            _ => Fsync::Never,
+        };
+    }
+    if let Some(max_keys) = matches.get_one("max-keys") {
+        config.storage.max_keys = Some(*max_keys);
+    }
+    if let Some(max_bytes) = matches.get_one("max-bytes") {
+        config.storage.max_bytes = Some(*max_bytes);
+    }
+    if let Some(body_limit) = matches.get_one("body-limit") {
+        config.http.body_limit_bytes = *body_limit;
+    }
+    if let Some(timeout) = matches.get_one("timeout-ms") {
+        config.http.timeout_ms = *timeout;
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("api-keys") {
+        config.auth.api_keys = Some(path.clone());
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("acl") {
+        config.auth.acl = Some(path.clone());
+    }
+    if let Some(leader) = matches.get_one::<String>("leader") {
+        config.replication.leader = Some(leader.clone());
+    }
+    if let Some(api_key) = matches.get_one::<String>("leader-api-key") {
+        config.replication.api_key = Some(api_key.clone());
+    }
+
+    config
+        .validate()
+        .map_err(|err| format!("invalid config: {err}"))?;
+    Ok(config)
+}
@@ -0,0 +1,250 @@
+use std::{
+    sync::LazyLock,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use hyper::Request;
+use prometheus::{
+    exponential_buckets, histogram_opts, opts, Encoder, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, Registry, TextEncoder,
+};
+use tokio::sync::{RwLockReadGuard, RwLockWriteGuard};
+use tower::{Layer, Service};
+
+use crate::{AppState, SharedState};
+
+/// Time spent waiting for the store's lock, shared by every router as they
+/// may share the store.
+static LOCK_WAIT: LazyLock<HistogramVec> = LazyLock::new(|| {
+    HistogramVec::new(
+        histogram_opts!(
+            "kv_lock_wait_seconds",
+            "Time requests waited for the store's lock",
+            exponential_buckets(0.000_01, 4.0, 10).expect("buckets are valid")
+        ),
+        &["mode"],
+    )
+    .expect("metric is valid")
+});
+
+/// Takes the read lock of the store, recording how long that took.
+pub(crate) async fn read(state: &SharedState) -> RwLockReadGuard<'_, AppState> {
+    let start = Instant::now();
+    let guard = state.read().await;
+    LOCK_WAIT
+        .with_label_values(&["read"])
+        .observe(start.elapsed().as_secs_f64());
+    guard
+}
+
+/// Takes the write lock of the store, recording how long that took.
+pub(crate) async fn write(state: &SharedState) -> RwLockWriteGuard<'_, AppState> {
+    let start = Instant::now();
+    let guard = state.write().await;
+    LOCK_WAIT
+        .with_label_values(&["write"])
+        .observe(start.elapsed().as_secs_f64());
+    guard
+}
+
+/// Request and store metrics of a router, served in the Prometheus text
+/// format.
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    keys: IntGauge,
+    bytes: IntGauge,
+    evictions: IntCounter,
+    expirations: IntCounter,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        let labels = ["method", "route", "status"];
+        let requests =
+            IntCounterVec::new(opts!("kv_http_requests_total", "Requests handled"), &labels)
+                .expect("metric is valid");
+        let latency = HistogramVec::new(
+            histogram_opts!(
+                "kv_http_request_duration_seconds",
+                "Time taken to respond to requests"
+            ),
+            &labels,
+        )
+        .expect("metric is valid");
+        let keys = IntGauge::new("kv_keys", "Entries in the store, including expired ones")
+            .expect("metric is valid");
+        let bytes = IntGauge::new("kv_bytes", "Total size of all keys and values")
+            .expect("metric is valid");
+        let evictions = IntCounter::new(
+            "kv_evictions_total",
+            "Entries removed to stay within the limits",
+        )
+        .expect("metric is valid");
+        let expirations = IntCounter::new(
+            "kv_expirations_total",
+            "Entries removed because their TTL ran out",
+        )
+        .expect("metric is valid");
+
+        let registry = Registry::new();
+        for collector in [
+            Box::new(requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(latency.clone()),
+            Box::new(keys.clone()),
+            Box::new(bytes.clone()),
+            Box::new(evictions.clone()),
+            Box::new(expirations.clone()),
+            Box::new(LOCK_WAIT.clone()),
+        ] {
+            registry.register(collector).expect("metrics are unique");
+        }
+
+        Self {
+            registry,
+            requests,
+            latency,
+            keys,
+            bytes,
+            evictions,
+            expirations,
+        }
+    }
+
+    /// Brings the store metrics up to date.
+    fn observe_store(&self, db: &AppState) {
+        let stats = db.stats();
+        self.keys.set(stats.keys as i64);
+        self.bytes.set(stats.bytes as i64);
+        // The store counts by itself, the counters only catch up.
+        self.evictions
+            .inc_by(stats.evictions.saturating_sub(self.evictions.get()));
+        self.expirations
+            .inc_by(stats.expirations.saturating_sub(self.expirations.get()));
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics encode as text");
+        buffer
+    }
+}
+
+pub(crate) async fn metrics(
+    State((state, metrics)): State<(SharedState, Metrics)>,
+) -> impl IntoResponse {
+    metrics.observe_store(&*read(&state).await);
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        metrics.encode(),
+    )
+}
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Metrics,
+}
+
+impl<S, B> Service<Request<B>> for MetricsService<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let mut this = self.inner.clone();
+        let metrics = self.metrics.clone();
+        let method = req.method().to_string();
+        // Routes rather than paths, so that every key doesn't get its own
+        // time series.
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map_or("unmatched", MatchedPath::as_str)
+            .to_string();
+        let start = Instant::now();
+        Box::pin(async move {
+            let res = this.call(req).await;
+            let status = match &res {
+                Ok(response) => response.status(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let labels = [method.as_str(), route.as_str(), status.as_str()];
+            metrics.requests.with_label_values(&labels).inc();
+            metrics
+                .latency
+                .with_label_values(&labels)
+                .observe(start.elapsed().as_secs_f64());
+            res
+        })
+    }
+}
+
+/// Counts requests and measures their latency by method, route and status.
+#[derive(Clone)]
+pub(crate) struct MetricsLayer {
+    metrics: Metrics,
+}
+
+impl MetricsLayer {
+    pub(crate) fn new(metrics: Metrics) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+
+    use super::Metrics;
+    use crate::{AppState, Limits};
+
+    #[test]
+    fn store_metrics() {
+        let db = AppState::default().with_limits(Limits {
+            max_keys: Some(1),
+            ..Default::default()
+        });
+        db.set("a".to_string(), Bytes::from_static(b"1")).unwrap();
+        db.set("b".to_string(), Bytes::from_static(b"22")).unwrap();
+
+        let metrics = Metrics::new();
+        metrics.observe_store(&db);
+        metrics.observe_store(&db);
+        let text = String::from_utf8(metrics.encode()).unwrap();
+        assert!(text.contains("kv_keys 1\n"), "{text}");
+        assert!(text.contains("kv_bytes 3\n"), "{text}");
+        assert!(text.contains("kv_evictions_total 1\n"), "{text}");
+    }
+}
@@ -0,0 +1,543 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequestParts, Path},
+    http::request::Parts,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{shard::Shard, wal::Record, AppState};
+
+/// The namespace of `/kv`, which always exists.
+pub(crate) const DEFAULT: &str = "";
+
+/// Keys of other namespaces are stored as `\0<namespace>\0<key>`, so they
+/// can't clash with each other or with keys of the default namespace, which
+/// may not start with `\0`.
+const SEPARATOR: char = '\0';
+
+/// Limits of a single namespace, unbounded by default. Keys count without
+/// their namespace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    pub max_keys: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Namespace {
+    quota: Quota,
+    keys: AtomicUsize,
+    bytes: AtomicUsize,
+    /// Held while a write is checked against the quota and applied, as
+    /// the keys of a namespace are spread over all shards.
+    writing: Mutex<()>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NamespaceInfo {
+    pub name: String,
+    /// Includes keys that expired but were not evicted yet.
+    pub keys: usize,
+    pub bytes: usize,
+    pub max_keys: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+/// Namespace names are short and plain, so they fit into URLs and the
+/// stored keys as they are.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Everything that stored keys of `namespace` start with.
+pub(crate) fn prefix(namespace: &str) -> String {
+    if namespace == DEFAULT {
+        String::new()
+    } else {
+        format!("{SEPARATOR}{namespace}{SEPARATOR}")
+    }
+}
+
+/// The stored key for `key` in `namespace`, `None` if the default namespace
+/// can't hold it.
+pub(crate) fn key(namespace: &str, key: &str) -> Option<String> {
+    if namespace == DEFAULT && key.starts_with(SEPARATOR) {
+        return None;
+    }
+    Some(prefix(namespace) + key)
+}
+
+/// Splits a stored key into its namespace and the key within it.
+pub(crate) fn split(stored: &str) -> (&str, &str) {
+    stored
+        .strip_prefix(SEPARATOR)
+        .and_then(|rest| rest.split_once(SEPARATOR))
+        .unwrap_or((DEFAULT, stored))
+}
+
+/// The namespace a request is for, taken from the `:namespace` segment of
+/// the path, or the default one.
+#[derive(Debug, Clone)]
+pub(crate) struct NamespaceName(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for NamespaceName {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let params = Path::<HashMap<String, String>>::from_request_parts(parts, state).await;
+        let name = params
+            .ok()
+            .and_then(|Path(mut params)| params.remove("namespace"))
+            .unwrap_or_default();
+        Ok(NamespaceName(name))
+    }
+}
+
+impl AppState {
+    /// Creates `name`, or updates its quota if it already exists. Returns
+    /// whether it was created.
+    pub fn create_namespace(&mut self, name: &str, quota: Quota) -> io::Result<bool> {
+        if !is_valid_name(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{name:?} is not a valid namespace name"),
+            ));
+        }
+        let created = !self.namespaces.contains_key(name);
+        self.commit(Record::Namespace {
+            name: name.to_string(),
+            quota,
+        })?;
+        Ok(created)
+    }
+
+    /// Deletes `name` and every key in it. Returns whether it existed.
+    pub fn drop_namespace(&mut self, name: &str) -> io::Result<bool> {
+        if !self.namespaces.contains_key(name) {
+            return Ok(false);
+        }
+        self.commit(Record::DropNamespace {
+            name: name.to_string(),
+        })?;
+        Ok(true)
+    }
+
+    pub fn has_namespace(&self, name: &str) -> bool {
+        name == DEFAULT || self.namespaces.contains_key(name)
+    }
+
+    /// All namespaces apart from the default one, ordered by name.
+    pub fn namespaces(&self) -> Vec<NamespaceInfo> {
+        let mut namespaces: Vec<NamespaceInfo> = self
+            .namespaces
+            .iter()
+            .map(|(name, namespace)| NamespaceInfo {
+                name: name.clone(),
+                keys: namespace.keys.load(Ordering::Relaxed),
+                bytes: namespace.bytes.load(Ordering::Relaxed),
+                max_keys: namespace.quota.max_keys,
+                max_bytes: namespace.quota.max_bytes,
+            })
+            .collect();
+        namespaces.sort_by(|a, b| a.name.cmp(&b.name));
+        namespaces
+    }
+
+    /// Up to `limit` keys of `namespace` starting with `prefix` in
+    /// lexicographic order, beginning after the key `after`.
+    pub fn scan_in(
+        &self,
+        namespace: &str,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Vec<String> {
+        let mut keys = Vec::new();
+        for shard in self.read_shards() {
+            keys.extend(shard.scan_in(namespace, prefix, after, limit));
+        }
+        keys.sort_unstable();
+        keys.truncate(limit);
+        keys
+    }
+
+    /// Up to `limit` entries of `namespace` with keys between `start` and
+    /// `end` in lexicographic order.
+    pub fn range_in(
+        &self,
+        namespace: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: usize,
+    ) -> Vec<(String, Bytes)> {
+        let mut entries = Vec::new();
+        for shard in self.read_shards() {
+            entries.extend(shard.range_in(namespace, start, end, limit));
+        }
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        entries.truncate(limit);
+        entries
+    }
+
+    /// Fails if applying `record` would take a namespace over its quota or
+    /// write to one that doesn't exist. Namespaces that are over their quota
+    /// already, e.g. as it was lowered, can still shrink. `size` tells how
+    /// large the entry of a key is now.
+    pub(crate) fn check_quotas(
+        &self,
+        record: &Record,
+        size: impl Fn(&str) -> Option<usize>,
+    ) -> io::Result<()> {
+        let mut sizes = HashMap::new();
+        collect_sizes(record, &mut sizes);
+
+        let mut changes: HashMap<&str, (isize, isize)> = HashMap::new();
+        for (key, after) in sizes {
+            let (namespace, own) = split(key);
+            if namespace == DEFAULT {
+                continue;
+            }
+            let before = size(key).map(|size| size - (key.len() - own.len()));
+            let change = changes.entry(namespace).or_default();
+            change.0 += after.is_some() as isize - before.is_some() as isize;
+            change.1 += after.unwrap_or(0) as isize - before.unwrap_or(0) as isize;
+        }
+
+        for (name, (keys, bytes)) in changes {
+            let Some(namespace) = self.namespaces.get(name) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("namespace {name} does not exist"),
+                ));
+            };
+            let exceeds = |used: usize, change: isize, max: Option<usize>| {
+                change > 0 && max.is_some_and(|max| used as isize + change > max as isize)
+            };
+            if exceeds(
+                namespace.keys.load(Ordering::Relaxed),
+                keys,
+                namespace.quota.max_keys,
+            ) || exceeds(
+                namespace.bytes.load(Ordering::Relaxed),
+                bytes,
+                namespace.quota.max_bytes,
+            ) {
+                return Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    format!("namespace {name} is over its quota"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apply_namespace(&mut self, record: Record) {
+        match record {
+            Record::Namespace { name, quota } => {
+                self.namespaces.entry(name).or_default().quota = quota;
+            }
+            Record::DropNamespace { name } => {
+                for mut shard in self.write_shards() {
+                    for key in shard.db.scan(&prefix(&name), None, usize::MAX) {
+                        self.remove_entry(&mut shard, &key);
+                    }
+                }
+                self.namespaces.remove(&name);
+            }
+            _ => unreachable!("not a namespace record: {record:?}"),
+        }
+    }
+
+    pub(crate) fn namespace_records(&self) -> impl Iterator<Item = Record> + '_ {
+        self.namespaces
+            .iter()
+            .map(|(name, namespace)| Record::Namespace {
+                name: name.clone(),
+                quota: namespace.quota,
+            })
+    }
+
+    /// Keeps the usage of the namespace of `key` up to date as its entry
+    /// changes from `before` to `after` bytes, `None` if there is none.
+    pub(crate) fn account(&self, key: &str, before: Option<usize>, after: Option<usize>) {
+        let (name, own) = split(key);
+        let Some(namespace) = self.namespaces.get(name) else {
+            return;
+        };
+        let prefix_len = key.len() - own.len();
+        if let Some(before) = before {
+            namespace.keys.fetch_sub(1, Ordering::Relaxed);
+            namespace
+                .bytes
+                .fetch_sub(before - prefix_len, Ordering::Relaxed);
+        }
+        if let Some(after) = after {
+            namespace.keys.fetch_add(1, Ordering::Relaxed);
+            namespace
+                .bytes
+                .fetch_add(after - prefix_len, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn clear_namespace_usage(&self) {
+        for namespace in self.namespaces.values() {
+            namespace.keys.store(0, Ordering::Relaxed);
+            namespace.bytes.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Keeps other writers of the namespace of `key` out until the guard
+    /// is dropped, if it has one besides the default.
+    pub(crate) fn lock_quota(&self, key: &str) -> Option<MutexGuard<'_, ()>> {
+        let (name, _) = split(key);
+        let namespace = self.namespaces.get(name)?;
+        Some(namespace.writing.lock().unwrap())
+    }
+}
+
+impl Shard {
+    /// The part of `AppState::scan_in` that is in this shard. Expired keys
+    /// are skipped and made up for.
+    fn scan_in(
+        &self,
+        namespace: &str,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Vec<String> {
+        let stored_prefix = self::prefix(namespace);
+        let mut keys = Vec::new();
+        let mut after = after.map(|after| stored_prefix.clone() + after);
+        while keys.len() < limit {
+            let wanted = limit - keys.len();
+            let batch = self
+                .db
+                .scan(&(stored_prefix.clone() + prefix), after.as_deref(), wanted);
+            let exhausted = batch.len() < wanted;
+            after = batch.last().cloned();
+            keys.extend(
+                batch
+                    .iter()
+                    .filter(|key| !self.is_expired(key))
+                    .filter_map(|key| own_key(namespace, key)),
+            );
+            if exhausted {
+                break;
+            }
+        }
+        keys
+    }
+
+    /// The part of `AppState::range_in` that is in this shard.
+    fn range_in(
+        &self,
+        namespace: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: usize,
+    ) -> Vec<(String, Bytes)> {
+        let stored_prefix = self::prefix(namespace);
+        let mut start = match start {
+            Bound::Included(start) => Bound::Included(stored_prefix.clone() + start),
+            Bound::Excluded(start) => Bound::Excluded(stored_prefix.clone() + start),
+            Bound::Unbounded => Bound::Included(stored_prefix.clone()),
+        };
+        let end = match end {
+            Bound::Included(end) => Bound::Included(stored_prefix.clone() + end),
+            Bound::Excluded(end) => Bound::Excluded(stored_prefix.clone() + end),
+            Bound::Unbounded if namespace == DEFAULT => Bound::Unbounded,
+            // The separator is the smallest character, anything after the
+            // namespace's keys starts with the next one.
+            Bound::Unbounded => Bound::Excluded(format!("{SEPARATOR}{namespace}\u{1}")),
+        };
+
+        let mut entries: Vec<(String, Bytes)> = Vec::new();
+        while entries.len() < limit {
+            let wanted = limit - entries.len();
+            let batch = self.db.range(
+                start.as_ref().map(String::as_str),
+                end.as_ref().map(String::as_str),
+                wanted,
+            );
+            let exhausted = batch.len() < wanted;
+            if let Some((last, _)) = batch.last() {
+                start = Bound::Excluded(last.clone());
+            }
+            entries.extend(
+                batch
+                    .into_iter()
+                    .filter(|(key, _)| !self.is_expired(key))
+                    .filter_map(|(key, value)| Some((own_key(namespace, &key)?, value))),
+            );
+            if exhausted {
+                break;
+            }
+        }
+        entries
+    }
+}
+
+/// The key within `namespace` if `stored` belongs to it.
+fn own_key(namespace: &str, stored: &str) -> Option<String> {
+    let (name, key) = split(stored);
+    (name == namespace).then(|| key.to_string())
+}
+
+/// The size every key written by `record` ends up with, without the
+/// namespace, `None` if it is deleted.
+fn collect_sizes<'a>(record: &'a Record, sizes: &mut HashMap<&'a str, Option<usize>>) {
+    match record {
+        Record::Set { key, value } | Record::SetEx { key, value, .. } => {
+            let (_, own) = split(key);
+            sizes.insert(key, Some(crate::eviction::entry_size(own, value)));
+        }
+        Record::Delete { key } => {
+            sizes.insert(key, None);
+        }
+        Record::Batch(records) => {
+            for record in records {
+                collect_sizes(record, sizes);
+            }
+        }
+        Record::Clear | Record::Namespace { .. } | Record::DropNamespace { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, ops::Bound};
+
+    use axum::body::Bytes;
+
+    use super::{key, split, Quota};
+    use crate::{AppState, FsyncPolicy};
+
+    fn set(db: &mut AppState, namespace: &str, k: &str, value: &'static [u8]) -> io::Result<()> {
+        db.set(key(namespace, k).unwrap(), Bytes::from_static(value))
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(key("", "a").unwrap(), "a");
+        assert_eq!(key("", "\0a"), None);
+        assert_eq!(split(&key("team", "a\0b").unwrap()), ("team", "a\0b"));
+        assert_eq!(split("a\0b"), ("", "a\0b"));
+    }
+
+    #[test]
+    fn namespaces_are_isolated() {
+        let mut db = AppState::default();
+        assert!(db.create_namespace("team", Quota::default()).unwrap());
+        assert!(db.create_namespace("other", Quota::default()).unwrap());
+        assert!(db.create_namespace("no/slash", Quota::default()).is_err());
+        set(&mut db, "", "a", b"1").unwrap();
+        set(&mut db, "team", "a", b"2").unwrap();
+        set(&mut db, "team", "b", b"3").unwrap();
+        set(&mut db, "other", "c", b"4").unwrap();
+
+        assert_eq!(db.keys(), vec!["a"]);
+        assert_eq!(db.scan("", None, 10), vec!["a"]);
+        assert_eq!(db.scan_in("team", "", None, 10), vec!["a", "b"]);
+        assert_eq!(db.scan_in("team", "", Some("a"), 10), vec!["b"]);
+        let keys = |entries: Vec<(String, Bytes)>| -> Vec<String> {
+            entries.into_iter().map(|(key, _)| key).collect()
+        };
+        assert_eq!(
+            keys(db.range(Bound::Unbounded, Bound::Unbounded, 10)),
+            vec!["a"]
+        );
+        assert_eq!(
+            keys(db.range_in("team", Bound::Unbounded, Bound::Unbounded, 10)),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            keys(db.range_in("other", Bound::Unbounded, Bound::Unbounded, 10)),
+            vec!["c"]
+        );
+
+        assert!(db.drop_namespace("team").unwrap());
+        assert!(!db.drop_namespace("team").unwrap());
+        assert!(!db.has_namespace("team"));
+        assert_eq!(db.len(), 2);
+        assert_eq!(
+            set(&mut db, "team", "a", b"2").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn quotas() {
+        let mut db = AppState::default();
+        let quota = Quota {
+            max_keys: Some(2),
+            max_bytes: Some(6),
+        };
+        db.create_namespace("team", quota).unwrap();
+        set(&mut db, "team", "a", b"12").unwrap();
+        set(&mut db, "team", "b", b"1").unwrap();
+
+        let full =
+            |result: io::Result<()>| result.unwrap_err().kind() == io::ErrorKind::StorageFull;
+        assert!(full(set(&mut db, "team", "c", b"1")));
+        assert!(full(set(&mut db, "team", "a", b"1234")));
+        // Overwriting within the quota is fine.
+        set(&mut db, "team", "a", b"1").unwrap();
+        let info = &db.namespaces()[0];
+        assert_eq!((info.keys, info.bytes), (2, 4));
+
+        db.create_namespace(
+            "team",
+            Quota {
+                max_keys: Some(1),
+                ..quota
+            },
+        )
+        .unwrap();
+        db.remove(&key("team", "b").unwrap()).unwrap();
+        assert!(full(set(&mut db, "team", "c", b"1")));
+        set(&mut db, "", "c", b"1").unwrap();
+    }
+
+    #[test]
+    fn namespaces_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+        let quota = Quota {
+            max_keys: Some(5),
+            max_bytes: None,
+        };
+
+        let mut db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        db.create_namespace("team", quota).unwrap();
+        db.create_namespace("gone", quota).unwrap();
+        set(&mut db, "team", "a", b"1").unwrap();
+        set(&mut db, "gone", "a", b"1").unwrap();
+        db.drop_namespace("gone").unwrap();
+        drop(db);
+
+        let db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        let namespaces = db.namespaces();
+        assert_eq!(namespaces.len(), 1);
+        assert_eq!(namespaces[0].name, "team");
+        assert_eq!(namespaces[0].keys, 1);
+        assert_eq!(namespaces[0].max_keys, Some(5));
+        assert_eq!(db.len(), 1);
+    }
+}
@@ -0,0 +1,562 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, request::Parts, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::{any, delete, get, post, put},
+    BoxError, Json, Router,
+};
+use hyper::{client::HttpConnector, Body, Client, Request};
+use serde::{Deserialize, Serialize};
+use tower_http::auth::RequireAuthorizationLayer;
+use tracing::{event, Level};
+
+use crate::{ring::Ring, ApiKeys};
+
+/// Keys listed per request while rebalancing.
+const LIST_LIMIT: usize = 1000;
+
+/// Settings of a proxy in front of several stores.
+#[derive(Debug, Clone)]
+pub struct ProxyOptions {
+    /// The backends the proxy starts out with, URLs like
+    /// `http://10.0.0.1:3000` by name.
+    pub backends: BTreeMap<String, String>,
+    /// Points each backend gets on the ring.
+    pub vnodes: usize,
+    /// Keys accepted by the `/admin` routes of the proxy.
+    pub api_keys: ApiKeys,
+    /// Admin API key of the backends, used to move keys between them.
+    pub backend_api_key: String,
+    /// How long a backend may take to respond before the request fails
+    /// with `504`.
+    pub timeout: Duration,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        Self {
+            backends: BTreeMap::new(),
+            vnodes: 128,
+            api_keys: ApiKeys::default(),
+            backend_api_key: String::new(),
+            timeout: Duration::from_secs(4),
+        }
+    }
+}
+
+/// Spreads the keys of the default namespace over several stores by
+/// consistent hashing.
+///
+/// When a backend is added or removed, the keys that now belong somewhere
+/// else are moved there. Until they all are, reads that come up empty fall
+/// back to where a key used to be, and deletes go to both places.
+#[derive(Clone)]
+pub struct Proxy {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    rings: RwLock<Rings>,
+    options: ProxyOptions,
+    client: Client<HttpConnector>,
+    /// Held while the backends change, one change at a time.
+    rebalancing: tokio::sync::Mutex<()>,
+    /// Keys that are being moved, and whether a client deleted them in the
+    /// meantime.
+    moving: Mutex<HashMap<String, bool>>,
+}
+
+struct Rings {
+    current: Ring,
+    /// Earlier rings, newest first, whose keys may not have moved yet.
+    stale: Vec<Ring>,
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Proxy")
+            .field("backends", &self.backends())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Number of keys a rebalance moved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Rebalanced {
+    pub moved: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyList {
+    keys: Vec<KeyInfo>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyInfo {
+    key: String,
+}
+
+impl Proxy {
+    pub fn new(options: ProxyOptions) -> Self {
+        let mut ring = Ring::new(options.vnodes);
+        for (name, url) in &options.backends {
+            ring.add(name, url);
+        }
+        Self {
+            inner: Arc::new(Inner {
+                rings: RwLock::new(Rings {
+                    current: ring,
+                    stale: Vec::new(),
+                }),
+                options,
+                client: Client::new(),
+                rebalancing: tokio::sync::Mutex::new(()),
+                moving: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// URL of each backend, by name.
+    pub fn backends(&self) -> BTreeMap<String, String> {
+        self.inner.rings.read().unwrap().current.backends().clone()
+    }
+
+    /// Adds the backend `name` at `url`, or moves it there, and moves the
+    /// keys that belong to it now. Returns whether it is a new backend.
+    ///
+    /// If moving the keys fails, the backend stays added and `rebalance`
+    /// can be tried again.
+    pub async fn add_backend(&self, name: &str, url: &str) -> Result<(bool, Rebalanced), BoxError> {
+        let _rebalancing = self.inner.rebalancing.lock().await;
+        let added = self.change(|ring| ring.add(name, url).is_none());
+        event!(Level::INFO, backend = name, url, "added a backend");
+        Ok((added, self.move_keys().await?))
+    }
+
+    /// Moves the keys of the backend `name` to the others and takes it off
+    /// the ring, `None` if there is no such backend. The last one can't be
+    /// removed.
+    pub async fn remove_backend(&self, name: &str) -> Result<Option<Rebalanced>, BoxError> {
+        let _rebalancing = self.inner.rebalancing.lock().await;
+        {
+            let rings = self.inner.rings.read().unwrap();
+            let backends = rings.current.backends();
+            if !backends.contains_key(name) {
+                return Ok(None);
+            }
+            if backends.len() == 1 {
+                return Err("the last backend can't be removed".into());
+            }
+        }
+        self.change(|ring| ring.remove(name));
+        event!(Level::INFO, backend = name, "removed a backend");
+        self.move_keys().await.map(Some)
+    }
+
+    /// Moves every key that isn't where it belongs, after a change of the
+    /// backends that failed halfway.
+    pub async fn rebalance(&self) -> Result<Rebalanced, BoxError> {
+        let _rebalancing = self.inner.rebalancing.lock().await;
+        self.move_keys().await
+    }
+
+    /// Applies `change` to the ring, keeping the old one around until the
+    /// keys have moved.
+    fn change<T>(&self, change: impl FnOnce(&mut Ring) -> T) -> T {
+        let mut rings = self.inner.rings.write().unwrap();
+        let old = rings.current.clone();
+        let result = change(&mut rings.current);
+        rings.stale.insert(0, old);
+        result
+    }
+
+    /// The backend `key` belongs to, followed by where it may still be if a
+    /// rebalance is under way.
+    fn owners(&self, key: &str) -> Vec<String> {
+        let rings = self.inner.rings.read().unwrap();
+        let mut owners: Vec<String> = Vec::new();
+        for ring in std::iter::once(&rings.current).chain(&rings.stale) {
+            if let Some((_, url)) = ring.backend(key) {
+                if !owners.iter().any(|owner| owner == url) {
+                    owners.push(url.to_string());
+                }
+            }
+        }
+        owners
+    }
+
+    /// Moves the keys of every backend, past and present, to their owner in
+    /// the current ring. Needs the `rebalancing` lock.
+    async fn move_keys(&self) -> Result<Rebalanced, BoxError> {
+        let (ring, mut urls) = {
+            let rings = self.inner.rings.read().unwrap();
+            let urls: Vec<String> = std::iter::once(&rings.current)
+                .chain(&rings.stale)
+                .flat_map(|ring| ring.backends().values().cloned())
+                .collect();
+            (rings.current.clone(), urls)
+        };
+        urls.sort();
+        urls.dedup();
+
+        let mut moved = 0;
+        for url in &urls {
+            let mut cursor = None;
+            loop {
+                let list = self.list(url, cursor.as_deref()).await?;
+                for KeyInfo { key } in list.keys {
+                    let Some((_, owner)) = ring.backend(&key) else {
+                        return Err("there are no backends to move keys to".into());
+                    };
+                    if owner != url && self.move_key(&key, url, owner).await? {
+                        moved += 1;
+                    }
+                }
+                if list.next_cursor.is_none() {
+                    break;
+                }
+                cursor = list.next_cursor;
+            }
+        }
+
+        let mut rings = self.inner.rings.write().unwrap();
+        // Changes wait for the lock we hold, the ring is still the same.
+        rings.stale.clear();
+        event!(Level::INFO, moved, "rebalanced the backends");
+        Ok(Rebalanced { moved })
+    }
+
+    /// A page of the keys of the backend at `url`.
+    async fn list(&self, url: &str, cursor: Option<&str>) -> Result<KeyList, BoxError> {
+        let mut uri = format!("{}/kv?limit={LIST_LIMIT}", url.trim_end_matches('/'));
+        if let Some(cursor) = cursor {
+            uri.push_str(&format!("&cursor={}", encode(cursor)));
+        }
+        let response = self.call(Method::GET, &uri, &[], Body::empty()).await?;
+        if response.status() != StatusCode::OK {
+            return Err(format!(
+                "listing the keys of {url} failed with {}",
+                response.status()
+            )
+            .into());
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Copies `key` from the backend at `from` to the one at `to`, unless it
+    /// was written there in the meantime, and deletes it at `from`. Returns
+    /// whether there was anything to move.
+    async fn move_key(&self, key: &str, from: &str, to: &str) -> Result<bool, BoxError> {
+        self.inner
+            .moving
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), false);
+        let copied = self.copy_key(key, from, to).await;
+        let deleted = self.inner.moving.lock().unwrap().remove(key) == Some(true);
+        let Some(etag) = copied? else {
+            return Ok(false);
+        };
+        if deleted {
+            // The delete reached `to` before the copy did, which must not
+            // bring the key back.
+            self.delete_if(key, to, &etag).await?;
+        }
+        self.delete_if(key, from, &etag).await?;
+        Ok(!deleted)
+    }
+
+    /// Copies `key` from `from` to `to`, returning the `ETag` of what was
+    /// copied, `None` if there was nothing to copy.
+    async fn copy_key(
+        &self,
+        key: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<Option<HeaderValue>, BoxError> {
+        let response = self
+            .call(Method::GET, &key_url(from, "kv", key), &[], Body::empty())
+            .await?;
+        match response.status() {
+            StatusCode::OK => {}
+            // Expired or deleted since it was listed.
+            StatusCode::NOT_FOUND => return Ok(None),
+            status => return Err(format!("reading {key} from {from} failed with {status}").into()),
+        }
+        let Some(etag) = response.headers().get(header::ETAG).cloned() else {
+            return Err(format!("{from} sent {key} without an ETag").into());
+        };
+        let ttl = response.headers().get("x-expire-after").cloned();
+        let value = hyper::body::to_bytes(response.into_body()).await?;
+
+        let mut headers = vec![(
+            header::IF_NONE_MATCH.as_str(),
+            HeaderValue::from_static("*"),
+        )];
+        headers.extend(ttl.map(|ttl| ("x-expire-after", ttl)));
+        let response = self
+            .call(
+                Method::POST,
+                &key_url(to, "kv", key),
+                &headers,
+                Body::from(value),
+            )
+            .await?;
+        // A newer value was written to its new place already.
+        if !matches!(
+            response.status(),
+            StatusCode::OK | StatusCode::PRECONDITION_FAILED
+        ) {
+            let status = response.status();
+            return Err(format!("writing {key} to {to} failed with {status}").into());
+        }
+        Ok(Some(etag))
+    }
+
+    /// Deletes `key` at `url` unless its value changed from the one `etag`
+    /// stands for, or it is gone already.
+    async fn delete_if(&self, key: &str, url: &str, etag: &HeaderValue) -> Result<(), BoxError> {
+        let headers = [(header::IF_MATCH.as_str(), etag.clone())];
+        let response = self
+            .call(
+                Method::DELETE,
+                &key_url(url, "admin/keys", key),
+                &headers,
+                Body::empty(),
+            )
+            .await?;
+        match response.status() {
+            StatusCode::OK | StatusCode::NOT_FOUND | StatusCode::PRECONDITION_FAILED => Ok(()),
+            status => Err(format!("deleting {key} from {url} failed with {status}").into()),
+        }
+    }
+
+    /// Sends a request of our own to a backend.
+    async fn call(
+        &self,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, HeaderValue)],
+        body: Body,
+    ) -> Result<Response<Body>, BoxError> {
+        let mut request = Request::builder().method(method).uri(uri).header(
+            header::AUTHORIZATION,
+            format!("Bearer {}", self.inner.options.backend_api_key),
+        );
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let request = request.body(body)?;
+        let response = tokio::time::timeout(
+            self.inner.options.timeout,
+            self.inner.client.request(request),
+        )
+        .await??;
+        Ok(response)
+    }
+
+    /// Passes a request of a client on to the backend at `url`.
+    async fn forward(&self, url: &str, parts: &Parts, body: Body) -> Response {
+        let path = parts
+            .uri
+            .path_and_query()
+            .map_or(parts.uri.path(), |path| path.as_str());
+        let Ok(uri) = format!("{}{path}", url.trim_end_matches('/')).parse::<Uri>() else {
+            return StatusCode::BAD_GATEWAY.into_response();
+        };
+        let mut request = Request::new(body);
+        *request.method_mut() = parts.method.clone();
+        *request.uri_mut() = uri;
+        *request.headers_mut() = parts.headers.clone();
+        request.headers_mut().remove(header::HOST);
+
+        let response = tokio::time::timeout(
+            self.inner.options.timeout,
+            self.inner.client.request(request),
+        )
+        .await;
+        match response {
+            Ok(Ok(response)) => response.into_response(),
+            Ok(Err(err)) => {
+                event!(Level::WARN, backend = url, "forwarding failed: {err}");
+                StatusCode::BAD_GATEWAY.into_response()
+            }
+            Err(_) => StatusCode::GATEWAY_TIMEOUT.into_response(),
+        }
+    }
+}
+
+/// The routes of a proxy: `/kv/:key` and `/admin/keys/:key` are passed on
+/// to the backend of the key, as they are. `/admin/backends` lists and
+/// changes the backends.
+#[allow(clippy::result_large_err)]
+pub fn proxy_router(proxy: &Proxy) -> Router {
+    let api_keys = proxy.inner.options.api_keys.clone();
+    let admin = Router::new()
+        .route("/backends", get(list_backends))
+        .route("/backends/:name", put(put_backend).delete(remove_backend))
+        .route("/rebalance", post(rebalance))
+        .with_state(proxy.clone())
+        .layer(RequireAuthorizationLayer::custom(
+            move |req: &mut Request<Body>| api_keys.authorize_admin(req).map(drop),
+        ));
+
+    Router::new()
+        .route("/kv/:key", any(forward))
+        .route("/admin/keys/:key", delete(forward))
+        .with_state(proxy.clone())
+        .nest("/admin", admin)
+}
+
+/// Reads that come up empty try the places the key may still be in, and
+/// deletes go to all of them.
+async fn forward(
+    State(proxy): State<Proxy>,
+    Path(key): Path<String>,
+    request: Request<Body>,
+) -> Response {
+    let mut owners = proxy.owners(&key).into_iter();
+    let Some(owner) = owners.next() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "there are no backends").into_response();
+    };
+    let (parts, body) = request.into_parts();
+    if parts.method == Method::DELETE {
+        // Noted before the delete goes out, so that a move that copies the
+        // key after it reached the new owner undoes the copy.
+        if let Some(deleted) = proxy.inner.moving.lock().unwrap().get_mut(&key) {
+            *deleted = true;
+        }
+    }
+    let response = proxy.forward(&owner, &parts, body).await;
+
+    match parts.method {
+        Method::GET | Method::HEAD => {
+            let mut response = response;
+            for url in owners {
+                if response.status() != StatusCode::NOT_FOUND {
+                    break;
+                }
+                response = proxy.forward(&url, &parts, Body::empty()).await;
+            }
+            response
+        }
+        Method::DELETE => {
+            let mut response = response;
+            for url in owners {
+                let other = proxy.forward(&url, &parts, Body::empty()).await;
+                if !response.status().is_success() && other.status().is_success() {
+                    response = other;
+                }
+            }
+            response
+        }
+        _ => response,
+    }
+}
+
+async fn list_backends(State(proxy): State<Proxy>) -> Json<BTreeMap<String, String>> {
+    Json(proxy.backends())
+}
+
+#[derive(Debug, Deserialize)]
+struct Backend {
+    url: String,
+}
+
+/// Responds with `201 Created` for a new backend and `200 OK` if only its
+/// URL changed.
+async fn put_backend(
+    State(proxy): State<Proxy>,
+    Path(name): Path<String>,
+    Json(backend): Json<Backend>,
+) -> Result<(StatusCode, Json<Rebalanced>), (StatusCode, String)> {
+    let uri: Uri = backend
+        .url
+        .parse()
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("invalid URL: {err}")))?;
+    if uri.scheme_str() != Some("http") || uri.host().is_none() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "the URL must be like http://host:port".to_string(),
+        ));
+    }
+    let (added, rebalanced) = proxy
+        .add_backend(&name, &backend.url)
+        .await
+        .map_err(rebalance_error)?;
+    let status = if added {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(rebalanced)))
+}
+
+async fn remove_backend(
+    State(proxy): State<Proxy>,
+    Path(name): Path<String>,
+) -> Result<Json<Rebalanced>, (StatusCode, String)> {
+    if proxy.backends().len() == 1 && proxy.backends().contains_key(&name) {
+        return Err((
+            StatusCode::CONFLICT,
+            "the last backend can't be removed".to_string(),
+        ));
+    }
+    match proxy.remove_backend(&name).await {
+        Ok(Some(rebalanced)) => Ok(Json(rebalanced)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, String::new())),
+        Err(err) => Err(rebalance_error(err)),
+    }
+}
+
+async fn rebalance(State(proxy): State<Proxy>) -> Result<Json<Rebalanced>, (StatusCode, String)> {
+    proxy.rebalance().await.map(Json).map_err(rebalance_error)
+}
+
+fn rebalance_error(err: BoxError) -> (StatusCode, String) {
+    event!(Level::WARN, "rebalancing failed: {err}");
+    (
+        StatusCode::BAD_GATEWAY,
+        format!("rebalancing failed: {err}"),
+    )
+}
+
+/// The URL of `key` under `route` of the backend at `url`.
+fn key_url(url: &str, route: &str, key: &str) -> String {
+    format!("{}/{route}/{}", url.trim_end_matches('/'), encode(key))
+}
+
+/// Percent-encodes everything but the unreserved characters, for keys in
+/// paths and queries.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode;
+
+    #[test]
+    fn keys_are_encoded() {
+        assert_eq!(encode("user-1.name_~"), "user-1.name_~");
+        assert_eq!(encode("a/b c?d"), "a%2Fb%20c%3Fd");
+        assert_eq!(encode("é"), "%C3%A9");
+    }
+}
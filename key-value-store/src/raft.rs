@@ -0,0 +1,767 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::Duration,
+};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{etag::Preconditions, wal::Record};
+
+/// Identifies a node of a cluster, it must never be reused by another one.
+pub type NodeId = u64;
+
+/// Base URLs of the members of a cluster, by id.
+pub type Members = BTreeMap<NodeId, String>;
+
+/// What a node of a cluster is doing in the current term.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// The changes the log of a cluster is made of.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Command {
+    /// Appended by each new leader, so that it can commit the entries of
+    /// earlier terms.
+    Noop,
+    /// A write of a single key, that each node applies to its store if the
+    /// preconditions still hold by then.
+    Write {
+        #[serde(with = "frame")]
+        record: Record,
+        #[serde(default)]
+        preconditions: Preconditions,
+    },
+    /// The members of the cluster from this entry on, whether it is
+    /// committed or not.
+    Members(Members),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) term: u64,
+    pub(crate) command: Command,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct VoteRequest {
+    pub(crate) term: u64,
+    pub(crate) candidate: NodeId,
+    pub(crate) last_index: u64,
+    pub(crate) last_term: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct VoteResponse {
+    pub(crate) term: u64,
+    pub(crate) granted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AppendRequest {
+    pub(crate) term: u64,
+    pub(crate) leader: NodeId,
+    pub(crate) prev_index: u64,
+    pub(crate) prev_term: u64,
+    pub(crate) entries: Vec<Entry>,
+    pub(crate) commit: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AppendResponse {
+    pub(crate) term: u64,
+    pub(crate) success: bool,
+    /// The last index that now matches the leader's log, or where the
+    /// leader should continue from if the entries didn't fit.
+    pub(crate) index: u64,
+}
+
+/// The whole store as of `index`, for followers that are too far behind
+/// for the entries that are still in the log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SnapshotRequest {
+    pub(crate) term: u64,
+    pub(crate) leader: NodeId,
+    pub(crate) index: u64,
+    pub(crate) index_term: u64,
+    pub(crate) members: Members,
+    #[serde(with = "frames")]
+    pub(crate) records: Vec<Record>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SnapshotResponse {
+    pub(crate) term: u64,
+}
+
+/// Where the log starts, everything before is part of the store already.
+#[derive(Debug, Clone, Default)]
+struct Snapshot {
+    index: u64,
+    term: u64,
+    members: Members,
+}
+
+/// The consensus of a single node, without any I/O. Messages to other
+/// nodes are returned, their responses handed back in.
+///
+/// Nothing is persisted: a node that restarts has forgotten its votes and
+/// log, so it has to rejoin the cluster under a new id. `Cluster::start`
+/// refuses to restart a node on the store it ran on.
+#[derive(Debug)]
+pub(crate) struct Raft {
+    pub(crate) id: NodeId,
+    pub(crate) term: u64,
+    voted_for: Option<NodeId>,
+    pub(crate) role: Role,
+    pub(crate) leader: Option<NodeId>,
+    snapshot: Snapshot,
+    /// Entries after the snapshot, the first one has the index after it.
+    log: Vec<Entry>,
+    pub(crate) commit: u64,
+    pub(crate) applied: u64,
+    /// The latest members in the log, committed or not.
+    pub(crate) members: Members,
+    /// Where they were set.
+    members_index: u64,
+    votes: BTreeSet<NodeId>,
+    /// Next entry to send to each follower, while leading.
+    next: HashMap<NodeId, u64>,
+    /// Last entry known to be replicated on each follower, while leading.
+    matched: HashMap<NodeId, u64>,
+    election_timeout: Duration,
+    /// When to start an election unless a leader is heard of.
+    deadline: Instant,
+    /// When a leader was last heard of.
+    heard: Option<Instant>,
+}
+
+impl Raft {
+    /// A node of a cluster that starts out with `members`. Nodes that are
+    /// not among them wait until a leader adds them.
+    pub(crate) fn new(
+        id: NodeId,
+        members: Members,
+        election_timeout: Duration,
+        now: Instant,
+    ) -> Self {
+        let mut raft = Self {
+            id,
+            term: 0,
+            voted_for: None,
+            role: Role::Follower,
+            leader: None,
+            snapshot: Snapshot {
+                members: members.clone(),
+                ..Snapshot::default()
+            },
+            log: Vec::new(),
+            commit: 0,
+            applied: 0,
+            members,
+            members_index: 0,
+            votes: BTreeSet::new(),
+            next: HashMap::new(),
+            matched: HashMap::new(),
+            election_timeout,
+            deadline: now,
+            heard: None,
+        };
+        raft.reset_deadline(now);
+        raft
+    }
+
+    pub(crate) fn last_index(&self) -> u64 {
+        self.snapshot.index + self.log.len() as u64
+    }
+
+    pub(crate) fn last_term(&self) -> u64 {
+        self.log
+            .last()
+            .map_or(self.snapshot.term, |entry| entry.term)
+    }
+
+    /// The term of the entry at `index`, `None` if it is not in the log.
+    pub(crate) fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    pub(crate) fn entry(&self, index: u64) -> Option<&Entry> {
+        let offset = index.checked_sub(self.snapshot.index + 1)?;
+        self.log.get(offset as usize)
+    }
+
+    /// The other members, to send messages to.
+    pub(crate) fn peers(&self) -> Vec<(NodeId, String)> {
+        self.members
+            .iter()
+            .filter(|(id, _)| **id != self.id)
+            .map(|(id, url)| (*id, url.clone()))
+            .collect()
+    }
+
+    /// The URL of the leader, if this node knows it.
+    pub(crate) fn leader_url(&self) -> Option<&str> {
+        self.leader
+            .and_then(|leader| self.members.get(&leader))
+            .map(String::as_str)
+    }
+
+    /// The members as of the entry at `index`.
+    pub(crate) fn members_at(&self, index: u64) -> Members {
+        (self.snapshot.index + 1..=index.min(self.last_index()))
+            .rev()
+            .find_map(|index| match &self.entry(index)?.command {
+                Command::Members(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot.members.clone())
+    }
+
+    fn quorum(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    fn reset_deadline(&mut self, now: Instant) {
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.election_timeout);
+        self.deadline = now + self.election_timeout + jitter;
+    }
+
+    /// Follows a newer term that came up in a message.
+    fn observe(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.role = Role::Follower;
+            self.leader = None;
+        }
+    }
+
+    /// Whether this node should try to become the leader. Nodes that are
+    /// not members never do.
+    pub(crate) fn election_due(&self, now: Instant) -> bool {
+        self.role != Role::Leader && now >= self.deadline && self.members.contains_key(&self.id)
+    }
+
+    /// Starts a new term as a candidate, a cluster of one is won at once.
+    pub(crate) fn start_election(&mut self, now: Instant) -> VoteRequest {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id);
+        self.votes = BTreeSet::from([self.id]);
+        self.reset_deadline(now);
+        self.count_votes();
+        VoteRequest {
+            term: self.term,
+            candidate: self.id,
+            last_index: self.last_index(),
+            last_term: self.last_term(),
+        }
+    }
+
+    pub(crate) fn handle_vote_request(&mut self, req: &VoteRequest, now: Instant) -> VoteResponse {
+        // Members that were removed don't hear about it, and would disrupt
+        // the cluster with their elections otherwise.
+        let leader_alive = self
+            .heard
+            .is_some_and(|heard| now < heard + self.election_timeout);
+        if leader_alive && self.leader.is_some_and(|leader| leader != req.candidate) {
+            return VoteResponse {
+                term: self.term,
+                granted: false,
+            };
+        }
+        self.observe(req.term);
+        let up_to_date = (req.last_term, req.last_index) >= (self.last_term(), self.last_index());
+        let granted = req.term == self.term
+            && up_to_date
+            && self.voted_for.is_none_or(|voted| voted == req.candidate);
+        if granted {
+            self.voted_for = Some(req.candidate);
+            self.reset_deadline(now);
+        }
+        VoteResponse {
+            term: self.term,
+            granted,
+        }
+    }
+
+    pub(crate) fn handle_vote_response(&mut self, from: NodeId, resp: &VoteResponse) {
+        self.observe(resp.term);
+        if self.role == Role::Candidate && resp.term == self.term && resp.granted {
+            self.votes.insert(from);
+            self.count_votes();
+        }
+    }
+
+    fn count_votes(&mut self) {
+        let votes = self
+            .votes
+            .iter()
+            .filter(|id| self.members.contains_key(id))
+            .count();
+        if self.role == Role::Candidate && votes >= self.quorum() {
+            self.role = Role::Leader;
+            self.leader = Some(self.id);
+            let next = self.last_index() + 1;
+            self.next = self.peers().into_iter().map(|(id, _)| (id, next)).collect();
+            self.matched = self.peers().into_iter().map(|(id, _)| (id, 0)).collect();
+            self.push(Command::Noop);
+        }
+    }
+
+    /// Appends `command` to the log of the leader, returning its index and
+    /// term, or the leader to ask instead.
+    pub(crate) fn propose(&mut self, command: Command) -> Result<(u64, u64), Option<NodeId>> {
+        if self.role != Role::Leader {
+            return Err(self.leader);
+        }
+        self.push(command);
+        Ok((self.last_index(), self.term))
+    }
+
+    /// Whether a change of members is still waiting to be committed. Only
+    /// one member is added or removed at a time.
+    pub(crate) fn changing_members(&self) -> bool {
+        self.members_index > self.commit
+    }
+
+    fn push(&mut self, command: Command) {
+        if let Command::Members(members) = &command {
+            let next = self.last_index() + 1;
+            for id in members.keys().filter(|id| **id != self.id) {
+                self.next.entry(*id).or_insert(next);
+                self.matched.entry(*id).or_insert(0);
+            }
+            self.members = members.clone();
+            self.members_index = self.last_index() + 1;
+        }
+        self.log.push(Entry {
+            term: self.term,
+            command,
+        });
+        self.advance_commit();
+    }
+
+    /// What to send to `peer` next, `None` if the entries it needs were
+    /// compacted and it needs a snapshot instead.
+    pub(crate) fn append_request(&self, peer: NodeId, max_entries: usize) -> Option<AppendRequest> {
+        let next = self.next.get(&peer).copied()?;
+        let prev_index = next - 1;
+        let prev_term = self.term_at(prev_index)?;
+        let start = (next - self.snapshot.index - 1) as usize;
+        Some(AppendRequest {
+            term: self.term,
+            leader: self.id,
+            prev_index,
+            prev_term,
+            entries: self.log[start..]
+                .iter()
+                .take(max_entries)
+                .cloned()
+                .collect(),
+            commit: self.commit,
+        })
+    }
+
+    /// Whether `peer` is missing entries the leader has.
+    pub(crate) fn behind(&self, peer: NodeId) -> bool {
+        self.role == Role::Leader
+            && self
+                .next
+                .get(&peer)
+                .is_some_and(|next| *next <= self.last_index())
+    }
+
+    pub(crate) fn handle_append_request(
+        &mut self,
+        req: AppendRequest,
+        now: Instant,
+    ) -> AppendResponse {
+        if req.term < self.term {
+            return AppendResponse {
+                term: self.term,
+                success: false,
+                index: 0,
+            };
+        }
+        self.follow(req.term, req.leader, now);
+
+        // Entries up to the commit index match those of every leader, so
+        // that is where to go on from if the logs differ.
+        let retry = AppendResponse {
+            term: self.term,
+            success: false,
+            index: self.commit + 1,
+        };
+        let (mut prev_index, mut prev_term, mut entries) =
+            (req.prev_index, req.prev_term, req.entries);
+        if prev_index < self.snapshot.index {
+            let skip = (self.snapshot.index - prev_index) as usize;
+            if entries.len() < skip {
+                return retry;
+            }
+            entries.drain(..skip);
+            prev_index = self.snapshot.index;
+            prev_term = self.snapshot.term;
+        }
+        if self.term_at(prev_index) != Some(prev_term) {
+            return retry;
+        }
+
+        let last_new = prev_index + entries.len() as u64;
+        for (index, entry) in (prev_index + 1..).zip(entries) {
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.log
+                        .truncate((index - self.snapshot.index - 1) as usize);
+                    self.log.push(entry);
+                }
+                None => self.log.push(entry),
+            }
+        }
+        self.update_members();
+        if req.commit > self.commit {
+            self.commit = req.commit.min(last_new).max(self.commit);
+        }
+        AppendResponse {
+            term: self.term,
+            success: true,
+            index: last_new,
+        }
+    }
+
+    fn follow(&mut self, term: u64, leader: NodeId, now: Instant) {
+        self.observe(term);
+        self.role = Role::Follower;
+        self.leader = Some(leader);
+        self.heard = Some(now);
+        self.reset_deadline(now);
+    }
+
+    /// Sets the members to the latest in the log, after it changed.
+    fn update_members(&mut self) {
+        let (index, members) = (self.snapshot.index + 1..=self.last_index())
+            .rev()
+            .find_map(|index| match &self.entry(index)?.command {
+                Command::Members(members) => Some((index, members.clone())),
+                _ => None,
+            })
+            .unwrap_or_else(|| (self.snapshot.index, self.snapshot.members.clone()));
+        self.members_index = index;
+        self.members = members;
+    }
+
+    pub(crate) fn handle_append_response(&mut self, peer: NodeId, resp: &AppendResponse) {
+        self.observe(resp.term);
+        if self.role != Role::Leader || resp.term != self.term {
+            return;
+        }
+        let (Some(next), Some(matched)) = (self.next.get_mut(&peer), self.matched.get_mut(&peer))
+        else {
+            return;
+        };
+        if resp.success {
+            *matched = (*matched).max(resp.index);
+            *next = (*next).max(resp.index + 1);
+            self.advance_commit();
+        } else {
+            *next = resp.index.max(*matched + 1);
+        }
+    }
+
+    /// What to put into a snapshot for a follower: the index and term it
+    /// ends at, which is the last applied entry, and the members by then.
+    pub(crate) fn snapshot_point(&self) -> (u64, u64, Members) {
+        let index = self.applied;
+        let term = self.term_at(index).expect("applied entries are in the log");
+        (index, term, self.members_at(index))
+    }
+
+    /// Takes in a snapshot from the leader, returning whether the store has
+    /// to be restored from it.
+    pub(crate) fn handle_snapshot_request(&mut self, req: &SnapshotRequest, now: Instant) -> bool {
+        if req.term < self.term {
+            return false;
+        }
+        self.follow(req.term, req.leader, now);
+        req.index > self.applied
+    }
+
+    /// Starts the log after the snapshot the store was restored from.
+    pub(crate) fn install_snapshot(&mut self, index: u64, term: u64, members: Members) {
+        if self.term_at(index) == Some(term) {
+            self.log.drain(..(index - self.snapshot.index) as usize);
+        } else {
+            self.log.clear();
+        }
+        self.snapshot = Snapshot {
+            index,
+            term,
+            members,
+        };
+        self.commit = self.commit.max(index);
+        self.applied = index;
+        self.update_members();
+    }
+
+    pub(crate) fn handle_snapshot_response(
+        &mut self,
+        peer: NodeId,
+        index: u64,
+        resp: &SnapshotResponse,
+    ) {
+        self.observe(resp.term);
+        if self.role != Role::Leader || resp.term != self.term {
+            return;
+        }
+        if let Some(matched) = self.matched.get_mut(&peer) {
+            *matched = (*matched).max(index);
+            self.next.insert(peer, *matched + 1);
+        }
+        self.advance_commit();
+    }
+
+    /// Drops the entries that were applied, once there are more than
+    /// `max_entries` of them.
+    pub(crate) fn compact(&mut self, max_entries: usize) {
+        if self.applied - self.snapshot.index <= max_entries as u64 {
+            return;
+        }
+        let (index, term, members) = self.snapshot_point();
+        self.log.drain(..(index - self.snapshot.index) as usize);
+        self.snapshot = Snapshot {
+            index,
+            term,
+            members,
+        };
+    }
+
+    /// Commits the entries of this term that a majority of the members
+    /// have.
+    fn advance_commit(&mut self) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let mut matched: Vec<u64> = self
+            .members
+            .keys()
+            .map(|id| match *id == self.id {
+                true => self.last_index(),
+                false => self.matched.get(id).copied().unwrap_or(0),
+            })
+            .collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let Some(&index) = matched.get(self.quorum() - 1) else {
+            return;
+        };
+        if index > self.commit && self.term_at(index) == Some(self.term) {
+            self.commit = index;
+        }
+        // A leader that was removed leads until the removal is committed.
+        if !self.members.contains_key(&self.id) && !self.changing_members() {
+            self.role = Role::Follower;
+            self.leader = None;
+        }
+    }
+}
+
+/// A record as a base64 encoded frame of the write-ahead log.
+mod frame {
+    use serde::{de, Deserializer, Serializer};
+
+    use crate::wal::Record;
+
+    pub(super) fn serialize<S: Serializer>(record: &Record, s: S) -> Result<S::Ok, S::Error> {
+        super::frames::serialize(std::slice::from_ref(record), s)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Record, D::Error> {
+        let mut records = super::frames::deserialize(d)?;
+        match records.len() {
+            1 => Ok(records.remove(0)),
+            n => Err(de::Error::custom(format!("expected one record, got {n}"))),
+        }
+    }
+}
+
+/// Records as base64 encoded frames of the write-ahead log.
+mod frames {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    use crate::wal::{self, Record};
+
+    pub(super) fn serialize<S: Serializer>(records: &[Record], s: S) -> Result<S::Ok, S::Error> {
+        let frames: Vec<u8> = records.iter().flat_map(Record::encode).collect();
+        s.serialize_str(&BASE64.encode(frames))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Record>, D::Error> {
+        let frames = BASE64
+            .decode(String::deserialize(d)?)
+            .map_err(de::Error::custom)?;
+        let (records, used) = wal::replay(&frames);
+        if used != frames.len() {
+            return Err(de::Error::custom("corrupt record"));
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::Bytes;
+    use tokio::time::Instant;
+
+    use super::{Command, Members, Raft, Role};
+    use crate::wal::Record;
+
+    fn members(ids: &[u64]) -> Members {
+        ids.iter()
+            .map(|id| (*id, format!("http://node-{id}")))
+            .collect()
+    }
+
+    fn write(key: &str) -> Command {
+        Command::Write {
+            record: Record::Set {
+                key: key.to_string(),
+                value: Bytes::from_static(b"1"),
+            },
+            preconditions: Default::default(),
+        }
+    }
+
+    /// Node 1 of three, elected with the vote of node 2.
+    fn leader(now: Instant) -> (Raft, Raft, Raft) {
+        let timeout = Duration::from_millis(100);
+        let mut nodes: Vec<Raft> = (1..=3)
+            .map(|id| Raft::new(id, members(&[1, 2, 3]), timeout, now))
+            .collect();
+        let req = nodes[0].start_election(now);
+        let resp = nodes[1].handle_vote_request(&req, now);
+        nodes[0].handle_vote_response(2, &resp);
+        assert_eq!(nodes[0].role, Role::Leader);
+        let third = nodes.pop().unwrap();
+        let second = nodes.pop().unwrap();
+        (nodes.pop().unwrap(), second, third)
+    }
+
+    #[test]
+    fn single_node_elects_itself() {
+        let now = Instant::now();
+        let mut raft = Raft::new(1, members(&[1]), Duration::from_millis(100), now);
+        assert!(!raft.election_due(now));
+        assert!(raft.election_due(now + Duration::from_secs(1)));
+        raft.start_election(now);
+        assert_eq!(raft.role, Role::Leader);
+        assert_eq!(raft.propose(write("a")), Ok((2, 1)));
+        assert_eq!(raft.commit, 2);
+
+        let outsider = Raft::new(2, Members::new(), Duration::from_millis(100), now);
+        assert!(!outsider.election_due(now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn votes_go_to_up_to_date_candidates() {
+        let now = Instant::now();
+        let (mut leader, mut follower, mut stale) = leader(now);
+        leader.propose(write("a")).unwrap();
+        let resp = follower.handle_append_request(leader.append_request(2, 10).unwrap(), now);
+        assert!(resp.success);
+
+        // Node 3 missed the entries, so node 2 doesn't vote for it even
+        // once it stopped hearing from the leader.
+        let later = now + Duration::from_secs(1);
+        let req = stale.start_election(later);
+        assert!(!follower.handle_vote_request(&req, later).granted);
+        let req = follower.start_election(later);
+        assert!(stale.handle_vote_request(&req, later).granted);
+        // One vote per term.
+        let mut other = req.clone();
+        other.candidate = 1;
+        assert!(!stale.handle_vote_request(&other, later).granted);
+    }
+
+    #[test]
+    fn entries_commit_on_a_majority() {
+        let now = Instant::now();
+        let (mut leader, mut follower, _) = leader(now);
+        let (index, _) = leader.propose(write("a")).unwrap();
+        assert_eq!(leader.commit, 0);
+
+        let resp = follower.handle_append_request(leader.append_request(2, 10).unwrap(), now);
+        leader.handle_append_response(2, &resp);
+        assert_eq!(leader.commit, index);
+        assert!(!leader.behind(2));
+        assert!(leader.behind(3));
+
+        // The follower learns about the commit with the next heartbeat.
+        assert_eq!(follower.commit, 0);
+        follower.handle_append_request(leader.append_request(2, 10).unwrap(), now);
+        assert_eq!(follower.commit, index);
+    }
+
+    #[test]
+    fn conflicting_entries_are_replaced() {
+        let now = Instant::now();
+        let (mut old, mut follower, mut third) = leader(now);
+        // Entries that never make it beyond the old leader.
+        old.propose(write("lost")).unwrap();
+        old.propose(write("lost")).unwrap();
+
+        let later = now + Duration::from_secs(1);
+        let req = follower.start_election(later);
+        let resp = third.handle_vote_request(&req, later);
+        follower.handle_vote_response(3, &resp);
+        assert_eq!(follower.role, Role::Leader);
+        follower.propose(write("kept")).unwrap();
+
+        let mut resp = old.handle_append_request(follower.append_request(1, 10).unwrap(), later);
+        while !resp.success {
+            follower.handle_append_response(1, &resp);
+            resp = old.handle_append_request(follower.append_request(1, 10).unwrap(), later);
+        }
+        assert_eq!(old.role, Role::Follower);
+        assert_eq!(old.leader, Some(2));
+        assert_eq!(old.last_index(), follower.last_index());
+        assert_eq!(old.entry(old.last_index()).unwrap().command, write("kept"));
+    }
+
+    #[test]
+    fn compacted_followers_need_a_snapshot() {
+        let now = Instant::now();
+        let (mut leader, _, _) = leader(now);
+        for _ in 0..5 {
+            leader.propose(write("a")).unwrap();
+        }
+        leader
+            .propose(Command::Members(members(&[1, 2, 3, 4])))
+            .unwrap();
+        leader.commit = leader.last_index();
+        leader.applied = leader.commit;
+        leader.compact(2);
+        assert!(leader.append_request(4, 10).is_none());
+
+        let (index, term, snapshot_members) = leader.snapshot_point();
+        let mut joined = Raft::new(4, Members::new(), Duration::from_millis(100), now);
+        joined.install_snapshot(index, term, snapshot_members);
+        assert_eq!(joined.members, members(&[1, 2, 3, 4]));
+        assert_eq!(joined.last_index(), leader.last_index());
+    }
+}
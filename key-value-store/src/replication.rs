@@ -0,0 +1,198 @@
+use std::{
+    convert::Infallible,
+    future, io,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::State,
+    http::{header, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use futures::{stream, StreamExt};
+use hyper::{body::HttpBody, client::HttpConnector, Body, Client, Request};
+use tokio::{
+    sync::{
+        broadcast::{error::RecvError, Receiver},
+        RwLock,
+    },
+    task::JoinHandle,
+};
+use tracing::{event, Level};
+
+use crate::{durable, metrics, wal, wal::Record, AppState, SharedState};
+
+/// Records a follower can fall behind by before it is cut off and has to
+/// start over from a new snapshot.
+pub(crate) const CAPACITY: usize = 4096;
+
+/// Length of the record count in front of the snapshot.
+const COUNT_LEN: usize = 8;
+
+impl AppState {
+    /// A snapshot of the store, and every record committed after it. Needs
+    /// the store to itself, so that no write falls in between.
+    fn replicate(&mut self) -> (Vec<Record>, Receiver<Record>) {
+        (self.snapshot_records(), self.replication.subscribe())
+    }
+
+    /// Replaces everything in the store with the leader's `snapshot`.
+    pub(crate) fn restore(&mut self, snapshot: Vec<Record>) -> io::Result<()> {
+        let namespaces: Vec<String> = self.namespaces.keys().cloned().collect();
+        for name in namespaces {
+            self.replay(Record::DropNamespace { name })?;
+        }
+        self.replay(Record::Clear)?;
+        for record in snapshot {
+            self.replay(record)?;
+        }
+        Ok(())
+    }
+
+    /// Logs and applies a record of the leader as it is. The leader checked
+    /// it against the limits and quotas already, and logs its evictions.
+    fn replay(&mut self, record: Record) -> io::Result<()> {
+        let pending = self.append(&record)?;
+        self.apply(record);
+        durable(pending)
+    }
+}
+
+/// Streams a snapshot of the store to a follower, followed by every change
+/// as it is committed. Records are framed like in the write-ahead log, the
+/// snapshot is preceded by the number of records in it.
+///
+/// Followers that fall more than `CAPACITY` records behind are cut off.
+pub(crate) async fn replicate(State(state): State<SharedState>) -> impl IntoResponse {
+    let (snapshot, changes) = metrics::write(&state).await.replicate();
+    event!(
+        Level::INFO,
+        records = snapshot.len(),
+        "a follower connected"
+    );
+
+    let count = Bytes::copy_from_slice(&(snapshot.len() as u64).to_le_bytes());
+    let snapshot = stream::iter(snapshot).map(|record| Bytes::from(record.encode()));
+    let changes = stream::unfold(changes, |mut changes| async move {
+        match changes.recv().await {
+            Ok(record) => Some((Bytes::from(record.encode()), changes)),
+            Err(RecvError::Lagged(missed)) => {
+                event!(
+                    Level::WARN,
+                    missed,
+                    "a follower fell behind, cutting it off"
+                );
+                None
+            }
+            Err(RecvError::Closed) => None,
+        }
+    });
+    let body = stream::once(future::ready(count))
+        .chain(snapshot)
+        .chain(changes)
+        .map(Ok::<_, Infallible>);
+    (
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        StreamBody::new(body),
+    )
+}
+
+/// Rejects every request that could change something, for followers.
+pub(crate) async fn read_only<B>(req: Request<B>, next: Next<B>) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD) {
+        return next.run(req).await;
+    }
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        [(header::ALLOW, "GET, HEAD")],
+    )
+        .into_response()
+}
+
+/// Keeps `state` a copy of the store at `leader`, like
+/// `http://10.0.0.1:3000`, logging in with the admin key `api_key`.
+///
+/// Whenever the connection ends, the follower tries again after `retry` and
+/// starts over from a new snapshot. The task ends once the state is dropped.
+pub fn spawn_follower(
+    state: &SharedState,
+    leader: Uri,
+    api_key: String,
+    retry: Duration,
+) -> JoinHandle<()> {
+    let state = Arc::downgrade(state);
+    tokio::spawn(async move {
+        let client = Client::new();
+        loop {
+            match follow(&client, &state, &leader, &api_key).await {
+                Ok(()) => event!(Level::WARN, "the leader ended replication"),
+                Err(err) => event!(Level::WARN, "replicating from {leader} failed: {err}"),
+            }
+            if state.strong_count() == 0 {
+                break;
+            }
+            tokio::time::sleep(retry).await;
+        }
+    })
+}
+
+/// Applies the stream of the leader until it ends.
+async fn follow(
+    client: &Client<HttpConnector>,
+    state: &Weak<RwLock<AppState>>,
+    leader: &Uri,
+    api_key: &str,
+) -> Result<(), BoxError> {
+    let request = Request::get(format!(
+        "{}/replicate",
+        leader.to_string().trim_end_matches('/')
+    ))
+    .header(header::AUTHORIZATION, format!("Bearer {api_key}"))
+    .body(Body::empty())?;
+    let response = client.request(request).await?;
+    if response.status() != StatusCode::OK {
+        return Err(format!("the leader responded with {}", response.status()).into());
+    }
+    let mut body = response.into_body();
+
+    let mut buf = Vec::new();
+    // Records of the snapshot still to come, once their number is known.
+    let mut remaining = None;
+    // The snapshot so far, until it is restored.
+    let mut snapshot = Some(Vec::new());
+    while let Some(chunk) = body.data().await {
+        buf.extend_from_slice(&chunk?);
+        if remaining.is_none() {
+            if buf.len() < COUNT_LEN {
+                continue;
+            }
+            let count: Vec<u8> = buf.drain(..COUNT_LEN).collect();
+            remaining = Some(u64::from_le_bytes(count.try_into().unwrap()) as usize);
+        }
+        let (records, used) = wal::replay(&buf);
+        buf.drain(..used);
+        let mut records = records.into_iter();
+
+        let Some(state) = state.upgrade() else {
+            return Ok(());
+        };
+        let mut db = state.write().await;
+        if let (Some(left), Some(snapshot_records)) = (&mut remaining, &mut snapshot) {
+            let taken = (*left).min(records.len());
+            snapshot_records.extend(records.by_ref().take(taken));
+            *left -= taken;
+            if *left == 0 {
+                db.restore(snapshot.take().unwrap())?;
+                event!(Level::INFO, "restored the snapshot of the leader");
+            }
+        }
+        for record in records {
+            db.replay(record)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,724 @@
+use std::{io, net, sync::Arc, time::Duration};
+
+use axum::{
+    body::Bytes,
+    http::{StatusCode, Uri},
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+    task::JoinHandle,
+};
+use tracing::{event, Level};
+
+use crate::{
+    cluster::WriteError, etag::Preconditions, expiry::set_record, namespace, storage_error,
+    wal::Record, Entry, Permission, Principal, RouterOptions, SharedState,
+};
+
+/// Longest line of a command, outside of its bulk strings.
+const MAX_LINE: usize = 64 * 1024;
+/// Most arguments a command can have.
+const MAX_ARGS: i64 = 4 * 1024;
+/// Most clients connected at once, those beyond are turned away.
+const MAX_CLIENTS: usize = 10_000;
+/// How long a client may go without sending a command.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Serves the default namespace of the store to Redis clients on
+/// `listener`, speaking RESP2. `GET`, `SET`, `DEL`, `EXISTS`, `KEYS`,
+/// `EXPIRE` and `FLUSHALL` work on the same entries as the HTTP routes.
+///
+/// The router options apply as they do to HTTP: clients send their API
+/// key with `AUTH`, which is needed for everything once there is an ACL.
+/// `DEL` and `FLUSHALL` need an admin key like the `/admin` routes do.
+/// Followers turn down writes, and those of a cluster go through its log.
+///
+/// Clients that stay idle for five minutes are disconnected, as are those
+/// that take longer than the timeout of the options to send a command once
+/// they started it. A command, all of its arguments together, may be as
+/// large as the body limit, plus a line's worth for the key.
+///
+/// Runs until the task is aborted.
+pub fn spawn_resp(
+    listener: net::TcpListener,
+    state: &SharedState,
+    options: RouterOptions,
+) -> io::Result<JoinHandle<()>> {
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let state = Arc::clone(state);
+    let options = Arc::new(options);
+    let clients = Arc::new(Semaphore::new(MAX_CLIENTS));
+    Ok(tokio::spawn(async move {
+        loop {
+            let (socket, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    event!(Level::WARN, "accepting a Redis client failed: {err}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let Ok(client) = Arc::clone(&clients).try_acquire_owned() else {
+                event!(Level::WARN, %addr, "turned a Redis client away, too many are connected");
+                let _ = socket.try_write(b"-ERR max number of clients reached\r\n");
+                continue;
+            };
+            let session = Session {
+                state: Arc::clone(&state),
+                options: Arc::clone(&options),
+                principal: None,
+            };
+            tokio::spawn(async move {
+                if let Err(err) = session.run(socket).await {
+                    event!(Level::DEBUG, %addr, "Redis connection failed: {err}");
+                }
+                drop(client);
+            });
+        }
+    }))
+}
+
+/// A reply to a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reply {
+    Simple(&'static str),
+    /// Starts with the kind of error, like `ERR`.
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Nil,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn error(message: impl std::fmt::Display) -> Self {
+        Self::Error(format!("ERR {message}"))
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Simple(message) => out.extend_from_slice(format!("+{message}\r\n").as_bytes()),
+            Self::Error(message) => {
+                // A line break would end the error early.
+                let message = message.replace(['\r', '\n'], " ");
+                out.extend_from_slice(format!("-{message}\r\n").as_bytes());
+            }
+            Self::Integer(n) => out.extend_from_slice(format!(":{n}\r\n").as_bytes()),
+            Self::Bulk(value) => {
+                out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                out.extend_from_slice(value);
+                out.extend_from_slice(b"\r\n");
+            }
+            Self::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Self::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+/// A connection of a client.
+struct Session {
+    state: SharedState,
+    options: Arc<RouterOptions>,
+    /// Who the client authenticated as with `AUTH`.
+    principal: Option<Principal>,
+}
+
+impl Session {
+    async fn run(mut self, socket: TcpStream) -> io::Result<()> {
+        let (reader, writer) = socket.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        let mut out = Vec::new();
+        let max_size = self.options.body_limit.saturating_add(MAX_LINE);
+        loop {
+            out.clear();
+            let Ok(waited) = tokio::time::timeout(IDLE_TIMEOUT, reader.fill_buf()).await else {
+                event!(Level::DEBUG, "closing an idle Redis connection");
+                break;
+            };
+            if waited?.is_empty() {
+                break;
+            }
+            let read = read_command(&mut reader, max_size);
+            let Ok(read) = tokio::time::timeout(self.options.timeout, read).await else {
+                event!(
+                    Level::DEBUG,
+                    "a Redis client took too long to send a command"
+                );
+                break;
+            };
+            let args = match read {
+                Ok(Some(args)) => args,
+                Ok(None) => break,
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    Reply::error(format!("Protocol error: {err}")).encode(&mut out);
+                    writer.write_all(&out).await?;
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+            let Some(name) = args.first() else {
+                continue;
+            };
+            let quit = name.eq_ignore_ascii_case(b"quit");
+            let reply = if quit {
+                Reply::Simple("OK")
+            } else {
+                self.execute(&args).await.unwrap_or_else(|err| err)
+            };
+            reply.encode(&mut out);
+            writer.write_all(&out).await?;
+            if quit {
+                break;
+            }
+            // Replies to pipelined commands go out together.
+            if reader.buffer().is_empty() {
+                writer.flush().await?;
+            }
+        }
+        writer.flush().await
+    }
+
+    async fn execute(&mut self, args: &[Bytes]) -> Result<Reply, Reply> {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let args = &args[1..];
+        match (name.as_str(), args.len()) {
+            ("PING", 0) => Ok(Reply::Simple("PONG")),
+            ("PING", 1) => Ok(Reply::Bulk(args[0].clone())),
+            // `AUTH password` or `AUTH username password`.
+            ("AUTH", 1 | 2) => self.auth(&args[args.len() - 1]),
+            ("GET", 1) => self.get(&args[0]).await,
+            ("SET", 2..) => self.set(args).await,
+            ("DEL", 1..) => self.del(args).await,
+            ("EXISTS", 1..) => self.exists(args).await,
+            ("KEYS", 1) => self.keys(&args[0]).await,
+            ("EXPIRE", 2) => self.expire(&args[0], &args[1]).await,
+            ("FLUSHALL", 0 | 1) => self.flush_all(args).await,
+            (
+                "PING" | "AUTH" | "GET" | "SET" | "DEL" | "EXISTS" | "KEYS" | "EXPIRE" | "FLUSHALL",
+                _,
+            ) => Err(Reply::error(format!(
+                "wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            ))),
+            _ => Err(Reply::error(format!("unknown command '{name}'"))),
+        }
+    }
+
+    fn auth(&mut self, key: &[u8]) -> Result<Reply, Reply> {
+        if self.options.api_keys.is_empty() {
+            return Err(Reply::error(
+                "AUTH <password> called without any password configured",
+            ));
+        }
+        let principal = std::str::from_utf8(key)
+            .ok()
+            .and_then(|key| self.options.api_keys.principal(key));
+        match principal {
+            Some(principal) => {
+                self.principal = Some(principal);
+                Ok(Reply::Simple("OK"))
+            }
+            None => Err(Reply::Error(
+                "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+            )),
+        }
+    }
+
+    async fn get(&self, key: &[u8]) -> Result<Reply, Reply> {
+        let key = self.key(key, Permission::Read)?;
+        Ok(match self.state.read().await.get(&key) {
+            Some(value) => Reply::Bulk(value),
+            None => Reply::Nil,
+        })
+    }
+
+    /// `SET key value [EX seconds | PX milliseconds] [NX | XX]`
+    async fn set(&self, args: &[Bytes]) -> Result<Reply, Reply> {
+        let key = self.key(&args[0], Permission::Write)?;
+        if args[1].len() > self.options.body_limit {
+            return Err(Reply::error("value is larger than the body limit"));
+        }
+        let mut ttl = None;
+        let mut preconditions = Preconditions::default();
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            let option = String::from_utf8_lossy(option).to_ascii_uppercase();
+            match option.as_str() {
+                "EX" | "PX" if ttl.is_none() => {
+                    let amount = options.next().ok_or_else(|| Reply::error("syntax error"))?;
+                    let amount = positive(amount)
+                        .ok_or_else(|| Reply::error("invalid expire time in 'set' command"))?;
+                    ttl = Some(if option == "EX" {
+                        Duration::from_secs(amount)
+                    } else {
+                        Duration::from_millis(amount)
+                    });
+                }
+                "NX" if preconditions == Preconditions::default() => {
+                    preconditions.if_none_match = Some("*".to_string());
+                }
+                "XX" if preconditions == Preconditions::default() => {
+                    preconditions.if_match = Some("*".to_string());
+                }
+                _ => return Err(Reply::error("syntax error")),
+            }
+        }
+
+        let record = set_record(key, args[1].clone(), ttl)
+            .map_err(|_| Reply::error("invalid expire time in 'set' command"))?;
+        Ok(if self.write(record, preconditions).await? {
+            Reply::Simple("OK")
+        } else {
+            Reply::Nil
+        })
+    }
+
+    async fn del(&self, args: &[Bytes]) -> Result<Reply, Reply> {
+        self.check_admin()?;
+        let mut deleted = 0;
+        for key in args {
+            let key = self.key(key, Permission::Delete)?;
+            let only_if_there = Preconditions {
+                if_match: Some("*".to_string()),
+                if_none_match: None,
+            };
+            if self.write(Record::Delete { key }, only_if_there).await? {
+                deleted += 1;
+            }
+        }
+        Ok(Reply::Integer(deleted))
+    }
+
+    /// Counts a key as often as it is named, like Redis does.
+    async fn exists(&self, args: &[Bytes]) -> Result<Reply, Reply> {
+        let keys = args
+            .iter()
+            .map(|key| self.key(key, Permission::Read))
+            .collect::<Result<Vec<_>, _>>()?;
+        let db = self.state.read().await;
+        let found = keys
+            .iter()
+            .filter(|key| db.value_len(key).is_some())
+            .count();
+        Ok(Reply::Integer(found as i64))
+    }
+
+    /// Keys the client may not read are left out.
+    async fn keys(&self, pattern: &[u8]) -> Result<Reply, Reply> {
+        self.check_auth()?;
+        let keys = self.state.read().await.keys();
+        Ok(Reply::Array(
+            keys.into_iter()
+                .filter(|key| glob(pattern, key.as_bytes()))
+                .filter(|key| self.allows(namespace::DEFAULT, key, Permission::Read))
+                .map(|key| Reply::Bulk(key.into()))
+                .collect(),
+        ))
+    }
+
+    /// Replaces the value with itself and a new TTL, unless it changes in
+    /// between.
+    async fn expire(&self, key: &[u8], seconds: &[u8]) -> Result<Reply, Reply> {
+        let key = self.key(key, Permission::Write)?;
+        let seconds = positive(seconds)
+            .ok_or_else(|| Reply::error("invalid expire time in 'expire' command"))?;
+        loop {
+            let Some(Entry { value, etag, .. }) = self.state.read().await.entry(&key) else {
+                return Ok(Reply::Integer(0));
+            };
+            let record = set_record(key.clone(), value, Some(Duration::from_secs(seconds)))
+                .map_err(|_| Reply::error("invalid expire time in 'expire' command"))?;
+            let unchanged = Preconditions {
+                if_match: Some(etag),
+                if_none_match: None,
+            };
+            if self.write(record, unchanged).await? {
+                return Ok(Reply::Integer(1));
+            }
+        }
+    }
+
+    /// `FLUSHALL [ASYNC | SYNC]`, both are synchronous.
+    async fn flush_all(&self, args: &[Bytes]) -> Result<Reply, Reply> {
+        if args.first().is_some_and(|mode| {
+            !mode.eq_ignore_ascii_case(b"async") && !mode.eq_ignore_ascii_case(b"sync")
+        }) {
+            return Err(Reply::error("syntax error"));
+        }
+        self.check_admin()?;
+        if !self.allows("*", "", Permission::Delete) {
+            return Err(no_permission());
+        }
+        self.check_writable()?;
+        if self.options.cluster.is_some() {
+            return Err(Reply::error("FLUSHALL is not supported by a cluster"));
+        }
+        let principal = self.principal.as_ref().map(|principal| &principal.name);
+        event!(Level::INFO, principal, "deleting all keys");
+        self.state
+            .write()
+            .await
+            .clear()
+            .map_err(|err| write_error(storage_error(err)))?;
+        Ok(Reply::Simple("OK"))
+    }
+
+    /// The key as it is stored, failing unless the client may use it with
+    /// `permission`.
+    fn key(&self, key: &[u8], permission: Permission) -> Result<String, Reply> {
+        let key = std::str::from_utf8(key).map_err(|_| Reply::error("keys must be UTF-8"))?;
+        let stored = namespace::key(namespace::DEFAULT, key)
+            .ok_or_else(|| Reply::error("keys may not start with a NUL byte"))?;
+        self.check_auth()?;
+        if !self.allows(namespace::DEFAULT, key, permission) {
+            return Err(no_permission());
+        }
+        Ok(stored)
+    }
+
+    /// Fails unless the client authenticated, if there is an ACL.
+    fn check_auth(&self) -> Result<(), Reply> {
+        if self.options.acl.is_some() && self.principal.is_none() {
+            return Err(no_auth());
+        }
+        Ok(())
+    }
+
+    /// Fails unless the client authenticated with an admin key.
+    fn check_admin(&self) -> Result<(), Reply> {
+        match &self.principal {
+            Some(principal) if principal.admin => Ok(()),
+            Some(_) => Err(no_permission()),
+            None => Err(no_auth()),
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), Reply> {
+        if self.options.read_only {
+            return Err(Reply::Error(
+                "READONLY You can't write against a read only replica.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the ACL lets the client use `key`. Without one, anyone may.
+    fn allows(&self, namespace: &str, key: &str, permission: Permission) -> bool {
+        let Some(policy) = &self.options.acl else {
+            return true;
+        };
+        self.principal
+            .as_ref()
+            .is_some_and(|principal| policy.allows(principal, namespace, key, permission))
+    }
+
+    /// Writes a single key if `preconditions` hold, through the log of the
+    /// cluster if there is one. Returns whether they held.
+    async fn write(&self, record: Record, preconditions: Preconditions) -> Result<bool, Reply> {
+        self.check_writable()?;
+        let result = match &self.options.cluster {
+            Some(cluster) => match cluster
+                .write(record, preconditions, &Uri::from_static("/"))
+                .await
+            {
+                Ok(()) => Ok(()),
+                Err(WriteError::Status(status)) => Err(status),
+                Err(WriteError::NotLeader(leader)) => {
+                    return Err(Reply::Error(format!(
+                        "READONLY this node isn't the leader, writes go to {leader}"
+                    )))
+                }
+            },
+            None => self
+                .state
+                .read()
+                .await
+                .commit_if(record, |current| preconditions.check(current))
+                .map_err(storage_error)
+                .and_then(|checked| checked),
+        };
+        match result {
+            Ok(()) => Ok(true),
+            Err(StatusCode::PRECONDITION_FAILED) => Ok(false),
+            Err(status) => Err(write_error(status)),
+        }
+    }
+}
+
+fn no_auth() -> Reply {
+    Reply::Error("NOAUTH Authentication required.".to_string())
+}
+
+fn no_permission() -> Reply {
+    Reply::Error(
+        "NOPERM this user has no permissions to access one of the keys used as arguments"
+            .to_string(),
+    )
+}
+
+fn write_error(status: StatusCode) -> Reply {
+    match status {
+        StatusCode::INSUFFICIENT_STORAGE => {
+            Reply::Error("OOM command not allowed, the store is full".to_string())
+        }
+        status => Reply::error(format!("the write failed with {status}")),
+    }
+}
+
+/// An argument that is a whole number greater than zero.
+fn positive(arg: &[u8]) -> Option<u64> {
+    std::str::from_utf8(arg)
+        .ok()?
+        .parse()
+        .ok()
+        .filter(|&n| n > 0)
+}
+
+/// Reads the arguments of the next command, `None` once the client hung
+/// up. Commands are arrays of bulk strings, or inline: a line of arguments
+/// separated by spaces, as typed into telnet.
+///
+/// Protocol errors fail with `InvalidData`, as do commands whose bulk
+/// strings take more than `max_size` bytes together.
+async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> io::Result<Option<Vec<Bytes>>> {
+    let Some(line) = read_line(reader).await? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let args = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|arg| !arg.is_empty())
+            .map(Bytes::copy_from_slice)
+            .collect();
+        return Ok(Some(args));
+    };
+    let count = integer(count)
+        .filter(|count| *count <= MAX_ARGS)
+        .ok_or_else(|| invalid("invalid multibulk length"))?;
+
+    let mut args = Vec::new();
+    let mut left = max_size;
+    for _ in 0..count {
+        let line = read_line(reader)
+            .await?
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        let len = line
+            .strip_prefix(b"$")
+            .ok_or_else(|| invalid("expected '$'"))?;
+        let len = integer(len)
+            .and_then(|len| usize::try_from(len).ok())
+            .ok_or_else(|| invalid("invalid bulk length"))?;
+        left = left
+            .checked_sub(len)
+            .ok_or_else(|| invalid("command too big"))?;
+        // Grows with what arrives rather than with what was announced.
+        let mut arg = Vec::with_capacity((len + 2).min(MAX_LINE));
+        reader.take(len as u64 + 2).read_to_end(&mut arg).await?;
+        if arg.len() < len + 2 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("expected CRLF after a bulk string"));
+        }
+        arg.truncate(len);
+        args.push(Bytes::from(arg));
+    }
+    Ok(Some(args))
+}
+
+/// The next line without its line break, `None` at the end of the stream.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let mut limited = reader.take(MAX_LINE as u64 + 2);
+    limited.read_until(b'\n', &mut line).await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        if line.len() > MAX_LINE {
+            return Err(invalid("too big request line"));
+        }
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn integer(digits: &[u8]) -> Option<i64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Whether `text` matches the glob-style `pattern` of `KEYS`: `*` and `?`
+/// stand for any bytes and any single byte, `[abc]`, `[^abc]` and `[a-z]`
+/// for one of a set, and `\` escapes the next byte.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The pattern after the last `*`, and how much of the text it took.
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+        } else if let Some(len) = match_one(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+        } else if let Some((after, taken)) = star {
+            // Let the `*` take one more byte and try again.
+            p = after;
+            t = taken + 1;
+            star = Some((after, taken + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// If the start of `pattern` stands for a single byte that matches `byte`,
+/// how long that part of the pattern is.
+fn match_one(pattern: &[u8], byte: u8) -> Option<usize> {
+    match *pattern.first()? {
+        b'?' => Some(1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == byte).then_some(2),
+        b'[' => {
+            let negated = pattern.get(1) == Some(&b'^');
+            let mut i = if negated { 2 } else { 1 };
+            let mut matched = false;
+            // A set that isn't closed matches nothing.
+            loop {
+                match *pattern.get(i)? {
+                    b']' => break,
+                    b'\\' => {
+                        matched |= *pattern.get(i + 1)? == byte;
+                        i += 2;
+                    }
+                    low if pattern.get(i + 1) == Some(&b'-')
+                        && pattern.get(i + 2).is_some_and(|&high| high != b']') =>
+                    {
+                        let high = pattern[i + 2];
+                        matched |= (low.min(high)..=low.max(high)).contains(&byte);
+                        i += 3;
+                    }
+                    other => {
+                        matched |= other == byte;
+                        i += 1;
+                    }
+                }
+            }
+            (matched != negated).then_some(i + 1)
+        }
+        other => (other == byte).then_some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+
+    use super::{glob, read_command, Reply};
+
+    async fn read(mut input: &[u8]) -> std::io::Result<Option<Vec<Bytes>>> {
+        read_command(&mut input, 16).await
+    }
+
+    #[tokio::test]
+    async fn commands_are_read() {
+        let command = read(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$4\r\n1\r\n2\r\n").await;
+        assert_eq!(
+            command.unwrap().unwrap(),
+            vec![Bytes::from("SET"), Bytes::from("a"), Bytes::from("1\r\n2")]
+        );
+        let command = read(b"GET  some-key\r\n").await;
+        assert_eq!(
+            command.unwrap().unwrap(),
+            vec![Bytes::from("GET"), Bytes::from("some-key")]
+        );
+        assert_eq!(read(b"\n").await.unwrap().unwrap(), Vec::<Bytes>::new());
+        assert!(read(b"").await.unwrap().is_none());
+
+        for invalid in [
+            &b"*x\r\n"[..],
+            b"*1\r\n:1\r\n",
+            b"*1\r\n$17\r\n",
+            b"*1\r\n$-1\r\n",
+            b"*1\r\n$1\r\nab\r\n",
+            b"*5000\r\n",
+            b"*2\r\n$9\r\n123456789\r\n$9\r\n123456789\r\n",
+        ] {
+            let err = read(invalid).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+        let err = read(b"*2\r\n$1\r\na\r\n").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn replies_are_encoded() {
+        let mut out = Vec::new();
+        Reply::Array(vec![
+            Reply::Simple("OK"),
+            Reply::Error("ERR bad\r\nthing".to_string()),
+            Reply::Integer(-2),
+            Reply::Bulk(Bytes::from("a\r\nb")),
+            Reply::Nil,
+        ])
+        .encode(&mut out);
+        assert_eq!(
+            out,
+            b"*5\r\n+OK\r\n-ERR bad  thing\r\n:-2\r\n$4\r\na\r\nb\r\n$-1\r\n"
+        );
+    }
+
+    #[test]
+    fn patterns_match_like_redis() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("*", "anything", true),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "heeeello", true),
+            ("h*llo", "hello world", false),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-b]llo", "hbllo", true),
+            ("h[a-b]llo", "hcllo", false),
+            ("h[a", "ha", false),
+            ("user:\\*", "user:*", true),
+            ("user:\\*", "user:1", false),
+            (
+                "*a*a*b",
+                "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaac",
+                false,
+            ),
+            ("*/*", "a/b/c", true),
+        ];
+        for (pattern, text, matches) in cases {
+            assert_eq!(
+                glob(pattern.as_bytes(), text.as_bytes()),
+                *matches,
+                "{pattern} {text}"
+            );
+        }
+    }
+}
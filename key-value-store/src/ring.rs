@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Backends placed on a hash ring. Each key belongs to the backend of the
+/// first point at or after its hash, wrapping around at the end.
+///
+/// Every backend gets `vnodes` points, which spreads the keys evenly and
+/// means adding or removing one only moves the keys of its own points.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ring {
+    vnodes: usize,
+    points: BTreeMap<u64, String>,
+    /// URL of each backend, by name.
+    backends: BTreeMap<String, String>,
+}
+
+impl Ring {
+    /// An empty ring, backends get `vnodes` points each. Panics if that is
+    /// zero.
+    pub fn new(vnodes: usize) -> Self {
+        assert!(vnodes > 0, "backends need at least one point on the ring");
+        Self {
+            vnodes,
+            points: BTreeMap::new(),
+            backends: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the backend `name` at `url`, or moves it there. Where it sits on
+    /// the ring only depends on its name. Returns its previous URL.
+    pub fn add(&mut self, name: &str, url: &str) -> Option<String> {
+        for point in points(name, self.vnodes) {
+            self.points.insert(point, name.to_string());
+        }
+        self.backends.insert(name.to_string(), url.to_string())
+    }
+
+    /// Takes the backend `name` off the ring, returning its URL.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let url = self.backends.remove(name)?;
+        for point in points(name, self.vnodes) {
+            // Another backend may have landed on the same point.
+            if self.points.get(&point).map(String::as_str) == Some(name) {
+                self.points.remove(&point);
+            }
+        }
+        Some(url)
+    }
+
+    /// Name and URL of the backend `key` belongs to, `None` if there are
+    /// no backends.
+    pub fn backend(&self, key: &str) -> Option<(&str, &str)> {
+        let hash = xxh3_64(key.as_bytes());
+        let (_, name) = self
+            .points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())?;
+        Some((name, &self.backends[name]))
+    }
+
+    /// URL of each backend, by name.
+    pub fn backends(&self) -> &BTreeMap<String, String> {
+        &self.backends
+    }
+}
+
+fn points(name: &str, vnodes: usize) -> impl Iterator<Item = u64> + '_ {
+    (0..vnodes).map(move |i| xxh3_64(format!("{name}#{i}").as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Ring;
+
+    fn ring(names: &[&str]) -> Ring {
+        let mut ring = Ring::new(100);
+        for name in names {
+            ring.add(name, &format!("http://{name}"));
+        }
+        ring
+    }
+
+    fn owners(ring: &Ring) -> Vec<String> {
+        (0..10_000)
+            .map(|i| ring.backend(&format!("key{i}")).unwrap().0.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn keys_are_spread_evenly() {
+        assert_eq!(Ring::new(10).backend("a"), None);
+
+        let ring = ring(&["a", "b", "c", "d"]);
+        assert_eq!(ring.backend("x"), ring.backend("x"));
+        let mut counts = HashMap::new();
+        for owner in owners(&ring) {
+            *counts.entry(owner).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 4);
+        // A quarter each, give or take.
+        assert!(counts.values().all(|&count| (1500..3500).contains(&count)));
+    }
+
+    #[test]
+    fn only_the_keys_of_a_changed_backend_move() {
+        let before = ring(&["a", "b", "c"]);
+        let mut after = before.clone();
+        after.add("d", "http://d");
+        let moved: Vec<_> = owners(&before)
+            .into_iter()
+            .zip(owners(&after))
+            .filter(|(before, after)| before != after)
+            .collect();
+        assert!(moved.iter().all(|(_, to)| to == "d"));
+        assert!((1500..3500).contains(&moved.len()));
+
+        assert_eq!(after.remove("d").as_deref(), Some("http://d"));
+        assert_eq!(after.remove("d"), None);
+        assert_eq!(after, before);
+    }
+}
@@ -0,0 +1,54 @@
+use std::{future::Future, io, net::SocketAddr, net::TcpListener, time::Duration};
+
+use axum::{Router, Server};
+use tokio::sync::oneshot;
+use tracing::{event, Level};
+
+use crate::SharedState;
+
+/// Serves `app` on `listener` until `signal` resolves, then shuts down
+/// gracefully: no new connections are accepted, requests in flight get up
+/// to `drain` to finish, and the log is flushed once they did or the time
+/// is up.
+///
+/// Connections that are still open after `drain`, like those of watchers,
+/// are dropped.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    state: &SharedState,
+    signal: impl Future<Output = ()>,
+    drain: Duration,
+) -> io::Result<()> {
+    let (draining, drain_started) = oneshot::channel();
+    let server = Server::from_tcp(listener)
+        .map_err(to_io_error)?
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            signal.await;
+            event!(Level::INFO, "shutting down, waiting for requests in flight");
+            let _ = draining.send(());
+        });
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => result.map_err(to_io_error)?,
+        Ok(()) = drain_started => {
+            match tokio::time::timeout(drain, &mut server).await {
+                Ok(result) => result.map_err(to_io_error)?,
+                Err(_) => event!(
+                    Level::WARN,
+                    "requests still in flight after {drain:?}, dropping them"
+                ),
+            }
+        }
+    }
+
+    state.read().await.sync()?;
+    event!(Level::INFO, "shut down");
+    Ok(())
+}
+
+fn to_io_error(err: hyper::Error) -> io::Error {
+    io::Error::other(err)
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    iter,
+    sync::{atomic::AtomicU64, Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
+};
+
+use axum::body::Bytes;
+use tokio::{sync::broadcast, time::Instant};
+
+use crate::{etag, eviction::Usage, replication, watch, AppState, Limits, Storage};
+
+/// Shards of a store made with `AppState::default`.
+pub(crate) const DEFAULT_SHARDS: usize = 16;
+
+/// Part of the keys of the store, together with everything that is tracked
+/// per key. Each shard has a lock of its own, so that writes of keys in
+/// different shards don't wait for each other.
+#[derive(Debug)]
+pub(crate) struct Shard {
+    pub(crate) db: Box<dyn Storage>,
+    pub(crate) expiries: HashMap<String, Instant>,
+    pub(crate) usage: Usage,
+}
+
+/// A value together with its `ETag` and the time it has left, read at the
+/// same time so that they belong together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub value: Bytes,
+    pub etag: String,
+    /// `None` if the entry never expires.
+    pub ttl: Option<Duration>,
+}
+
+impl Shard {
+    /// The `ETag` of the value stored under `key`, a hash of its content.
+    pub(crate) fn etag(&self, key: &str) -> Option<String> {
+        if self.is_expired(key) {
+            return None;
+        }
+        self.usage.etag(key).map(etag::format)
+    }
+
+    /// The entry stored under `key`, counting as a read of it.
+    pub(crate) fn entry(&self, key: &str) -> Option<Entry> {
+        if self.is_expired(key) {
+            return None;
+        }
+        let value = self.db.get(key)?;
+        let etag = self.usage.etag(key).map(etag::format)?;
+        let ttl = self
+            .expiries
+            .get(key)
+            .map(|at| at.saturating_duration_since(Instant::now()));
+        self.usage.touch(key);
+        Some(Entry { value, etag, ttl })
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.expiries.clear();
+        self.usage.clear();
+        self.db.clear();
+    }
+}
+
+impl AppState {
+    /// A store with one shard for each of `storages`. They share a clock,
+    /// so that entries of different shards can be compared for eviction.
+    pub(crate) fn from_storages(storages: impl IntoIterator<Item = Box<dyn Storage>>) -> Self {
+        let clock = Arc::new(AtomicU64::new(0));
+        let shards: Box<[RwLock<Shard>]> = storages
+            .into_iter()
+            .map(|db| {
+                RwLock::new(Shard {
+                    db,
+                    expiries: HashMap::new(),
+                    usage: Usage::new(Arc::clone(&clock)),
+                })
+            })
+            .collect();
+        assert!(!shards.is_empty(), "a store needs at least one shard");
+        Self {
+            shards,
+            limits: Limits::default(),
+            wal: Mutex::new(None),
+            events: broadcast::channel(watch::CAPACITY).0,
+            replication: broadcast::channel(replication::CAPACITY).0,
+            namespaces: HashMap::new(),
+            evicting: Mutex::new(()),
+            compacting: false,
+        }
+    }
+
+    /// A store that spreads its keys over `shards` storages made by
+    /// `storage`. Panics if there are none.
+    pub fn sharded<S: Storage + 'static>(shards: usize, storage: impl FnMut() -> S) -> Self {
+        Self::from_storages(
+            iter::repeat_with(storage)
+                .take(shards)
+                .map(|storage| Box::new(storage) as Box<dyn Storage>),
+        )
+    }
+
+    pub(crate) fn shard_of(&self, key: &str) -> &RwLock<Shard> {
+        let hash = xxhash_rust::xxh3::xxh3_64(key.as_bytes());
+        &self.shards[(hash % self.shards.len() as u64) as usize]
+    }
+
+    pub(crate) fn read_shard(&self, key: &str) -> RwLockReadGuard<'_, Shard> {
+        self.shard_of(key).read().unwrap()
+    }
+
+    pub(crate) fn write_shard(&self, key: &str) -> RwLockWriteGuard<'_, Shard> {
+        self.shard_of(key).write().unwrap()
+    }
+
+    /// Every shard, each locked only while it is looked at.
+    pub(crate) fn read_shards(&self) -> impl Iterator<Item = RwLockReadGuard<'_, Shard>> {
+        self.shards.iter().map(|shard| shard.read().unwrap())
+    }
+
+    /// Every shard, each locked only while it is changed.
+    pub(crate) fn write_shards(&self) -> impl Iterator<Item = RwLockWriteGuard<'_, Shard>> {
+        self.shards.iter().map(|shard| shard.write().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use axum::body::Bytes;
+
+    use crate::{AppState, Limits, MemoryStorage};
+
+    fn key(i: usize) -> String {
+        format!("key/{i:03}")
+    }
+
+    #[test]
+    fn shards_are_merged_in_order() {
+        let db = AppState::sharded(4, MemoryStorage::default);
+        for i in (0..100).rev() {
+            db.set(key(i), Bytes::from(i.to_string())).unwrap();
+        }
+        assert!(db.read_shards().all(|shard| !shard.db.is_empty()));
+        assert_eq!(db.len(), 100);
+
+        let all: Vec<String> = (0..100).map(key).collect();
+        assert_eq!(db.keys(), all);
+
+        let mut scanned = Vec::new();
+        let mut after = None;
+        loop {
+            let page = db.scan("key/", after.as_deref(), 7);
+            scanned.extend(page.iter().cloned());
+            if page.len() < 7 {
+                break;
+            }
+            after = page.last().cloned();
+        }
+        assert_eq!(scanned, all);
+
+        let range = db.range(Bound::Included("key/010"), Bound::Excluded("key/020"), 5);
+        let keys: Vec<&str> = range.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(
+            keys,
+            ["key/010", "key/011", "key/012", "key/013", "key/014"]
+        );
+        assert_eq!(&range[0].1[..], b"10");
+    }
+
+    #[test]
+    fn eviction_spans_shards() {
+        let db = AppState::sharded(4, MemoryStorage::default).with_limits(Limits {
+            max_keys: Some(10),
+            ..Default::default()
+        });
+        for i in 0..10 {
+            db.set(key(i), Bytes::from_static(b"1")).unwrap();
+        }
+        for i in 1..10 {
+            db.get(&key(i));
+        }
+        db.set(key(10), Bytes::from_static(b"1")).unwrap();
+
+        assert_eq!(db.len(), 10);
+        assert!(db.get(&key(0)).is_none());
+        assert_eq!(db.stats().evictions, 1);
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    ops::{Bound, RangeBounds},
+};
+
+use axum::body::Bytes;
+
+/// A backend that holds the actual keys and values of the store.
+///
+/// `AppState` owns one storage per shard and wraps them with everything
+/// that is independent of where the data lives, like the write-ahead log.
+pub trait Storage: Debug + Send + Sync {
+    fn get(&self, key: &str) -> Option<Bytes>;
+
+    fn set(&mut self, key: String, value: Bytes);
+
+    fn delete(&mut self, key: &str) -> Option<Bytes>;
+
+    fn clear(&mut self);
+
+    /// All keys currently stored, in no particular order.
+    fn list(&self) -> Vec<String>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Up to `limit` keys starting with `prefix` in lexicographic order,
+    /// beginning after the key `after`.
+    ///
+    /// The default sorts all keys on every call, storages that keep their
+    /// keys ordered should override it.
+    fn scan(&self, prefix: &str, after: Option<&str>, limit: usize) -> Vec<String> {
+        let mut keys = self.list();
+        keys.retain(|key| {
+            key.starts_with(prefix) && after.is_none_or(|after| key.as_str() > after)
+        });
+        keys.sort_unstable();
+        keys.truncate(limit);
+        keys
+    }
+
+    /// Up to `limit` entries with keys between `start` and `end` in
+    /// lexicographic order.
+    ///
+    /// Like `scan`, the default sorts all keys on every call.
+    fn range(&self, start: Bound<&str>, end: Bound<&str>, limit: usize) -> Vec<(String, Bytes)> {
+        let mut keys = self.list();
+        keys.retain(|key| RangeBounds::<str>::contains(&(start, end), key.as_str()));
+        keys.sort_unstable();
+        keys.into_iter()
+            .filter_map(|key| {
+                let value = self.get(&key)?;
+                Some((key, value))
+            })
+            .take(limit)
+            .collect()
+    }
+}
+
+/// The default storage, keeping everything ordered in a `BTreeMap`.
+#[derive(Default, Debug)]
+pub struct MemoryStorage(BTreeMap<String, Bytes>);
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> Option<Bytes> {
+        self.0.get(key).cloned()
+    }
+
+    fn set(&mut self, key: String, value: Bytes) {
+        self.0.insert(key, value);
+    }
+
+    fn delete(&mut self, key: &str) -> Option<Bytes> {
+        self.0.remove(key)
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.0.keys().cloned().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn scan(&self, prefix: &str, after: Option<&str>, limit: usize) -> Vec<String> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        self.0
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    fn range(&self, start: Bound<&str>, end: Bound<&str>, limit: usize) -> Vec<(String, Bytes)> {
+        if is_empty_range(start, end) {
+            return Vec::new();
+        }
+        self.0
+            .range::<str, _>((start, end))
+            .take(limit)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+}
+
+/// `BTreeMap::range` panics on ranges that end before they start.
+fn is_empty_range(start: Bound<&str>, end: Bound<&str>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use axum::body::Bytes;
+
+    use super::{MemoryStorage, Storage};
+
+    /// Only implements the required methods, to test the default `scan` and `range`.
+    #[derive(Debug, Default)]
+    struct Unordered(MemoryStorage);
+
+    impl Storage for Unordered {
+        fn get(&self, key: &str) -> Option<Bytes> {
+            self.0.get(key)
+        }
+
+        fn set(&mut self, key: String, value: Bytes) {
+            self.0.set(key, value)
+        }
+
+        fn delete(&mut self, key: &str) -> Option<Bytes> {
+            self.0.delete(key)
+        }
+
+        fn clear(&mut self) {
+            self.0.clear()
+        }
+
+        fn list(&self) -> Vec<String> {
+            let mut keys = self.0.list();
+            keys.reverse();
+            keys
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    fn fill(storage: &mut impl Storage) {
+        for key in ["b", "ab", "aa", "a", "ac", "c"] {
+            storage.set(key.to_string(), Bytes::from(key));
+        }
+        storage.delete("ac");
+    }
+
+    fn check_scan(mut storage: impl Storage) {
+        fill(&mut storage);
+
+        assert_eq!(storage.scan("", None, 10), vec!["a", "aa", "ab", "b", "c"]);
+        assert_eq!(storage.scan("a", None, 10), vec!["a", "aa", "ab"]);
+        assert_eq!(storage.scan("a", None, 2), vec!["a", "aa"]);
+        assert_eq!(storage.scan("a", Some("aa"), 2), vec!["ab"]);
+        assert_eq!(storage.scan("b", Some("a"), 2), vec!["b"]);
+        assert!(storage.scan("d", None, 10).is_empty());
+    }
+
+    fn check_range(mut storage: impl Storage) {
+        fill(&mut storage);
+        let keys = |start, end, limit| -> Vec<String> {
+            storage
+                .range(start, end, limit)
+                .into_iter()
+                .map(|(key, value)| {
+                    assert_eq!(key.as_bytes(), &value[..]);
+                    key
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            keys(Bound::Unbounded, Bound::Unbounded, 10),
+            vec!["a", "aa", "ab", "b", "c"]
+        );
+        assert_eq!(
+            keys(Bound::Included("aa"), Bound::Excluded("b"), 10),
+            vec!["aa", "ab"]
+        );
+        assert_eq!(
+            keys(Bound::Excluded("aa"), Bound::Included("b"), 10),
+            vec!["ab", "b"]
+        );
+        assert_eq!(
+            keys(Bound::Included("a"), Bound::Unbounded, 2),
+            vec!["a", "aa"]
+        );
+        assert!(keys(Bound::Included("b"), Bound::Excluded("a"), 10).is_empty());
+        assert!(keys(Bound::Excluded("b"), Bound::Excluded("b"), 10).is_empty());
+    }
+
+    #[test]
+    fn memory_storage_scan() {
+        check_scan(MemoryStorage::default());
+    }
+
+    #[test]
+    fn default_scan() {
+        check_scan(Unordered::default());
+    }
+
+    #[test]
+    fn memory_storage_range() {
+        check_range(MemoryStorage::default());
+    }
+
+    #[test]
+    fn default_range() {
+        check_range(Unordered::default());
+    }
+}
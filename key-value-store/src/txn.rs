@@ -0,0 +1,260 @@
+use std::{io, time::Duration};
+
+use axum::body::Bytes;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::{expiry, namespace, wal::Record, AppState, Permission};
+
+/// One operation of a transaction.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Op {
+    /// Requires the key to have the given ETag, or to be absent if there is
+    /// none.
+    Check {
+        key: String,
+        etag: Option<String>,
+    },
+    Set {
+        key: String,
+        /// Base64 encoded.
+        #[serde(deserialize_with = "base64_bytes")]
+        value: Bytes,
+        /// Seconds until the value expires.
+        ttl: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+impl Op {
+    pub(crate) fn key(&self) -> &str {
+        match self {
+            Op::Check { key, .. } | Op::Set { key, .. } | Op::Delete { key } => key,
+        }
+    }
+
+    fn key_mut(&mut self) -> &mut String {
+        match self {
+            Op::Check { key, .. } | Op::Set { key, .. } | Op::Delete { key } => key,
+        }
+    }
+
+    /// What the sender needs to be allowed to do with the key.
+    pub(crate) fn permission(&self) -> Permission {
+        match self {
+            Op::Check { .. } => Permission::Read,
+            Op::Set { .. } => Permission::Write,
+            Op::Delete { .. } => Permission::Delete,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OpResult {
+    pub key: String,
+    /// Whether a check passed or a write was applied.
+    pub ok: bool,
+    /// The ETag of the key once the transaction is done.
+    pub etag: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Transaction {
+    pub committed: bool,
+    pub results: Vec<OpResult>,
+}
+
+fn base64_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    BASE64
+        .decode(value)
+        .map(Bytes::from)
+        .map_err(de::Error::custom)
+}
+
+/// ETags are compared with or without their surrounding quotes.
+fn same_etag(expected: Option<&str>, current: Option<&str>) -> bool {
+    expected.map(|etag| etag.trim_matches('"')) == current.map(|etag| etag.trim_matches('"'))
+}
+
+impl AppState {
+    /// Applies all writes in `ops` or none of them.
+    ///
+    /// Every check is evaluated against the store as it was before the
+    /// transaction, the writes are then applied in order and logged as a
+    /// single record, so a crash can't leave half a transaction behind.
+    pub fn transaction(&mut self, ops: Vec<Op>) -> io::Result<Transaction> {
+        self.transaction_in(namespace::DEFAULT, ops)
+    }
+
+    /// Like `transaction`, with the keys of `ops` taken from `namespace`.
+    pub fn transaction_in(&mut self, namespace: &str, mut ops: Vec<Op>) -> io::Result<Transaction> {
+        if !self.has_namespace(namespace) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("namespace {namespace} does not exist"),
+            ));
+        }
+        let keys: Vec<String> = ops.iter().map(|op| op.key().to_string()).collect();
+        for op in &mut ops {
+            let Some(stored) = namespace::key(namespace, op.key()) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} is not a valid key", op.key()),
+                ));
+            };
+            *op.key_mut() = stored;
+        }
+
+        let check_passed = |op: &Op| match op {
+            Op::Check { key, etag } => same_etag(etag.as_deref(), self.etag(key).as_deref()),
+            Op::Set { .. } | Op::Delete { .. } => true,
+        };
+
+        if !ops.iter().all(check_passed) {
+            let results = ops
+                .iter()
+                .zip(keys)
+                .map(|(op, key)| OpResult {
+                    key,
+                    ok: matches!(op, Op::Check { .. }) && check_passed(op),
+                    etag: self.etag(op.key()),
+                })
+                .collect();
+            return Ok(Transaction {
+                committed: false,
+                results,
+            });
+        }
+
+        let records = ops
+            .iter()
+            .filter_map(|op| match op {
+                Op::Check { .. } => None,
+                Op::Set { key, value, ttl } => Some(expiry::set_record(
+                    key.clone(),
+                    value.clone(),
+                    ttl.map(Duration::from_secs),
+                )),
+                Op::Delete { key } => Some(Ok(Record::Delete { key: key.clone() })),
+            })
+            .collect::<io::Result<Vec<Record>>>()?;
+        if !records.is_empty() {
+            self.commit(Record::Batch(records))?;
+        }
+
+        let results = ops
+            .iter()
+            .zip(keys)
+            .map(|(op, key)| OpResult {
+                key,
+                ok: true,
+                etag: self.etag(op.key()),
+            })
+            .collect();
+        Ok(Transaction {
+            committed: true,
+            results,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+
+    use super::Op;
+    use crate::{AppState, FsyncPolicy};
+
+    fn set(key: &str, value: &'static [u8]) -> Op {
+        Op::Set {
+            key: key.to_string(),
+            value: Bytes::from_static(value),
+            ttl: None,
+        }
+    }
+
+    #[test]
+    fn failed_check_applies_nothing() {
+        let mut db = AppState::default();
+        db.set("a".to_string(), Bytes::from_static(b"1")).unwrap();
+        let etag = db.etag("a");
+
+        let txn = db
+            .transaction(vec![
+                Op::Check {
+                    key: "a".to_string(),
+                    etag: etag.clone(),
+                },
+                set("b", b"2"),
+                Op::Delete {
+                    key: "a".to_string(),
+                },
+                Op::Check {
+                    key: "c".to_string(),
+                    etag: Some("\"0\"".to_string()),
+                },
+            ])
+            .unwrap();
+
+        assert!(!txn.committed);
+        let ok: Vec<bool> = txn.results.iter().map(|result| result.ok).collect();
+        assert_eq!(ok, vec![true, false, false, false]);
+        assert_eq!(db.keys(), vec!["a"]);
+        assert_eq!(db.etag("a"), etag);
+    }
+
+    #[test]
+    fn passed_checks_apply_everything() {
+        let mut db = AppState::default();
+        db.set("a".to_string(), Bytes::from_static(b"1")).unwrap();
+        let etag = db.etag("a").unwrap();
+
+        let txn = db
+            .transaction(vec![
+                Op::Check {
+                    key: "a".to_string(),
+                    etag: Some(etag.trim_matches('"').to_string()),
+                },
+                Op::Check {
+                    key: "b".to_string(),
+                    etag: None,
+                },
+                set("b", b"2"),
+                set("c", b"3"),
+                Op::Delete {
+                    key: "a".to_string(),
+                },
+            ])
+            .unwrap();
+
+        assert!(txn.committed);
+        assert!(txn.results.iter().all(|result| result.ok));
+        assert_eq!(txn.results[2].etag, db.etag("b"));
+        assert_eq!(txn.results[4].etag, None);
+        assert_eq!(db.keys(), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn transaction_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+
+        let mut db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        db.set("a".to_string(), Bytes::from_static(b"1")).unwrap();
+        db.transaction(vec![
+            set("b", b"2"),
+            Op::Delete {
+                key: "a".to_string(),
+            },
+        ])
+        .unwrap();
+        drop(db);
+
+        let db = AppState::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(db.keys(), vec!["b"]);
+    }
+}
@@ -0,0 +1,651 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use axum::body::Bytes;
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+use crate::Quota;
+
+const OP_SET: u8 = 1;
+const OP_DELETE: u8 = 2;
+const OP_CLEAR: u8 = 3;
+const OP_SET_EX: u8 = 4;
+const OP_BATCH: u8 = 5;
+const OP_NAMESPACE: u8 = 6;
+const OP_DROP_NAMESPACE: u8 = 7;
+
+/// Stands for "no limit" in the quota of a namespace record.
+const UNLIMITED: u64 = u64::MAX;
+
+/// Every frame starts with the payload length and its CRC32, both little endian.
+const HEADER_LEN: usize = 8;
+
+/// A single mutation of the store as it is written to disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    Set {
+        key: String,
+        value: Bytes,
+    },
+    /// A set that expires at the given unix time in milliseconds.
+    SetEx {
+        key: String,
+        value: Bytes,
+        expires_at: u64,
+    },
+    Delete {
+        key: String,
+    },
+    Clear,
+    /// Records that are applied all together or not at all.
+    Batch(Vec<Record>),
+    /// Creates a namespace or updates its quota.
+    Namespace {
+        name: String,
+        quota: Quota,
+    },
+    /// Deletes a namespace together with all of its keys.
+    DropNamespace {
+        name: String,
+    },
+}
+
+impl Record {
+    /// The key of a record that writes a single one.
+    pub(crate) fn key(&self) -> Option<&str> {
+        match self {
+            Record::Set { key, .. } | Record::SetEx { key, .. } | Record::Delete { key } => {
+                Some(key)
+            }
+            Record::Clear
+            | Record::Batch(_)
+            | Record::Namespace { .. }
+            | Record::DropNamespace { .. } => None,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        self.encode_payload(&mut payload);
+
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    fn encode_payload(&self, payload: &mut Vec<u8>) {
+        match self {
+            Record::Set { key, value } => {
+                payload.push(OP_SET);
+                encode_entry(payload, key, value);
+            }
+            Record::SetEx {
+                key,
+                value,
+                expires_at,
+            } => {
+                payload.push(OP_SET_EX);
+                payload.extend_from_slice(&expires_at.to_le_bytes());
+                encode_entry(payload, key, value);
+            }
+            Record::Delete { key } => {
+                payload.push(OP_DELETE);
+                payload.extend_from_slice(key.as_bytes());
+            }
+            Record::Clear => payload.push(OP_CLEAR),
+            Record::Batch(records) => {
+                payload.push(OP_BATCH);
+                for record in records {
+                    let mut inner = Vec::new();
+                    record.encode_payload(&mut inner);
+                    payload.extend_from_slice(&(inner.len() as u32).to_le_bytes());
+                    payload.extend_from_slice(&inner);
+                }
+            }
+            Record::Namespace { name, quota } => {
+                payload.push(OP_NAMESPACE);
+                for limit in [quota.max_keys, quota.max_bytes] {
+                    let limit = limit.map_or(UNLIMITED, |limit| limit as u64);
+                    payload.extend_from_slice(&limit.to_le_bytes());
+                }
+                payload.extend_from_slice(name.as_bytes());
+            }
+            Record::DropNamespace { name } => {
+                payload.push(OP_DROP_NAMESPACE);
+                payload.extend_from_slice(name.as_bytes());
+            }
+        }
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let (op, rest) = payload.split_first()?;
+        match *op {
+            OP_SET => {
+                let (key, value) = decode_entry(rest)?;
+                Some(Record::Set { key, value })
+            }
+            OP_SET_EX => {
+                let expires_at = u64::from_le_bytes(rest.get(..8)?.try_into().ok()?);
+                let (key, value) = decode_entry(&rest[8..])?;
+                Some(Record::SetEx {
+                    key,
+                    value,
+                    expires_at,
+                })
+            }
+            OP_DELETE => Some(Record::Delete {
+                key: String::from_utf8(rest.to_vec()).ok()?,
+            }),
+            OP_CLEAR if rest.is_empty() => Some(Record::Clear),
+            OP_BATCH => {
+                let mut records = Vec::new();
+                let mut rest = rest;
+                while !rest.is_empty() {
+                    let len = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+                    records.push(Record::decode(rest.get(4..4 + len)?)?);
+                    rest = &rest[4 + len..];
+                }
+                Some(Record::Batch(records))
+            }
+            OP_NAMESPACE => {
+                let limit = |at: usize| -> Option<Option<usize>> {
+                    let limit = u64::from_le_bytes(rest.get(at..at + 8)?.try_into().ok()?);
+                    Some((limit != UNLIMITED).then_some(limit as usize))
+                };
+                let quota = Quota {
+                    max_keys: limit(0)?,
+                    max_bytes: limit(8)?,
+                };
+                Some(Record::Namespace {
+                    name: String::from_utf8(rest[16..].to_vec()).ok()?,
+                    quota,
+                })
+            }
+            OP_DROP_NAMESPACE => Some(Record::DropNamespace {
+                name: String::from_utf8(rest.to_vec()).ok()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn encode_entry(payload: &mut Vec<u8>, key: &str, value: &[u8]) {
+    payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    payload.extend_from_slice(key.as_bytes());
+    payload.extend_from_slice(value);
+}
+
+fn decode_entry(buf: &[u8]) -> Option<(String, Bytes)> {
+    let key_len = u32::from_le_bytes(buf.get(..4)?.try_into().ok()?) as usize;
+    let key = buf.get(4..4 + key_len)?;
+    let value = &buf[4 + key_len..];
+    Some((
+        String::from_utf8(key.to_vec()).ok()?,
+        Bytes::copy_from_slice(value),
+    ))
+}
+
+/// When the log is flushed to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// `fsync` after every record, before the write is acknowledged.
+    #[default]
+    Always,
+    /// `fsync` from a background thread at a fixed interval.
+    Interval(Duration),
+    /// Leave flushing to the operating system.
+    Never,
+}
+
+/// Append-only write-ahead log.
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    file: Arc<File>,
+    policy: FsyncPolicy,
+    size: u64,
+    synced: Arc<Synced>,
+    /// Bytes of the next frame that get written before the write fails.
+    #[cfg(test)]
+    short_write: Option<usize>,
+}
+
+/// How much of the log is on disk, shared with the writers waiting for it.
+#[derive(Debug)]
+struct Synced {
+    /// Bytes written to the file so far.
+    written: AtomicU64,
+    /// Bytes known to be on disk. Held while syncing, so that writers that
+    /// come in meanwhile wait and are then likely covered already.
+    durable: Mutex<u64>,
+    /// Set once a write couldn't be cut off again or a sync failed. Nothing
+    /// more is appended after that.
+    failed: AtomicBool,
+}
+
+impl Synced {
+    fn new(size: u64) -> Arc<Self> {
+        Arc::new(Self {
+            written: AtomicU64::new(size),
+            durable: Mutex::new(size),
+            failed: AtomicBool::new(false),
+        })
+    }
+}
+
+/// A record that was written to the log and still needs to be synced, with
+/// `FsyncPolicy::Always`.
+#[derive(Debug)]
+pub(crate) struct PendingSync {
+    file: Arc<File>,
+    synced: Arc<Synced>,
+    /// Where the record ends in the log.
+    end: u64,
+}
+
+impl PendingSync {
+    /// Waits until the record is on disk. One sync covers every record that
+    /// was written before it started, so writers share them.
+    pub(crate) fn wait(self) -> io::Result<()> {
+        let mut durable = self.synced.durable.lock().unwrap();
+        if *durable >= self.end {
+            return Ok(());
+        }
+        if self.synced.failed.load(Ordering::Acquire) {
+            return Err(io::Error::other("an earlier sync of the log failed"));
+        }
+        let written = self.synced.written.load(Ordering::Acquire);
+        match self.file.sync_data() {
+            Ok(()) => {
+                *durable = written;
+                Ok(())
+            }
+            Err(err) => {
+                // Whether any of it made it to disk is anyone's guess, and
+                // the store already shows it.
+                self.synced.failed.store(true, Ordering::Release);
+                Err(err)
+            }
+        }
+    }
+
+    /// Like `wait`, but on a multi-threaded runtime the worker first hands
+    /// its other tasks to the rest, so that they don't wait for the disk.
+    pub(crate) fn wait_in_place(self) -> io::Result<()> {
+        match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(|| self.wait())
+            }
+            _ => self.wait(),
+        }
+    }
+}
+
+impl Wal {
+    /// Opens (or creates) the log at `path` and returns all intact records.
+    ///
+    /// A torn or corrupt record at the end of the file, e.g. from a crash in
+    /// the middle of a write, is cut off so that new records follow the last
+    /// good one.
+    pub fn open(path: impl AsRef<Path>, policy: FsyncPolicy) -> io::Result<(Self, Vec<Record>)> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let (records, valid_len) = replay(&buf);
+        if valid_len < buf.len() {
+            file.set_len(valid_len as u64)?;
+            file.sync_data()?;
+        }
+
+        let file = Arc::new(file);
+        if let FsyncPolicy::Interval(every) = policy {
+            spawn_syncer(Arc::downgrade(&file), every);
+        }
+
+        let wal = Self {
+            path,
+            file,
+            policy,
+            size: valid_len as u64,
+            synced: Synced::new(valid_len as u64),
+            #[cfg(test)]
+            short_write: None,
+        };
+        Ok((wal, records))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of bytes currently in the log.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Appends a record, syncing it to disk if the policy demands it.
+    #[cfg(test)]
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        self.write(record)?.map_or(Ok(()), PendingSync::wait)
+    }
+
+    /// Writes a record to the log, returning what is left to sync with
+    /// `FsyncPolicy::Always`. The sync can wait until the log is unlocked.
+    ///
+    /// A record that fails is cut off again, so that it is neither torn nor
+    /// replayed after a restart. If even that fails, so does every write
+    /// after it.
+    pub(crate) fn write(&mut self, record: &Record) -> io::Result<Option<PendingSync>> {
+        if self.synced.failed.load(Ordering::Acquire) {
+            return Err(io::Error::other(format!(
+                "{}: an earlier write or sync failed, the log needs to be reopened",
+                self.path.display()
+            )));
+        }
+        let frame = record.encode();
+        if let Err(err) = self.write_frame(&frame) {
+            let cut = self
+                .file
+                .set_len(self.size)
+                .and_then(|()| self.file.sync_data());
+            if cut.is_err() {
+                self.synced.failed.store(true, Ordering::Release);
+            }
+            return Err(err);
+        }
+        self.size += frame.len() as u64;
+        self.synced.written.store(self.size, Ordering::Release);
+        Ok((self.policy == FsyncPolicy::Always).then(|| PendingSync {
+            file: Arc::clone(&self.file),
+            synced: Arc::clone(&self.synced),
+            end: self.size,
+        }))
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        #[cfg(test)]
+        if let Some(len) = self.short_write.take() {
+            (&*self.file).write_all(&frame[..len])?;
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "short write"));
+        }
+        (&*self.file).write_all(frame)
+    }
+
+    /// Flushes everything written so far to stable storage.
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Moves the log written so far to `to` and continues with an empty one.
+    pub fn rotate(&mut self, to: &Path) -> io::Result<()> {
+        self.file.sync_data()?;
+        fs::rename(&self.path, to)?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        sync_dir(&self.path)?;
+
+        self.file = Arc::new(file);
+        self.size = 0;
+        self.synced = Synced::new(0);
+        if let FsyncPolicy::Interval(every) = self.policy {
+            spawn_syncer(Arc::downgrade(&self.file), every);
+        }
+        Ok(())
+    }
+
+    /// Drops every record in the log.
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.size = 0;
+        self.synced = Synced::new(0);
+        Ok(())
+    }
+}
+
+/// Reads a complete log file, e.g. a snapshot or a rotated log. Unlike
+/// [`Wal::open`] a damaged record is an error, as these files are never
+/// written to in place.
+pub fn read(path: &Path) -> io::Result<Vec<Record>> {
+    let buf = fs::read(path)?;
+    let (records, valid_len) = replay(&buf);
+    if valid_len < buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is corrupt at byte {valid_len}", path.display()),
+        ));
+    }
+    Ok(records)
+}
+
+/// Writes `records` to a new file at `path`, replacing an existing file only
+/// once everything is safely on disk.
+pub fn write(path: &Path, records: impl IntoIterator<Item = Record>) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    for record in records {
+        file.write_all(&record.encode())?;
+    }
+    file.into_inner()?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_dir(path)
+}
+
+/// Makes renames and newly created files in the directory of `path` durable.
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Decodes records from the start of `buf` until the first incomplete or
+/// corrupt frame. Returns the records and the number of bytes they span.
+pub(crate) fn replay(buf: &[u8]) -> (Vec<Record>, usize) {
+    let mut records = Vec::new();
+    let mut offset = 0;
+
+    while let Some(header) = buf.get(offset..offset + HEADER_LEN) {
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let Some(payload) = buf.get(offset + HEADER_LEN..offset + HEADER_LEN + len) else {
+            break;
+        };
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        let Some(record) = Record::decode(payload) else {
+            break;
+        };
+        records.push(record);
+        offset += HEADER_LEN + len;
+    }
+
+    (records, offset)
+}
+
+fn spawn_syncer(file: Weak<File>, every: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(every);
+        match file.upgrade() {
+            Some(file) => {
+                if let Err(err) = file.sync_data() {
+                    tracing::error!("syncing the write-ahead log failed: {err}");
+                }
+            }
+            None => break,
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write};
+
+    use axum::body::Bytes;
+
+    use super::{FsyncPolicy, Record, Wal};
+    use crate::Quota;
+
+    fn set(key: &str, value: &'static [u8]) -> Record {
+        Record::Set {
+            key: key.to_string(),
+            value: Bytes::from_static(value),
+        }
+    }
+
+    #[test]
+    fn records_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+
+        let written = vec![
+            set("a", b"1"),
+            set("b", b""),
+            Record::SetEx {
+                key: "e".to_string(),
+                value: Bytes::from_static(b"expiring"),
+                expires_at: 1_700_000_000_000,
+            },
+            Record::Delete {
+                key: "a".to_string(),
+            },
+            Record::Clear,
+            Record::Batch(vec![
+                set("c", b"3"),
+                Record::Delete {
+                    key: "b".to_string(),
+                },
+            ]),
+            set("d", b"4"),
+            Record::Namespace {
+                name: "team".to_string(),
+                quota: Quota {
+                    max_keys: Some(10),
+                    max_bytes: None,
+                },
+            },
+            Record::DropNamespace {
+                name: "team".to_string(),
+            },
+        ];
+
+        let (mut wal, records) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        assert!(records.is_empty());
+        for record in &written {
+            wal.append(record).unwrap();
+        }
+        drop(wal);
+
+        let (_, records) = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(records, written);
+    }
+
+    #[test]
+    fn truncated_trailing_record_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+
+        let (mut wal, _) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        wal.append(&set("a", b"1")).unwrap();
+        wal.append(&set("b", b"2")).unwrap();
+        drop(wal);
+
+        // Simulate a crash half way through writing the last record.
+        let intact = std::fs::metadata(&path).unwrap().len();
+        let torn = set("c", b"a value that never fully made it").encode();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&torn[..torn.len() / 2]).unwrap();
+        drop(file);
+
+        let (mut wal, records) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records, vec![set("a", b"1"), set("b", b"2")]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), intact);
+
+        // New writes continue right after the last intact record.
+        wal.append(&set("d", b"4")).unwrap();
+        drop(wal);
+        let (_, records) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(
+            records,
+            vec![set("a", b"1"), set("b", b"2"), set("d", b"4")]
+        );
+    }
+
+    #[test]
+    fn failed_append_is_cut_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+
+        let (mut wal, _) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        wal.append(&set("a", b"1")).unwrap();
+        let size = wal.size();
+        wal.short_write = Some(5);
+        assert!(wal.append(&set("b", b"2")).is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+
+        // Had the torn frame stayed, this record would be lost behind it.
+        wal.append(&set("c", b"3")).unwrap();
+        drop(wal);
+        let (_, records) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records, vec![set("a", b"1"), set("c", b"3")]);
+    }
+
+    #[test]
+    fn one_sync_covers_earlier_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+
+        let (mut wal, _) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        let first = wal.write(&set("a", b"1")).unwrap().unwrap();
+        let second = wal.write(&set("b", b"2")).unwrap().unwrap();
+        first.wait().unwrap();
+        assert_eq!(*wal.synced.durable.lock().unwrap(), wal.size());
+        second.wait().unwrap();
+
+        let (mut wal, _) = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        assert!(wal.write(&set("c", b"3")).unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt_trailing_record_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.log");
+
+        let (mut wal, _) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        wal.append(&set("a", b"1")).unwrap();
+        drop(wal);
+
+        let mut torn = set("b", b"2").encode();
+        let last = torn.len() - 1;
+        torn[last] ^= 0xff;
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&torn).unwrap();
+        drop(file);
+
+        let (_, records) = Wal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records, vec![set("a", b"1")]);
+    }
+}
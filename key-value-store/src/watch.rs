@@ -0,0 +1,238 @@
+use std::convert::Infallible;
+
+use axum::{
+    response::sse::{self, KeepAlive, Sse},
+    Extension,
+};
+use futures::{stream, Stream};
+use serde::Serialize;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use crate::{
+    acl::{self, Access},
+    etag, namespace, AppState, Permission,
+};
+
+/// Events a slow watcher can fall behind by before it misses some.
+pub(crate) const CAPACITY: usize = 1024;
+
+/// A change to the store, sent to everyone watching the key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Event {
+    /// A value was written. Watchers fetch it themselves if they need it.
+    Set { key: String, etag: String },
+    /// A key was deleted, evicted or expired.
+    Delete { key: String },
+    /// Every key was deleted.
+    Clear,
+}
+
+impl Event {
+    pub(crate) fn set(key: &str, value: &[u8]) -> Self {
+        Event::Set {
+            key: key.to_string(),
+            etag: etag::format(etag::hash(value)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Set { .. } => "set",
+            Event::Delete { .. } => "delete",
+            Event::Clear => "clear",
+        }
+    }
+
+    /// The event as seen from within `namespace`, `None` if it happened in
+    /// another one.
+    fn within(self, namespace: &str) -> Option<Self> {
+        let own = |stored: String| {
+            let (name, key) = namespace::split(&stored);
+            (name == namespace).then(|| key.to_string())
+        };
+        match self {
+            Event::Set { key, etag } => Some(Event::Set {
+                key: own(key)?,
+                etag,
+            }),
+            Event::Delete { key } => Some(Event::Delete { key: own(key)? }),
+            Event::Clear => Some(Event::Clear),
+        }
+    }
+
+    fn concerns(
+        &self,
+        namespace: &str,
+        filter: &Filter,
+        access: &Option<Extension<Access>>,
+    ) -> bool {
+        let key = match self {
+            Event::Set { key, .. } | Event::Delete { key } => key,
+            // Only those who may read every key learn that they are gone.
+            Event::Clear => return acl::allows(access, namespace, "", Permission::Read),
+        };
+        let matches = match filter {
+            Filter::Key(watched) => key == watched,
+            Filter::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        };
+        matches && acl::allows(access, namespace, key, Permission::Read)
+    }
+}
+
+/// Which keys a watcher is interested in.
+#[derive(Debug, Clone)]
+pub(crate) enum Filter {
+    Key(String),
+    Prefix(String),
+}
+
+impl AppState {
+    /// Receives every change made to the store from now on.
+    ///
+    /// Entries that expire are only reported once they are evicted, by the
+    /// reaper or to make room for other entries.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Nobody might be watching, that's fine.
+    pub(crate) fn notify(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
+}
+
+/// Streams the events of `namespace` matching `filter` that the watcher may
+/// read as Server-Sent Events, with keys relative to the namespace. A watcher that can't keep up gets a `lagged` event
+/// with the number of events it missed and should re-read the keys it
+/// cares about.
+pub(crate) fn sse(
+    events: Receiver<Event>,
+    namespace: String,
+    filter: Filter,
+    access: Option<Extension<Access>>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let stream = stream::unfold(events, move |mut events| {
+        let namespace = namespace.clone();
+        let filter = filter.clone();
+        let access = access.clone();
+        async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => match event.within(&namespace) {
+                        Some(event) if event.concerns(&namespace, &filter, &access) => {
+                            sse::Event::default()
+                                .event(event.name())
+                                .json_data(&event)
+                                .expect("events serialize to JSON")
+                        }
+                        _ => continue,
+                    },
+                    Err(RecvError::Lagged(missed)) => sse::Event::default()
+                        .event("lagged")
+                        .data(missed.to_string()),
+                    Err(RecvError::Closed) => return None,
+                };
+                return Some((Ok(event), events));
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use std::sync::Arc;
+
+    use axum::Extension;
+
+    use super::{Event, Filter};
+    use crate::{acl::Access, AppState, Limits, Op, Policy, Principal};
+
+    #[test]
+    fn changes_are_broadcast() {
+        let mut db = AppState::default().with_limits(Limits {
+            max_keys: Some(2),
+            ..Default::default()
+        });
+        let mut events = db.subscribe();
+
+        db.set("a".to_string(), Bytes::from_static(b"1")).unwrap();
+        db.remove("a").unwrap();
+        // Deleting a key that isn't there changes nothing.
+        db.remove("a").unwrap();
+        db.transaction(vec![
+            Op::Set {
+                key: "b".to_string(),
+                value: Bytes::from_static(b"2"),
+                ttl: None,
+            },
+            Op::Set {
+                key: "c".to_string(),
+                value: Bytes::from_static(b"3"),
+                ttl: None,
+            },
+        ])
+        .unwrap();
+        db.set("d".to_string(), Bytes::from_static(b"4")).unwrap();
+        db.clear().unwrap();
+
+        let mut received = Vec::new();
+        loop {
+            match events.try_recv() {
+                Ok(event) => received.push(event),
+                Err(TryRecvError::Empty) => break,
+                Err(err) => panic!("{err}"),
+            }
+        }
+        assert_eq!(
+            received,
+            vec![
+                Event::set("a", b"1"),
+                Event::Delete {
+                    key: "a".to_string()
+                },
+                Event::set("b", b"2"),
+                Event::set("c", b"3"),
+                Event::set("d", b"4"),
+                Event::Delete {
+                    key: "b".to_string()
+                },
+                Event::Clear,
+            ]
+        );
+    }
+
+    #[test]
+    fn clears_reach_those_who_may_read_everything() {
+        let policy: Policy = r#"
+            [[rules]]
+            principal = "team-a"
+            prefix = "team-a/"
+            allow = ["read"]
+
+            [[rules]]
+            principal = "ops"
+            allow = ["read"]
+        "#
+        .parse()
+        .unwrap();
+        let policy = Arc::new(policy);
+        let access = |name: &str| {
+            let principal = Principal {
+                name: name.to_string(),
+                admin: false,
+            };
+            Some(Extension(Access::new(Arc::clone(&policy), principal)))
+        };
+        let everything = Filter::Prefix(String::new());
+
+        assert!(Event::Clear.concerns("", &everything, &access("ops")));
+        assert!(Event::Clear.concerns("", &everything, &None));
+        assert!(!Event::Clear.concerns("", &everything, &access("team-a")));
+        assert!(Event::set("team-a/x", b"1").concerns("", &everything, &access("team-a")));
+    }
+}
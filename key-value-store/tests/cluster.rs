@@ -0,0 +1,342 @@
+use std::{future::Future, net::TcpListener, time::Duration};
+
+use hyper::{header, Body, Client, Method, Request, Response, StatusCode};
+use key_value_store::{
+    router_with_options, serve, ApiKeys, AppState, Cluster, ClusterOptions, Limits, Members,
+    NodeId, Role, RouterOptions, SharedState,
+};
+use tokio::sync::oneshot;
+
+const API_KEY: &str = "cluster-secret";
+
+/// A node served on a local port.
+struct Node {
+    id: NodeId,
+    url: String,
+    state: SharedState,
+    cluster: Cluster,
+    shutdown: oneshot::Sender<()>,
+}
+
+/// Nodes of a cluster, running in this process.
+struct TestCluster {
+    nodes: Vec<Node>,
+    max_log_entries: usize,
+}
+
+fn bind() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    (listener, url)
+}
+
+async fn start_node(
+    listener: TcpListener,
+    url: String,
+    id: NodeId,
+    members: Members,
+    max_log_entries: usize,
+) -> Node {
+    let state = SharedState::default();
+    let cluster = Cluster::start(
+        &state,
+        ClusterOptions {
+            id,
+            members,
+            api_key: API_KEY.to_string(),
+            heartbeat: Duration::from_millis(20),
+            election_timeout: Duration::from_millis(150),
+            max_log_entries,
+            path: None,
+        },
+    )
+    .await
+    .unwrap();
+    let api_keys: ApiKeys =
+        format!("[[keys]]\nname = \"cluster\"\nkey = \"{API_KEY}\"\nadmin = true")
+            .parse()
+            .unwrap();
+    let options = RouterOptions::builder()
+        .api_keys(api_keys)
+        .cluster(cluster.clone())
+        .build();
+    let app = router_with_options(&state, options);
+    let (shutdown, signal) = oneshot::channel::<()>();
+    let server_state = state.clone();
+    tokio::spawn(async move {
+        serve(
+            listener,
+            app,
+            &server_state,
+            async {
+                let _ = signal.await;
+            },
+            Duration::ZERO,
+        )
+        .await
+    });
+    Node {
+        id,
+        url,
+        state,
+        cluster,
+        shutdown,
+    }
+}
+
+impl TestCluster {
+    async fn start(size: u64, max_log_entries: usize) -> Self {
+        let listeners: Vec<_> = (1..=size).map(|id| (id, bind())).collect();
+        let members: Members = listeners
+            .iter()
+            .map(|(id, (_, url))| (*id, url.clone()))
+            .collect();
+        let mut nodes = Vec::new();
+        for (id, (listener, url)) in listeners {
+            nodes.push(start_node(listener, url, id, members.clone(), max_log_entries).await);
+        }
+        let cluster = Self {
+            nodes,
+            max_log_entries,
+        };
+        cluster.leader().await;
+        cluster
+    }
+
+    /// Starts a node that waits to be added to the cluster.
+    async fn start_outsider(&mut self, id: NodeId) -> &Node {
+        let (listener, url) = bind();
+        let node = start_node(listener, url, id, Members::new(), self.max_log_entries).await;
+        self.nodes.push(node);
+        self.nodes.last().unwrap()
+    }
+
+    /// Waits until the running nodes agree on a leader.
+    async fn leader(&self) -> &Node {
+        let mut leader = None;
+        eventually(|| {
+            let statuses: Vec<_> = self
+                .nodes
+                .iter()
+                .map(|node| node.cluster.status())
+                .collect();
+            leader = statuses
+                .iter()
+                .find(|status| status.role == Role::Leader)
+                .map(|status| status.id);
+            let agreed = statuses
+                .iter()
+                .filter(|status| status.members.contains_key(&status.id))
+                .all(|status| status.leader.is_some() && status.leader == leader);
+            async move { agreed }
+        })
+        .await;
+        self.node(leader.unwrap())
+    }
+
+    fn node(&self, id: NodeId) -> &Node {
+        self.nodes.iter().find(|node| node.id == id).unwrap()
+    }
+
+    fn follower(&self, leader: NodeId) -> &Node {
+        self.nodes.iter().find(|node| node.id != leader).unwrap()
+    }
+
+    /// Stops serving a node and drops it from the cluster.
+    fn stop(&mut self, id: NodeId) {
+        let i = self.nodes.iter().position(|node| node.id == id).unwrap();
+        let node = self.nodes.remove(i);
+        node.shutdown.send(()).unwrap();
+    }
+
+    /// Waits until every running node has `value` under `key`, or nothing
+    /// for `None`.
+    async fn converged(&self, key: &str, value: Option<&str>) {
+        eventually(|| async move {
+            for node in &self.nodes {
+                let current = node.state.read().await.get(key);
+                if current.as_deref() != value.map(str::as_bytes) {
+                    return false;
+                }
+            }
+            true
+        })
+        .await;
+    }
+}
+
+async fn request(
+    method: Method,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &'static str,
+) -> Response<Body> {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(url)
+        .header(header::AUTHORIZATION, format!("Bearer {API_KEY}"))
+        .header(header::CONTENT_TYPE, "application/json");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    Client::new()
+        .request(request.body(Body::from(body)).unwrap())
+        .await
+        .unwrap()
+}
+
+/// Waits for `done` to hold, failing after a few seconds.
+async fn eventually<F: Future<Output = bool>>(mut done: impl FnMut() -> F) {
+    for _ in 0..500 {
+        if done().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("the cluster didn't get there");
+}
+
+#[tokio::test]
+async fn writes_go_through_the_leader() {
+    let cluster = TestCluster::start(3, 1000).await;
+    let leader = cluster.leader().await;
+    let follower = cluster.follower(leader.id);
+
+    let response = request(Method::POST, &format!("{}/kv/a", leader.url), &[], "1").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(&leader.state.read().await.get("a").unwrap()[..], b"1");
+    cluster.converged("a", Some("1")).await;
+
+    // Followers send writes on to the leader.
+    let url = format!("{}/kv/b?ttl=60", follower.url);
+    let response = request(Method::POST, &url, &[], "2").await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    let location = response.headers()[header::LOCATION].to_str().unwrap();
+    assert_eq!(location, format!("{}/kv/b?ttl=60", leader.url));
+    let response = request(Method::POST, location, &[], "2").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    cluster.converged("b", Some("2")).await;
+
+    // Preconditions are checked as the write is applied.
+    let url = format!("{}/kv/a", leader.url);
+    let response = request(Method::POST, &url, &[("if-none-match", "*")], "3").await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+    let url = format!("{}/admin/keys/a", follower.url);
+    let response = request(Method::DELETE, &url, &[], "").await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    let url = format!("{}/admin/keys/a", leader.url);
+    let response = request(Method::DELETE, &url, &[], "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    cluster.converged("a", None).await;
+    cluster.converged("b", Some("2")).await;
+
+    // Writes that don't go through the log yet are turned down.
+    let url = format!("{}/txn", leader.url);
+    let response = request(Method::POST, &url, &[], r#"{"ops": []}"#).await;
+    assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+}
+
+#[tokio::test]
+async fn a_new_leader_takes_over() {
+    let mut cluster = TestCluster::start(3, 1000).await;
+    let old = cluster.leader().await;
+    let (old_id, old_url) = (old.id, old.url.clone());
+    let response = request(Method::POST, &format!("{old_url}/kv/a"), &[], "1").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    cluster.converged("a", Some("1")).await;
+
+    cluster.stop(old_id);
+    let leader = cluster.leader().await;
+    assert_ne!(leader.id, old_id);
+    let status = leader.cluster.status();
+    assert!(status.term > 1);
+
+    let response = request(Method::POST, &format!("{}/kv/b", leader.url), &[], "2").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    cluster.converged("a", Some("1")).await;
+    cluster.converged("b", Some("2")).await;
+}
+
+#[tokio::test]
+async fn members_are_added_and_removed() {
+    // A short log, so that the new member needs a snapshot.
+    let mut cluster = TestCluster::start(3, 4).await;
+    let leader_url = cluster.leader().await.url.clone();
+    for i in 0..10 {
+        let url = format!("{leader_url}/kv/key{i}");
+        let response = request(Method::POST, &url, &[], "1").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let new_url = cluster.start_outsider(4).await.url.clone();
+    let url = format!("{leader_url}/admin/cluster/members/4");
+    let body = Box::leak(format!(r#"{{"url": "{new_url}"}}"#).into_boxed_str());
+    let response = request(Method::PUT, &url, &[], body).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    cluster.converged("key0", Some("1")).await;
+    cluster.converged("key9", Some("1")).await;
+
+    let response = request(Method::POST, &format!("{leader_url}/kv/new"), &[], "2").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    cluster.converged("new", Some("2")).await;
+    let leader = cluster.leader().await;
+    assert_eq!(cluster.node(4).cluster.status().leader, Some(leader.id));
+
+    let removed = cluster.follower(leader.id).id;
+    let url = format!("{leader_url}/admin/cluster/members/{removed}");
+    let response = request(Method::DELETE, &url, &[], "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = request(Method::DELETE, &url, &[], "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    cluster.stop(removed);
+
+    let response = request(Method::GET, &format!("{leader_url}/admin/cluster"), &[], "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["role"], "leader");
+    assert_eq!(status["members"].as_object().unwrap().len(), 3);
+    assert!(status["members"].get(removed.to_string()).is_none());
+
+    let response = request(Method::POST, &format!("{leader_url}/kv/last"), &[], "3").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    cluster.converged("last", Some("3")).await;
+}
+
+#[tokio::test]
+async fn only_new_stores_become_nodes() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = ClusterOptions {
+        members: Members::from([(1, "http://127.0.0.1:1".to_string())]),
+        path: Some(dir.path().join("kv.node")),
+        ..ClusterOptions::default()
+    };
+    let state = SharedState::default();
+    let cluster = Cluster::start(&state, options.clone()).await.unwrap();
+    drop(cluster);
+
+    // It may have voted, and its log is gone.
+    let err = Cluster::start(&SharedState::default(), options.clone())
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    let restarted = ClusterOptions {
+        id: 2,
+        path: Some(dir.path().join("other.node")),
+        ..options
+    };
+    state.read().await.set("a".to_string(), "1".into()).unwrap();
+    let err = Cluster::start(&state, restarted.clone()).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+
+    // Each node would evict entries of its own.
+    let limited = AppState::default().with_limits(Limits {
+        max_keys: Some(10),
+        ..Limits::default()
+    });
+    let err = Cluster::start(&SharedState::new(limited.into()), restarted)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
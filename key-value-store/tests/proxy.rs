@@ -0,0 +1,276 @@
+use std::{collections::BTreeMap, net::TcpListener, time::Duration};
+
+use hyper::{header, Body, Method, Request, StatusCode};
+use key_value_store::{
+    proxy_router, router_with_options, serve, ApiKeys, Config, Fault, Proxy, ProxyOptions, Ring,
+    RouterOptions, SharedState,
+};
+use tower::ServiceExt;
+
+const API_KEY: &str = "proxy-secret";
+
+/// A store served on a local port.
+struct Backend {
+    url: String,
+    state: SharedState,
+}
+
+fn api_keys() -> ApiKeys {
+    format!("[[keys]]\nname = \"proxy\"\nkey = \"{API_KEY}\"\nadmin = true")
+        .parse()
+        .unwrap()
+}
+
+/// Serves a store for the rest of the test.
+fn start_backend(options: RouterOptions) -> Backend {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let state = SharedState::default();
+    let app = router_with_options(&state, options);
+    let server_state = state.clone();
+    tokio::spawn(async move {
+        serve(
+            listener,
+            app,
+            &server_state,
+            std::future::pending(),
+            Duration::ZERO,
+        )
+        .await
+    });
+    Backend { url, state }
+}
+
+fn options() -> RouterOptions {
+    RouterOptions::builder().api_keys(api_keys()).build()
+}
+
+fn proxy(backends: &[(&str, &Backend)]) -> Proxy {
+    Proxy::new(ProxyOptions {
+        backends: backends
+            .iter()
+            .map(|(name, backend)| (name.to_string(), backend.url.clone()))
+            .collect(),
+        vnodes: 32,
+        api_keys: api_keys(),
+        backend_api_key: API_KEY.to_string(),
+        ..ProxyOptions::default()
+    })
+}
+
+async fn request(
+    proxy: &Proxy,
+    method: Method,
+    uri: &str,
+    headers: &[(&str, &str)],
+    body: String,
+) -> (StatusCode, String) {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {API_KEY}"))
+        .header(header::CONTENT_TYPE, "application/json");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = proxy_router(proxy)
+        .oneshot(request.body(Body::from(body)).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+/// Writes `count` keys through the proxy, every other one with a TTL.
+async fn write_keys(proxy: &Proxy, count: usize) {
+    for i in 0..count {
+        let uri = if i % 2 == 0 {
+            format!("/kv/key{i}")
+        } else {
+            format!("/kv/key{i}?ttl=600")
+        };
+        let (status, _) = request(proxy, Method::POST, &uri, &[], i.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
+
+/// Checks that each key is stored by exactly one backend, and can be read
+/// through the proxy with its TTL.
+async fn check_keys(proxy: &Proxy, backends: &[&Backend], count: usize) {
+    for i in 0..count {
+        let key = format!("key{i}");
+        let mut holders = Vec::new();
+        for backend in backends {
+            let db = backend.state.read().await;
+            if db.get(&key).is_some() {
+                assert_eq!(db.ttl(&key).is_some(), i % 2 == 1, "{key}");
+                holders.push(backend.url.clone());
+            }
+        }
+        assert_eq!(holders.len(), 1, "{key} is stored by {holders:?}");
+
+        let (status, body) =
+            request(proxy, Method::GET, &format!("/kv/{key}"), &[], "".into()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, i.to_string());
+    }
+}
+
+#[tokio::test]
+async fn keys_are_spread_over_the_backends() {
+    let (a, b, c) = (
+        start_backend(options()),
+        start_backend(options()),
+        start_backend(options()),
+    );
+    let proxy = proxy(&[("a", &a), ("b", &b), ("c", &c)]);
+    write_keys(&proxy, 60).await;
+    check_keys(&proxy, &[&a, &b, &c], 60).await;
+    for backend in [&a, &b, &c] {
+        assert!(backend.state.read().await.len() > 5);
+    }
+
+    let (status, _) = request(&proxy, Method::DELETE, "/admin/keys/key0", &[], "".into()).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = request(&proxy, Method::GET, "/kv/key0", &[], "".into()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, body) = request(&proxy, Method::GET, "/admin/backends", &[], "".into()).await;
+    assert_eq!(status, StatusCode::OK);
+    let backends: BTreeMap<String, String> = serde_json::from_str(&body).unwrap();
+    assert_eq!(backends, proxy.backends());
+    let response = proxy_router(&proxy)
+        .oneshot(Request::get("/admin/backends").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn backends_are_added_and_removed() {
+    let (a, b, c) = (
+        start_backend(options()),
+        start_backend(options()),
+        start_backend(options()),
+    );
+    let proxy = proxy(&[("a", &a), ("b", &b)]);
+    write_keys(&proxy, 100).await;
+    assert!(c.state.read().await.is_empty());
+
+    let body = format!(r#"{{"url": "{}"}}"#, c.url);
+    let (status, moved) = request(&proxy, Method::PUT, "/admin/backends/c", &[], body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let moved: serde_json::Value = serde_json::from_str(&moved).unwrap();
+    let moved = moved["moved"].as_u64().unwrap() as usize;
+    assert!(moved > 10, "{moved}");
+    assert_eq!(c.state.read().await.len(), moved);
+    check_keys(&proxy, &[&a, &b, &c], 100).await;
+
+    let (status, _) = request(&proxy, Method::DELETE, "/admin/backends/a", &[], "".into()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(a.state.read().await.is_empty());
+    check_keys(&proxy, &[&b, &c], 100).await;
+
+    let (status, _) = request(&proxy, Method::DELETE, "/admin/backends/a", &[], "".into()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = request(&proxy, Method::DELETE, "/admin/backends/b", &[], "".into()).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = request(&proxy, Method::DELETE, "/admin/backends/c", &[], "".into()).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    check_keys(&proxy, &[&c], 100).await;
+}
+
+#[tokio::test]
+async fn stores_with_the_default_config_take_rebalancing() {
+    // The proxy uses the admin routes for every key it moves.
+    let options = || RouterOptions {
+        api_keys: api_keys(),
+        ..Config::default().router_options().unwrap()
+    };
+    let (a, b) = (start_backend(options()), start_backend(options()));
+    let proxy = proxy(&[("a", &a)]);
+    write_keys(&proxy, 100).await;
+
+    let body = format!(r#"{{"url": "{}"}}"#, b.url);
+    let (status, moved) = request(&proxy, Method::PUT, "/admin/backends/b", &[], body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let moved: serde_json::Value = serde_json::from_str(&moved).unwrap();
+    assert!(moved["moved"].as_u64().unwrap() > 20, "{moved}");
+    check_keys(&proxy, &[&a, &b], 100).await;
+}
+
+#[tokio::test]
+async fn deletes_while_a_key_moves_stick() {
+    let a = start_backend(options());
+    // Writes to the new backend take a while, deletes get in before the
+    // copy lands.
+    let slow = Fault {
+        route: "/kv/:key".to_string(),
+        method: Some("POST".to_string()),
+        latency_ms: 300,
+        ..Fault::default()
+    };
+    let b = start_backend(RouterOptions {
+        faults: vec![slow],
+        ..options()
+    });
+    let mut ring = Ring::new(32);
+    ring.add("a", &a.url);
+    ring.add("b", &b.url);
+    let key = (0..)
+        .map(|i| format!("key{i}"))
+        .find(|key| ring.backend(key).unwrap().0 == "b")
+        .unwrap();
+    let proxy = proxy(&[("a", &a)]);
+    let uri = format!("/kv/{key}");
+    let (status, _) = request(&proxy, Method::POST, &uri, &[], "1".into()).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let adding = {
+        let proxy = proxy.clone();
+        let url = b.url.clone();
+        tokio::spawn(async move { proxy.add_backend("b", &url).await.unwrap() })
+    };
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let uri = format!("/admin/keys/{key}");
+    let (status, _) = request(&proxy, Method::DELETE, &uri, &[], "".into()).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, rebalanced) = adding.await.unwrap();
+    assert_eq!(rebalanced.moved, 0);
+    assert!(a.state.read().await.is_empty());
+    assert!(b.state.read().await.is_empty());
+    let (status, _) = request(&proxy, Method::GET, &format!("/kv/{key}"), &[], "".into()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn reads_fall_back_while_keys_move() {
+    let (a, b) = (start_backend(options()), start_backend(options()));
+    // Nothing can be written to this one, so keys can't move there.
+    let locked = start_backend(RouterOptions::builder().read_only().build());
+    let proxy = proxy(&[("a", &a), ("b", &b)]);
+    write_keys(&proxy, 40).await;
+
+    let body = format!(r#"{{"url": "{}"}}"#, locked.url);
+    let (status, _) = request(&proxy, Method::PUT, "/admin/backends/c", &[], body).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert!(locked.state.read().await.is_empty());
+    // Every key can still be read where it was, and deleted.
+    check_keys(&proxy, &[&a, &b, &locked], 40).await;
+    for i in 0..40 {
+        let uri = format!("/admin/keys/key{i}");
+        let (status, _) = request(&proxy, Method::DELETE, &uri, &[], "".into()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    assert!(a.state.read().await.is_empty());
+    assert!(b.state.read().await.is_empty());
+
+    let (status, body) = request(&proxy, Method::DELETE, "/admin/backends/c", &[], "".into()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, r#"{"moved":0}"#);
+    let (status, body) = request(&proxy, Method::POST, "/admin/rebalance", &[], "".into()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, r#"{"moved":0}"#);
+}
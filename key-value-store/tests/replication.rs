@@ -0,0 +1,159 @@
+use std::{future::Future, net::TcpListener, time::Duration};
+
+use hyper::{header, Body, Client, Method, Request, StatusCode};
+use key_value_store::{
+    router_with_options, serve, spawn_follower, ApiKeys, AppState, RouterOptions, SharedState,
+};
+
+const API_KEY: &str = "follower-secret";
+
+/// Serves the store on a free port for the rest of the test, returning its
+/// base URL.
+fn start(state: &SharedState, options: RouterOptions) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app = router_with_options(state, options);
+    let state = state.clone();
+    tokio::spawn(async move {
+        serve(
+            listener,
+            app,
+            &state,
+            std::future::pending(),
+            Duration::ZERO,
+        )
+        .await
+    });
+    url
+}
+
+fn leader_options() -> RouterOptions {
+    let api_keys: ApiKeys =
+        format!("[[keys]]\nname = \"follower\"\nkey = \"{API_KEY}\"\nadmin = true")
+            .parse()
+            .unwrap();
+    RouterOptions::builder().api_keys(api_keys).build()
+}
+
+async fn request(method: Method, url: String, body: &'static str) -> StatusCode {
+    let request = Request::builder()
+        .method(method)
+        .uri(url)
+        .header(header::AUTHORIZATION, format!("Bearer {API_KEY}"))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap();
+    Client::new().request(request).await.unwrap().status()
+}
+
+/// Waits for `done` to hold, failing after a few seconds.
+async fn eventually<F: Future<Output = bool>>(mut done: impl FnMut() -> F) {
+    for _ in 0..200 {
+        if done().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("the follower didn't catch up");
+}
+
+async fn in_sync(leader: &SharedState, follower: &SharedState) -> bool {
+    let (leader, follower) = (leader.read().await, follower.read().await);
+    leader.keys() == follower.keys()
+        && leader.namespaces() == follower.namespaces()
+        && leader
+            .keys()
+            .iter()
+            .all(|key| leader.get(key) == follower.get(key))
+}
+
+#[tokio::test]
+async fn follower_replicates_the_leader() {
+    let leader = SharedState::default();
+    let leader_url = start(&leader, leader_options());
+    for (key, value) in [("a", "1"), ("b", "2"), ("c", "3")] {
+        let status = request(Method::POST, format!("{leader_url}/kv/{key}"), value).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    // Everything so far arrives with the snapshot.
+    let follower = SharedState::new(AppState::default().into());
+    follower
+        .read()
+        .await
+        .set("stale".to_string(), "x".into())
+        .unwrap();
+    let handle = spawn_follower(
+        &follower,
+        leader_url.parse().unwrap(),
+        API_KEY.to_string(),
+        Duration::from_millis(10),
+    );
+    eventually(|| in_sync(&leader, &follower)).await;
+    assert!(follower.read().await.get("stale").is_none());
+
+    // And everything after that with the stream.
+    let status = request(Method::POST, format!("{leader_url}/kv/a"), "one").await;
+    assert_eq!(status, StatusCode::OK);
+    let status = request(Method::DELETE, format!("{leader_url}/admin/keys/b"), "").await;
+    assert_eq!(status, StatusCode::OK);
+    let status = request(
+        Method::PUT,
+        format!("{leader_url}/admin/namespaces/team"),
+        "{}",
+    )
+    .await;
+    assert!(status.is_success(), "{status}");
+    let status = request(Method::POST, format!("{leader_url}/ns/team/kv/d"), "4").await;
+    assert_eq!(status, StatusCode::OK);
+    eventually(|| in_sync(&leader, &follower)).await;
+    assert_eq!(&follower.read().await.get("a").unwrap()[..], b"one");
+    assert_eq!(follower.read().await.namespaces().len(), 1);
+
+    drop(follower);
+    request(Method::POST, format!("{leader_url}/kv/e"), "5").await;
+    tokio::time::timeout(Duration::from_secs(2), handle)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn follower_is_read_only() {
+    let leader = SharedState::default();
+    let leader_url = start(&leader, leader_options());
+    request(Method::POST, format!("{leader_url}/kv/a"), "1").await;
+
+    let follower = SharedState::default();
+    let follower_url = start(&follower, RouterOptions::builder().read_only().build());
+    spawn_follower(
+        &follower,
+        leader_url.parse().unwrap(),
+        API_KEY.to_string(),
+        Duration::from_millis(10),
+    );
+    eventually(|| in_sync(&leader, &follower)).await;
+
+    let response = Client::new()
+        .get(format!("{follower_url}/kv/a").parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let status = request(Method::POST, format!("{follower_url}/kv/a"), "2").await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    let status = request(Method::DELETE, format!("{follower_url}/admin/keys/a"), "").await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(&follower.read().await.get("a").unwrap()[..], b"1");
+}
+
+#[tokio::test]
+async fn replication_needs_an_admin_key() {
+    let leader = SharedState::default();
+    let leader_url = start(&leader, leader_options());
+
+    let response = Client::new()
+        .get(format!("{leader_url}/replicate").parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
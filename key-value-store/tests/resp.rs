@@ -0,0 +1,311 @@
+use std::{net::TcpListener, time::Duration};
+
+use hyper::{header, Body, Client, Method, Request, StatusCode};
+use key_value_store::{
+    router_with_options, serve, spawn_resp, ApiKeys, Policy, RouterOptions, SharedState,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+
+const ADMIN_KEY: &str = "admin-secret";
+const USER_KEY: &str = "user-secret";
+
+/// A reply as it was sent.
+#[derive(Debug, PartialEq, Eq)]
+enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Value>),
+}
+
+fn ok() -> Value {
+    Value::Simple("OK".to_string())
+}
+
+fn bulk(value: &str) -> Value {
+    Value::Bulk(value.as_bytes().to_vec())
+}
+
+/// Just enough of a Redis client to talk to the store.
+struct RespClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl RespClient {
+    async fn connect(addr: &str) -> Self {
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        Self {
+            reader: BufReader::new(reader),
+            writer,
+        }
+    }
+
+    /// Sends a command as an array of bulk strings.
+    async fn send(&mut self, args: &[&str]) {
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            command.push_str(&format!("${}\r\n{arg}\r\n", arg.len()));
+        }
+        self.writer.write_all(command.as_bytes()).await.unwrap();
+    }
+
+    async fn call(&mut self, args: &[&str]) -> Value {
+        self.send(args).await;
+        self.read().await
+    }
+
+    async fn read(&mut self) -> Value {
+        let line = self.line().await;
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Value::Simple(rest.to_string()),
+            "-" => Value::Error(rest.to_string()),
+            ":" => Value::Integer(rest.parse().unwrap()),
+            "$" => {
+                let Ok(len) = rest.parse::<usize>() else {
+                    return Value::Nil;
+                };
+                let mut value = vec![0; len + 2];
+                self.reader.read_exact(&mut value).await.unwrap();
+                value.truncate(len);
+                Value::Bulk(value)
+            }
+            "*" => {
+                let len: usize = rest.parse().unwrap();
+                let mut items = Vec::new();
+                for _ in 0..len {
+                    items.push(Box::pin(self.read()).await);
+                }
+                Value::Array(items)
+            }
+            _ => panic!("unexpected reply {line:?}"),
+        }
+    }
+
+    async fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).await.unwrap();
+        line.strip_suffix("\r\n").unwrap().to_string()
+    }
+}
+
+fn options() -> RouterOptions {
+    let api_keys: ApiKeys = format!(
+        "[[keys]]\nname = \"admin\"\nkey = \"{ADMIN_KEY}\"\nadmin = true\n\
+         [[keys]]\nname = \"user\"\nkey = \"{USER_KEY}\""
+    )
+    .parse()
+    .unwrap();
+    RouterOptions::builder().api_keys(api_keys).build()
+}
+
+/// Serves the store on free ports over HTTP and RESP, returning the base
+/// URL and the RESP address.
+fn start(state: &SharedState, options: RouterOptions) -> (String, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let resp = listener.local_addr().unwrap().to_string();
+    spawn_resp(listener, state, options.clone()).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app = router_with_options(state, options);
+    let state = state.clone();
+    tokio::spawn(async move {
+        serve(
+            listener,
+            app,
+            &state,
+            std::future::pending(),
+            Duration::ZERO,
+        )
+        .await
+    });
+    (url, resp)
+}
+
+async fn http(method: Method, url: String, body: &'static str) -> (StatusCode, String) {
+    let request = Request::builder()
+        .method(method)
+        .uri(url)
+        .header(header::AUTHORIZATION, format!("Bearer {ADMIN_KEY}"))
+        .body(Body::from(body))
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn commands_see_the_same_store_as_http() {
+    let state = SharedState::default();
+    let (url, resp) = start(&state, options());
+    let mut client = RespClient::connect(&resp).await;
+
+    assert_eq!(client.call(&["PING"]).await, Value::Simple("PONG".into()));
+    assert_eq!(client.call(&["SET", "a", "1"]).await, ok());
+    assert_eq!(client.call(&["get", "a"]).await, bulk("1"));
+    assert_eq!(client.call(&["GET", "missing"]).await, Value::Nil);
+    assert_eq!(
+        http(Method::GET, format!("{url}/kv/a"), "").await,
+        (StatusCode::OK, "1".to_string())
+    );
+
+    let (status, _) = http(Method::POST, format!("{url}/kv/b"), "two").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(client.call(&["GET", "b"]).await, bulk("two"));
+    assert_eq!(
+        client.call(&["EXISTS", "a", "b", "a", "c"]).await,
+        Value::Integer(3)
+    );
+
+    assert_eq!(client.call(&["SET", "a", "x", "NX"]).await, Value::Nil);
+    assert_eq!(client.call(&["SET", "c", "3", "XX"]).await, Value::Nil);
+    assert_eq!(client.call(&["SET", "c", "3", "EX", "60"]).await, ok());
+    assert!(state.read().await.ttl("c").is_some());
+    assert_eq!(
+        client.call(&["SET", "c", "3", "EX", "0"]).await,
+        Value::Error("ERR invalid expire time in 'set' command".into())
+    );
+    assert_eq!(
+        client.call(&["EXPIRE", "a", &u64::MAX.to_string()]).await,
+        Value::Error("ERR invalid expire time in 'expire' command".into())
+    );
+    assert_eq!(client.call(&["EXPIRE", "a", "60"]).await, Value::Integer(1));
+    assert_eq!(
+        client.call(&["EXPIRE", "missing", "60"]).await,
+        Value::Integer(0)
+    );
+    let ttl = state.read().await.ttl("a").unwrap();
+    assert!(ttl > Duration::from_secs(59));
+    assert_eq!(client.call(&["GET", "a"]).await, bulk("1"));
+
+    let (status, _) = http(Method::POST, format!("{url}/kv/user:1"), "u").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        client.call(&["KEYS", "*"]).await,
+        Value::Array(vec![bulk("a"), bulk("b"), bulk("c"), bulk("user:1")])
+    );
+    assert_eq!(
+        client.call(&["KEYS", "user:*"]).await,
+        Value::Array(vec![bulk("user:1")])
+    );
+    assert_eq!(
+        client.call(&["KEYS", "[ab]"]).await,
+        Value::Array(vec![bulk("a"), bulk("b")])
+    );
+
+    // Deleting needs an admin key, like `/admin/keys` does.
+    assert_eq!(
+        client.call(&["DEL", "a"]).await,
+        Value::Error("NOAUTH Authentication required.".into())
+    );
+    assert_eq!(client.call(&["AUTH", USER_KEY]).await, ok());
+    assert!(
+        matches!(client.call(&["DEL", "a"]).await, Value::Error(err) if err.starts_with("NOPERM"))
+    );
+    assert!(
+        matches!(client.call(&["AUTH", "wrong"]).await, Value::Error(err) if err.starts_with("WRONGPASS"))
+    );
+    assert_eq!(client.call(&["AUTH", "default", ADMIN_KEY]).await, ok());
+    assert_eq!(
+        client.call(&["DEL", "a", "missing", "b"]).await,
+        Value::Integer(2)
+    );
+    assert_eq!(
+        http(Method::GET, format!("{url}/kv/a"), "").await.0,
+        StatusCode::NOT_FOUND
+    );
+
+    assert_eq!(client.call(&["FLUSHALL"]).await, ok());
+    assert!(state.read().await.is_empty());
+    assert_eq!(client.call(&["QUIT"]).await, ok());
+}
+
+#[tokio::test]
+async fn pipelined_and_inline_commands() {
+    let state = SharedState::default();
+    let (_, resp) = start(&state, options());
+    let mut client = RespClient::connect(&resp).await;
+
+    client.send(&["SET", "a", "1"]).await;
+    client.send(&["SET", "b", "line\r\nbreak"]).await;
+    client
+        .writer
+        .write_all(b"GET a\r\nGET b\r\nNOPE\r\n")
+        .await
+        .unwrap();
+    assert_eq!(client.read().await, ok());
+    assert_eq!(client.read().await, ok());
+    assert_eq!(client.read().await, bulk("1"));
+    assert_eq!(client.read().await, bulk("line\r\nbreak"));
+    assert_eq!(
+        client.read().await,
+        Value::Error("ERR unknown command 'NOPE'".into())
+    );
+    assert_eq!(
+        client.call(&["GET"]).await,
+        Value::Error("ERR wrong number of arguments for 'get' command".into())
+    );
+
+    // The connection is closed after a protocol error.
+    client.writer.write_all(b"*1\r\n$x\r\n").await.unwrap();
+    assert!(
+        matches!(client.read().await, Value::Error(err) if err.starts_with("ERR Protocol error"))
+    );
+    let mut rest = Vec::new();
+    client.reader.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+}
+
+#[tokio::test]
+async fn options_apply_like_they_do_to_http() {
+    let policy: Policy =
+        "[[rules]]\nprincipal = \"user\"\nprefix = \"public/\"\nallow = [\"read\", \"write\"]"
+            .parse()
+            .unwrap();
+    let options = RouterOptions {
+        acl: Some(policy),
+        ..options()
+    };
+    let state = SharedState::default();
+    state
+        .read()
+        .await
+        .set("secret".to_string(), "x".into())
+        .unwrap();
+    let (_, resp) = start(&state, options);
+    let mut client = RespClient::connect(&resp).await;
+
+    assert_eq!(
+        client.call(&["GET", "public/a"]).await,
+        Value::Error("NOAUTH Authentication required.".into())
+    );
+    assert_eq!(client.call(&["AUTH", USER_KEY]).await, ok());
+    assert_eq!(client.call(&["SET", "public/a", "1"]).await, ok());
+    assert!(
+        matches!(client.call(&["GET", "secret"]).await, Value::Error(err) if err.starts_with("NOPERM"))
+    );
+    assert_eq!(
+        client.call(&["KEYS", "*"]).await,
+        Value::Array(vec![bulk("public/a")])
+    );
+
+    let follower = SharedState::default();
+    let (_, resp) = start(&follower, RouterOptions::builder().read_only().build());
+    let mut client = RespClient::connect(&resp).await;
+    assert_eq!(
+        client.call(&["SET", "a", "1"]).await,
+        Value::Error("READONLY You can't write against a read only replica.".into())
+    );
+    assert_eq!(client.call(&["GET", "a"]).await, Value::Nil);
+}
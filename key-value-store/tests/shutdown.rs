@@ -0,0 +1,86 @@
+use std::{net::TcpListener, time::Duration};
+
+use key_value_store::{router, serve, AppState, FsyncPolicy, SharedState};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::oneshot,
+    task::JoinHandle,
+};
+
+/// Serves the store on a free port until something is sent on the
+/// returned channel.
+fn start(
+    state: &SharedState,
+    drain: Duration,
+) -> (String, oneshot::Sender<()>, JoinHandle<std::io::Result<()>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (shutdown, signal) = oneshot::channel::<()>();
+    let app = router(state);
+    let state = state.clone();
+    let server = tokio::spawn(async move {
+        serve(
+            listener,
+            app,
+            &state,
+            async {
+                let _ = signal.await;
+            },
+            drain,
+        )
+        .await
+    });
+    (addr, shutdown, server)
+}
+
+#[tokio::test]
+async fn requests_in_flight_finish() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("kv.log");
+    let state = SharedState::new(AppState::open(&path, FsyncPolicy::Never).unwrap().into());
+    let (addr, shutdown, server) = start(&state, Duration::from_secs(5));
+
+    // Only half of the body is sent before the server is told to stop.
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    client
+        .write_all(b"POST /kv/a HTTP/1.1\r\nhost: kv\r\ncontent-length: 5\r\n\r\nhe")
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    shutdown.send(()).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(TcpStream::connect(&addr).await.is_err());
+
+    client.write_all(b"llo").await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+    server.await.unwrap().unwrap();
+    drop(state);
+    let db = AppState::open(&path, FsyncPolicy::Never).unwrap();
+    assert_eq!(&db.get("a").unwrap()[..], b"hello");
+}
+
+#[tokio::test]
+async fn shutdown_gives_up_after_drain() {
+    let state = SharedState::default();
+    let (addr, shutdown, server) = start(&state, Duration::from_millis(200));
+
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    client
+        .write_all(b"POST /kv/a HTTP/1.1\r\nhost: kv\r\ncontent-length: 5\r\n\r\nhe")
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    shutdown.send(()).unwrap();
+
+    tokio::time::timeout(Duration::from_secs(2), server)
+        .await
+        .expect("the server stops once the time to drain is up")
+        .unwrap()
+        .unwrap();
+    assert!(state.read().await.get("a").is_none());
+}
@@ -0,0 +1,11 @@
+//! The subscriber is global to the process, so this gets a test binary of
+//! its own.
+
+use key_value_store::{init_tracing, LogFormat};
+use tracing::Level;
+
+#[test]
+fn tracing_is_set_up_once() {
+    assert!(init_tracing(Level::INFO, LogFormat::Logfmt).is_ok());
+    assert!(init_tracing(Level::INFO, LogFormat::Json).is_err());
+}